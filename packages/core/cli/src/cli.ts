@@ -25,6 +25,7 @@ async function init(adminSigners: PublicKey[], minSignatures: number) {
     allowPnlWithdrawal: true,
     allowCollateralWithdrawal: true,
     allowSizeChange: true,
+    positionRequestTimeoutSec: 60,
   };
   client.init(adminSigners, perpetualsConfig);
 }
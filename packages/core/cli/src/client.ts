@@ -201,7 +201,8 @@ export class PerpetualsClient {
     wallet: PublicKey,
     poolName: string,
     tokenMint: PublicKey,
-    side: PositionSide
+    side: PositionSide,
+    positionId = 0
   ) => {
     let pool = this.getPoolKey(poolName);
     let custody = this.getCustodyKey(poolName, tokenMint);
@@ -210,6 +211,7 @@ export class PerpetualsClient {
       pool,
       custody,
       side === "long" ? [1] : [0],
+      new BN(positionId).toArray("le", 8),
     ]).publicKey;
   };
 
@@ -217,10 +219,11 @@ export class PerpetualsClient {
     wallet: PublicKey,
     poolName: string,
     tokenMint: PublicKey,
-    side: PositionSide
+    side: PositionSide,
+    positionId = 0
   ) => {
     return this.program.account.position.fetch(
-      this.getPositionKey(wallet, poolName, tokenMint, side)
+      this.getPositionKey(wallet, poolName, tokenMint, side, positionId)
     );
   };
 
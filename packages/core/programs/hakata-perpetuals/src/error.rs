@@ -54,4 +54,48 @@ pub enum PerpetualsError {
     InstructionNotAllowed,
     #[msg("Token utilization limit exceeded")]
     MaxUtilization,
+    #[msg("Invalid collateral custody")]
+    InvalidCollateralCustody,
+    #[msg("Order trigger price has not been reached")]
+    OrderNotTriggered,
+    #[msg("Order has expired")]
+    OrderExpired,
+    #[msg("Position trigger price has not been reached")]
+    TriggerNotReached,
+    #[msg("Oracle price was published before the position request")]
+    OraclePriceBeforeRequest,
+    #[msg("Position request has expired")]
+    PositionRequestExpired,
+    #[msg("Position request has not expired yet")]
+    PositionRequestNotExpired,
+    #[msg("Custody is solvent, auto-deleveraging is not required")]
+    AutoDeleverageNotRequired,
+    #[msg("A higher ranked position must be deleveraged first")]
+    AutoDeleverageNotTopRanked,
+    #[msg("Oracle price update is for a different feed")]
+    OracleFeedMismatch,
+    #[msg("Oracle price update is not fully verified")]
+    InsufficientOracleVerification,
+    #[msg("Oracle sources deviate more than allowed")]
+    OracleSourcesDeviate,
+    #[msg("Custody is in close-only mode")]
+    CustodyCloseOnly,
+    #[msg("Signed oracle price is missing or invalid")]
+    InvalidSignedPrice,
+    #[msg("Signed oracle price is not newer than the last accepted one")]
+    SignedPriceNotNewer,
+    #[msg("Market is closed")]
+    MarketClosed,
+    #[msg("Time-weighted average price is not available")]
+    TwapNotAvailable,
+    #[msg("Auto-deleveraging must reduce the custody deficit")]
+    AutoDeleverageDeficitNotReduced,
+    #[msg("Circuit breaker is tripped and no price has been accepted")]
+    CircuitBreakerTripped,
+    #[msg("Position score is below the pool auto-deleverage watermark")]
+    AutoDeleverageScoreTooLow,
+    #[msg("Signed oracle price is published in the future")]
+    SignedPriceInFuture,
+    #[msg("Not enough oracle sources are available")]
+    OracleQuorumNotMet,
 }
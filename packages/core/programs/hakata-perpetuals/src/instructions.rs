@@ -3,13 +3,19 @@ pub mod add_custody;
 pub mod testing_edit_custody;
 
 pub mod add_pool;
+pub mod add_synthetic_custody;
 pub mod init;
 pub mod remove_custody;
 pub mod remove_pool;
+pub mod reset_circuit_breaker;
 pub mod set_admin_signers;
 pub mod set_custody_config;
 pub mod set_permissions;
-// pub mod upgrade_custody;
+pub mod set_perpetuals_config;
+pub mod set_pool_config;
+pub mod upgrade_custody;
+pub mod upgrade_perpetuals;
+pub mod upgrade_pool;
 pub mod withdraw_fees;
 pub mod withdraw_sol_fees;
 
@@ -21,7 +27,16 @@ pub mod test_init;
 // public instructions
 pub mod add_collateral;
 pub mod add_liquidity;
+pub mod auto_deleverage;
+pub mod cancel_order;
+pub mod cancel_position_request;
 pub mod close_position;
+pub mod create_order;
+pub mod create_position_request;
+pub mod decrease_position;
+pub mod execute_order;
+pub mod execute_position_request;
+pub mod execute_trigger;
 pub mod get_add_liquidity_amount_and_fee;
 pub mod get_assets_under_management;
 pub mod get_entry_price_and_fee;
@@ -32,22 +47,36 @@ pub mod get_oracle_price;
 pub mod get_pnl;
 pub mod get_remove_liquidity_amount_and_fee;
 pub mod get_swap_amount_and_fees;
+pub mod get_user_positions;
+pub mod increase_position;
 pub mod liquidate;
+pub mod liquidate_batch;
 pub mod open_position;
+pub mod post_signed_price;
 pub mod remove_collateral;
 pub mod remove_liquidity;
+pub mod set_position_triggers;
 pub mod swap;
+pub mod update_oracle_aggregate;
+pub mod update_oracle_composite;
+pub mod update_twap;
+pub mod upgrade_position;
 
 // bring everything in scope
 pub use {
-    add_collateral::*, add_custody::*, add_liquidity::*, add_pool::*, close_position::*,
-    get_add_liquidity_amount_and_fee::*, get_assets_under_management::*,
-    get_entry_price_and_fee::*, get_exit_price_and_fee::*, get_liquidation_price::*,
-    get_liquidation_state::*, get_oracle_price::*, get_pnl::*,
-    get_remove_liquidity_amount_and_fee::*, get_swap_amount_and_fees::*, init::*, liquidate::*,
-    open_position::*, remove_collateral::*, remove_custody::*, remove_liquidity::*, remove_pool::*,
-    set_admin_signers::*, set_custody_config::*, set_permissions::*, set_test_oracle_price::*,
-    set_test_time::*, swap::*, test_init::*, testing_edit_custody::*, 
-    // upgrade_custody::*,
-    withdraw_fees::*, withdraw_sol_fees::*,
+    add_collateral::*, add_custody::*, add_liquidity::*, add_pool::*, add_synthetic_custody::*,
+    auto_deleverage::*, cancel_order::*, cancel_position_request::*, close_position::*,
+    create_order::*, create_position_request::*, decrease_position::*, execute_order::*,
+    execute_position_request::*, execute_trigger::*, get_add_liquidity_amount_and_fee::*,
+    get_assets_under_management::*, get_entry_price_and_fee::*, get_exit_price_and_fee::*,
+    get_liquidation_price::*, get_liquidation_state::*, get_oracle_price::*, get_pnl::*,
+    get_remove_liquidity_amount_and_fee::*, get_swap_amount_and_fees::*, get_user_positions::*,
+    increase_position::*, init::*, liquidate::*, liquidate_batch::*, open_position::*,
+    post_signed_price::*, remove_collateral::*, remove_custody::*, remove_liquidity::*,
+    remove_pool::*, reset_circuit_breaker::*, set_admin_signers::*, set_custody_config::*,
+    set_permissions::*, set_perpetuals_config::*, set_pool_config::*, set_position_triggers::*,
+    set_test_oracle_price::*, set_test_time::*, swap::*, test_init::*, testing_edit_custody::*,
+    update_oracle_aggregate::*, update_oracle_composite::*, update_twap::*, upgrade_custody::*,
+    upgrade_perpetuals::*, upgrade_pool::*, upgrade_position::*, withdraw_fees::*,
+    withdraw_sol_fees::*,
 };
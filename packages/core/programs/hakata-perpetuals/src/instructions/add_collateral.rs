@@ -2,11 +2,13 @@
 
 use {
     crate::{
-        error::PerpetualsError,
         math,
         state::{
-            custody::Custody, oracle::OraclePrice, perpetuals::Perpetuals, pool::Pool,
+            custody::Custody,
+            perpetuals::Perpetuals,
+            pool::Pool,
             position::Position,
+            trade::{Trade, TradePrices},
         },
     },
     anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
@@ -21,7 +23,7 @@ pub struct AddCollateral<'info> {
 
     #[account(
         mut,
-        constraint = funding_account.mint == custody.mint,
+        constraint = funding_account.mint == collateral_custody.mint,
         has_one = owner
     )]
     pub funding_account: Box<Account<'info, TokenAccount>>,
@@ -50,11 +52,13 @@ pub struct AddCollateral<'info> {
     #[account(
         mut,
         has_one = owner,
+        has_one = collateral_custody,
         seeds = [b"position",
                  owner.key().as_ref(),
                  pool.key().as_ref(),
                  custody.key().as_ref(),
-                 &[position.side as u8]],
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
         bump = position.bump
     )]
     pub position: Box<Account<'info, Position>>,
@@ -68,20 +72,35 @@ pub struct AddCollateral<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
 
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
     #[account(
         mut,
         seeds = [b"custody_token_account",
                  pool.key().as_ref(),
-                 custody.mint.as_ref()],
-        bump = custody.token_account_bump
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
     )]
-    pub custody_token_account: Box<Account<'info, TokenAccount>>,
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
 
     token_program: Program<'info, Token>,
 }
@@ -99,84 +118,62 @@ pub fn add_collateral(ctx: Context<AddCollateral>, params: &AddCollateralParams)
     }
     let perpetuals = ctx.accounts.perpetuals.as_mut();
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
     let position = ctx.accounts.position.as_mut();
     let pool = ctx.accounts.pool.as_mut();
-    let token_id = pool.get_token_id(&custody.key())?;
+    let token_id = pool.get_token_id(&collateral_custody.key())?;
 
     // compute position price
     let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_add_collateral(curtime)?;
 
-    let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+    // stale prices are replaced in degraded mode, fallback oracles are passed as remaining accounts
+    let (token_price, token_ema_price, _) = custody.get_degradable_prices(
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
+        ctx.remaining_accounts,
         curtime,
-        false,
     )?;
 
-    let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
-        &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
+    let (collateral_token_price, collateral_token_ema_price, _) = collateral_custody
+        .get_degradable_prices(
+            &ctx.accounts
+                .collateral_custody_oracle_account
+                .to_account_info(),
+            ctx.remaining_accounts,
+            curtime,
+        )?;
+
+    let same_custody = custody.key() == collateral_custody.key();
+    let mut trade = Trade {
+        pool,
+        custody,
+        collateral_custody,
+        same_custody,
+        prices: TradePrices {
+            token_price,
+            token_ema_price,
+            collateral_token_price,
+            collateral_token_ema_price,
+        },
         curtime,
-        custody.pricing.use_ema,
-    )?;
-
-    let min_price = if token_price < token_ema_price {
-        token_price
-    } else {
-        token_ema_price
     };
-
-    // compute fee
-    let fee_amount =
-        pool.get_add_liquidity_fee(token_id, params.collateral, custody, &token_ema_price)?;
-    msg!("Collected fee: {}", fee_amount);
+    let fee_amount = trade.add_collateral(position, token_id, params.collateral)?;
 
     // compute amount to transfer
     let transfer_amount = math::checked_add(params.collateral, fee_amount)?;
-    let collateral_usd = min_price.get_asset_amount_usd(params.collateral, custody.decimals)?;
     msg!("Amount in: {}", transfer_amount);
-    msg!("Collateral added in USD: {}", collateral_usd);
-
-    // update existing position
-    msg!("Update existing position");
-    position.update_time = perpetuals.get_time()?;
-    position.collateral_usd = math::checked_add(position.collateral_usd, collateral_usd)?;
-    position.collateral_amount = math::checked_add(position.collateral_amount, params.collateral)?;
-
-    // check position risk
-    msg!("Check position risks");
-    require!(
-        pool.check_leverage(position, &token_ema_price, custody, curtime, true)?,
-        PerpetualsError::MaxLeverage
-    );
 
     // transfer tokens
     msg!("Transfer tokens");
     perpetuals.transfer_tokens_from_user(
         ctx.accounts.funding_account.to_account_info(),
-        ctx.accounts.custody_token_account.to_account_info(),
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
         ctx.accounts.owner.to_account_info(),
         ctx.accounts.token_program.to_account_info(),
         transfer_amount,
     )?;
 
-    // update custody stats
-    msg!("Update custody stats");
-    custody.collected_fees.open_position_usd = custody
-        .collected_fees
-        .open_position_usd
-        .wrapping_add(token_ema_price.get_asset_amount_usd(fee_amount, custody.decimals)?);
-
-    custody.assets.collateral = math::checked_add(custody.assets.collateral, params.collateral)?;
-
-    let protocol_fee = Pool::get_fee_amount(custody.fees.protocol_share, fee_amount)?;
-    custody.assets.protocol_fees = math::checked_add(custody.assets.protocol_fees, protocol_fee)?;
-
-    custody.add_collateral(position.side, collateral_usd)?;
-
     Ok(())
 }
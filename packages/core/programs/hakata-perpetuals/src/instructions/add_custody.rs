@@ -4,7 +4,11 @@ use {
     crate::{
         error::PerpetualsError,
         state::{
-            custody::{BorrowRateParams, Custody, Fees, OracleParams, PricingParams},
+            custody::{
+                BorrowRateParams, CircuitBreakerParams, Custody, DegradedModeParams, Fees,
+                FundingRateParams, OracleParams, PricingParams, TwapParams,
+            },
+            market_hours::MarketHours,
             multisig::{AdminInstruction, Multisig},
             perpetuals::{Permissions, Perpetuals},
             pool::{Pool, TokenRatios},
@@ -90,6 +94,11 @@ pub struct AddCustodyParams {
     pub permissions: Permissions,
     pub fees: Fees,
     pub borrow_rate: BorrowRateParams,
+    pub funding_rate: FundingRateParams,
+    pub market_hours: MarketHours,
+    pub circuit_breaker: CircuitBreakerParams,
+    pub twap: TwapParams,
+    pub degraded_mode: DegradedModeParams,
     pub ratios: Vec<TokenRatios>,
 }
 
@@ -143,12 +152,18 @@ pub fn add_custody<'info>(
     custody.permissions = params.permissions;
     custody.fees = params.fees;
     custody.borrow_rate = params.borrow_rate;
+    custody.funding_rate = params.funding_rate;
+    custody.market_hours = params.market_hours;
+    custody.circuit_breaker = params.circuit_breaker;
+    custody.twap = params.twap;
+    custody.degraded_mode = params.degraded_mode;
     custody.borrow_rate_state.current_rate = params.borrow_rate.base_rate;
     custody.borrow_rate_state.last_update = ctx.accounts.perpetuals.get_time()?;
+    custody.funding_rate_state.last_update = custody.borrow_rate_state.last_update;
     custody.bump = ctx.bumps.custody;
     custody.token_account_bump = ctx.bumps.custody_token_account;
 
-    if !custody.validate() {
+    if !custody.validate() || !pool.quote.validate_custody(custody) {
         err!(PerpetualsError::InvalidCustodyConfig)
     } else {
         Ok(0)
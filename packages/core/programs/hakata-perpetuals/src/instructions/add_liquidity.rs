@@ -122,23 +122,27 @@ pub fn add_liquidity(ctx: Context<AddLiquidity>, params: &AddLiquidityParams) ->
     let curtime = perpetuals.get_time()?;
 
     let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         custody.pricing.use_ema,
     )?;
 
+    // LP tokens are priced with every pool custody, so liquidity changes are blocked
+    // while any of them is close-only
+    pool.check_custodies_not_close_only(ctx.remaining_accounts, curtime)?;
+    if !custody.record_trade_price(&token_price, curtime)? {
+        // keep the tripped circuit breaker
+        return Ok(());
+    }
+
     let min_price = if token_price < token_ema_price {
         token_price
     } else {
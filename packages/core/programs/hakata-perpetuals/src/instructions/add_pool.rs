@@ -6,7 +6,7 @@ use {
         state::{
             multisig::{AdminInstruction, Multisig},
             perpetuals::Perpetuals,
-            pool::Pool,
+            pool::{Pool, QuoteCurrency},
         },
     },
     anchor_lang::prelude::*,
@@ -78,6 +78,7 @@ pub struct AddPool<'info> {
 #[derive(AnchorSerialize, AnchorDeserialize)]
 pub struct AddPoolParams {
     pub name: String,
+    pub quote: QuoteCurrency,
 }
 
 pub fn add_pool<'info>(
@@ -116,6 +117,7 @@ pub fn add_pool<'info>(
     msg!("Record pool: {}", params.name);
     pool.inception_time = perpetuals.get_time()?;
     pool.name = params.name.clone();
+    pool.quote = params.quote;
     pool.bump = ctx.bumps.pool;
     pool.lp_token_bump = ctx.bumps.lp_token_mint;
 
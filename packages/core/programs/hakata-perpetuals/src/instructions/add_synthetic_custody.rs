@@ -0,0 +1,147 @@
+//! AddSyntheticCustody instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::{
+                BorrowRateParams, CircuitBreakerParams, Custody, DegradedModeParams, Fees,
+                FundingRateParams, OracleParams, PricingParams, TwapParams,
+            },
+            market_hours::MarketHours,
+            multisig::{AdminInstruction, Multisig},
+            perpetuals::{Permissions, Perpetuals},
+            pool::{Pool, TokenRatios},
+        },
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+#[instruction(params: AddSyntheticCustodyParams)]
+pub struct AddSyntheticCustody<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.load()?.bump
+    )]
+    pub multisig: AccountLoader<'info, Multisig>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        mut,
+        realloc = Pool::LEN + (pool.custodies.len() + 1) * std::mem::size_of::<Pubkey>() +
+                              (pool.ratios.len() + 1) * std::mem::size_of::<TokenRatios>(),
+        realloc::payer = admin,
+        realloc::zero = false,
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = Custody::LEN,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 params.mint.as_ref()],
+        bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AddSyntheticCustodyParams {
+    // identifies the synthetic asset, there is no token mint behind it
+    pub mint: Pubkey,
+    pub decimals: u8,
+    pub oracle: OracleParams,
+    pub pricing: PricingParams,
+    pub permissions: Permissions,
+    pub fees: Fees,
+    pub borrow_rate: BorrowRateParams,
+    pub funding_rate: FundingRateParams,
+    pub market_hours: MarketHours,
+    pub circuit_breaker: CircuitBreakerParams,
+    pub twap: TwapParams,
+    pub degraded_mode: DegradedModeParams,
+    pub ratios: Vec<TokenRatios>,
+}
+
+pub fn add_synthetic_custody<'info>(
+    ctx: Context<'_, '_, '_, 'info, AddSyntheticCustody<'info>>,
+    params: &AddSyntheticCustodyParams,
+) -> Result<u8> {
+    // validate inputs
+    if params.ratios.len() != ctx.accounts.pool.ratios.len() + 1 {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+
+    // validate signatures
+    let mut multisig = ctx.accounts.multisig.load_mut()?;
+
+    let signatures_left = multisig.sign_multisig(
+        &ctx.accounts.admin,
+        &Multisig::get_account_infos(&ctx)[1..],
+        &Multisig::get_instruction_data(AdminInstruction::AddSyntheticCustody, params)?,
+    )?;
+    if signatures_left > 0 {
+        msg!(
+            "Instruction has been signed but more signatures are required: {}",
+            signatures_left
+        );
+        return Ok(signatures_left);
+    }
+
+    let pool = ctx.accounts.pool.as_mut();
+    if pool.get_token_id(&ctx.accounts.custody.key()).is_ok() {
+        // return error if custody is already initialized
+        return Err(ProgramError::AccountAlreadyInitialized.into());
+    }
+
+    // update pool data
+    pool.custodies.push(ctx.accounts.custody.key());
+    pool.ratios = params.ratios.clone();
+    if !pool.validate() {
+        return err!(PerpetualsError::InvalidPoolConfig);
+    }
+
+    // record custody data
+    let custody = ctx.accounts.custody.as_mut();
+    custody.pool = pool.key();
+    custody.mint = params.mint;
+    custody.decimals = params.decimals;
+    custody.is_synthetic = true;
+    custody.oracle = params.oracle;
+    custody.pricing = params.pricing;
+    custody.permissions = params.permissions;
+    custody.fees = params.fees;
+    custody.borrow_rate = params.borrow_rate;
+    custody.funding_rate = params.funding_rate;
+    custody.market_hours = params.market_hours;
+    custody.circuit_breaker = params.circuit_breaker;
+    custody.twap = params.twap;
+    custody.degraded_mode = params.degraded_mode;
+    custody.borrow_rate_state.current_rate = params.borrow_rate.base_rate;
+    custody.borrow_rate_state.last_update = ctx.accounts.perpetuals.get_time()?;
+    custody.funding_rate_state.last_update = custody.borrow_rate_state.last_update;
+    custody.bump = ctx.bumps.custody;
+
+    if !custody.validate() || !pool.quote.validate_custody(custody) {
+        err!(PerpetualsError::InvalidCustodyConfig)
+    } else {
+        Ok(0)
+    }
+}
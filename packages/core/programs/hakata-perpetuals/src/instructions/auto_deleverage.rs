@@ -0,0 +1,368 @@
+//! AutoDeleverage instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position::{Position, Side},
+        },
+        try_from,
+    },
+    anchor_lang::prelude::*,
+    anchor_spl::token::{Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+pub struct AutoDeleverage<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    /// CHECK: position owner, receives the position account rent
+    #[account(
+        mut,
+        constraint = owner.key() == position.owner
+    )]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = receiving_account.mint == collateral_custody.mint,
+        constraint = receiving_account.owner == position.owner
+    )]
+    pub receiving_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        mut,
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        has_one = collateral_custody,
+        seeds = [b"position",
+                 position.owner.as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
+        bump = position.bump,
+        close = owner
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the position token
+    #[account(
+        constraint = custody_oracle_account.key() == custody.oracle.oracle_account
+    )]
+    pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
+    )]
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
+
+    token_program: Program<'info, Token>,
+    // remaining accounts:
+    //   other open positions in the same market (read-only, unsigned),
+    //   the deleveraged position must rank at least as high as each of them
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct AutoDeleverageParams {}
+
+#[event]
+pub struct AutoDeleverageEvent {
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub custody: Pubkey,
+    pub collateral_custody: Pubkey,
+    pub side: Side,
+    pub exit_price: u64,
+    pub size_usd: u64,
+    pub profit_usd: u64,
+    pub loss_usd: u64,
+    pub adl_score: u64,
+    pub amount_out: u64,
+}
+
+pub fn auto_deleverage(ctx: Context<AutoDeleverage>, _params: &AutoDeleverageParams) -> Result<()> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_close_position && custody.permissions.allow_close_position,
+        PerpetualsError::InstructionNotAllowed
+    );
+
+    let position_key = ctx.accounts.position.key();
+    let position = ctx.accounts.position.as_mut();
+    let pool = ctx.accounts.pool.as_mut();
+
+    // check custody state
+    msg!("Check custody state");
+    let deficit = collateral_custody.get_deficit();
+    msg!("Custody deficit: {}", deficit);
+    require!(deficit > 0, PerpetualsError::AutoDeleverageNotRequired);
+
+    // compute position rank
+    msg!("Check position rank");
+    let curtime = perpetuals.get_time()?;
+
+    let token_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    custody.record_price(&token_price, curtime)?;
+    if custody.key() == collateral_custody.key() {
+        collateral_custody.sync_price_state(custody);
+    }
+
+    let token_ema_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        custody.pricing.use_ema,
+    )?;
+
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
+    // while the circuit breaker is tripped positions are settled at the last accepted price
+    let (token_price, token_ema_price) =
+        custody.get_accepted_prices(&token_price, &token_ema_price, curtime)?;
+    let (collateral_token_price, collateral_token_ema_price) = collateral_custody
+        .get_accepted_prices(
+            &collateral_token_price,
+            &collateral_token_ema_price,
+            curtime,
+        )?;
+
+    let adl_score = pool.get_adl_score(
+        position,
+        &token_ema_price,
+        custody,
+        &collateral_token_ema_price,
+        collateral_custody,
+        curtime,
+    )?;
+    msg!("ADL score: {}", adl_score);
+    require!(adl_score > 0, PerpetualsError::InvalidPositionState);
+    // anyone can deleverage a position, but only the most profitable and leveraged ones
+    require!(
+        pool.check_adl_score(adl_score),
+        PerpetualsError::AutoDeleverageScoreTooLow
+    );
+
+    for account in ctx.remaining_accounts {
+        if account.key() == position_key {
+            continue;
+        }
+        let candidate = try_from!(Account<Position>, account)?;
+        require!(
+            candidate.pool == pool.key()
+                && candidate.custody == custody.key()
+                && candidate.collateral_custody == collateral_custody.key(),
+            PerpetualsError::InvalidPositionState
+        );
+        let candidate_score = pool.get_adl_score(
+            &candidate,
+            &token_ema_price,
+            custody,
+            &collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+        )?;
+        require_gte!(
+            adl_score,
+            candidate_score,
+            PerpetualsError::AutoDeleverageNotTopRanked
+        );
+    }
+
+    let exit_price = pool.get_exit_price(
+        &token_price,
+        &token_ema_price,
+        position.side,
+        custody,
+        curtime,
+    )?;
+    msg!("Exit price: {}", exit_price);
+
+    msg!("Settle position");
+    let (transfer_amount, fee_amount, profit_usd, loss_usd) = pool.get_close_amount(
+        position,
+        &token_price,
+        &token_ema_price,
+        custody,
+        &collateral_token_price,
+        &collateral_token_ema_price,
+        collateral_custody,
+        curtime,
+        false,
+    )?;
+
+    msg!("Net profit: {}, loss: {}", profit_usd, loss_usd);
+    msg!("Collected fee: {}", fee_amount);
+    msg!("Amount out: {}", transfer_amount);
+
+    // unlock pool funds
+    collateral_custody.unlock_funds(position.locked_amount)?;
+
+    // check pool constraints
+    msg!("Check pool constraints");
+    require!(
+        pool.check_available_amount(transfer_amount, collateral_custody)?,
+        PerpetualsError::CustodyAmountLimit
+    );
+
+    // transfer tokens
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens(
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
+        ctx.accounts.receiving_account.to_account_info(),
+        ctx.accounts.transfer_authority.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        transfer_amount,
+    )?;
+
+    // update custody stats
+    msg!("Update custody stats");
+    collateral_custody.collected_fees.close_position_usd = collateral_custody
+        .collected_fees
+        .close_position_usd
+        .wrapping_add(
+            collateral_token_ema_price
+                .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+        );
+
+    let reserved_amount = collateral_custody.reserve_fee(fee_amount)?;
+    collateral_custody.settle_collateral(
+        position.collateral_amount,
+        transfer_amount,
+        reserved_amount,
+    )?;
+    require_gt!(
+        deficit,
+        collateral_custody.get_deficit(),
+        PerpetualsError::AutoDeleverageDeficitNotReduced
+    );
+
+    emit!(AutoDeleverageEvent {
+        position: position_key,
+        owner: position.owner,
+        custody: custody.key(),
+        collateral_custody: collateral_custody.key(),
+        side: position.side,
+        exit_price,
+        size_usd: position.size_usd,
+        profit_usd,
+        loss_usd,
+        adl_score,
+        amount_out: transfer_amount,
+    });
+
+    // collateral_custody is serialized last, so if both are the same account
+    // position token stats must be recorded there
+    let (custody, position_collateral_custody) = if custody.key() == collateral_custody.key() {
+        (&mut **collateral_custody, None)
+    } else {
+        (&mut **custody, Some(&**collateral_custody))
+    };
+
+    custody.volume_stats.close_position_usd = custody
+        .volume_stats
+        .close_position_usd
+        .wrapping_add(position.size_usd);
+
+    if position.side == Side::Long {
+        custody.trade_stats.oi_long_usd = custody
+            .trade_stats
+            .oi_long_usd
+            .saturating_sub(position.size_usd);
+    } else {
+        custody.trade_stats.oi_short_usd = custody
+            .trade_stats
+            .oi_short_usd
+            .saturating_sub(position.size_usd);
+    }
+
+    custody.trade_stats.profit_usd = custody.trade_stats.profit_usd.wrapping_add(profit_usd);
+    custody.trade_stats.loss_usd = custody.trade_stats.loss_usd.wrapping_add(loss_usd);
+
+    custody.remove_position(position, curtime, position_collateral_custody)?;
+    custody.update_funding_rate(curtime)?;
+    collateral_custody.update_borrow_rate(curtime)?;
+
+    Ok(())
+}
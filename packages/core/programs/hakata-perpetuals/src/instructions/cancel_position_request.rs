@@ -0,0 +1,170 @@
+//! CancelPositionRequest instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position_request::{PositionRequest, PositionRequestType},
+        },
+    },
+    anchor_lang::prelude::*,
+    anchor_spl::token::{Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+pub struct CancelPositionRequest<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = receiving_account.mint == position_request_token_account.mint,
+        has_one = owner
+    )]
+    pub receiving_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = pool,
+        seeds = [b"position_request",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 position_request.custody.as_ref(),
+                 &[position_request.side as u8],
+                 &position_request.position_id.to_le_bytes()],
+        bump = position_request.bump,
+        close = owner
+    )]
+    pub position_request: Box<Account<'info, PositionRequest>>,
+
+    #[account(
+        mut,
+        seeds = [b"position_request_token_account",
+                 position_request.key().as_ref()],
+        bump = position_request.token_account_bump
+    )]
+    pub position_request_token_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: position the request applies to, holds the rent deposit of Open requests
+    #[account(
+        mut,
+        seeds = [b"position",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 position_request.custody.as_ref(),
+                 &[position_request.side as u8],
+                 &position_request.position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: AccountInfo<'info>,
+
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct CancelPositionRequestParams {}
+
+pub fn cancel_position_request(
+    ctx: Context<CancelPositionRequest>,
+    _params: &CancelPositionRequestParams,
+) -> Result<()> {
+    // validate inputs, keepers get the full timeout to fill the request
+    msg!("Validate inputs");
+    let perpetuals = ctx.accounts.perpetuals.as_ref();
+    require!(
+        ctx.accounts.position_request.is_expired(
+            perpetuals.get_time()?,
+            perpetuals.get_position_request_timeout()
+        )?,
+        PerpetualsError::PositionRequestNotExpired
+    );
+
+    // refund the whole escrow balance
+    msg!("Transfer tokens");
+    let refund_amount = ctx.accounts.position_request_token_account.amount;
+    msg!("Amount out: {}", refund_amount);
+
+    if refund_amount > 0 {
+        perpetuals.transfer_tokens(
+            ctx.accounts
+                .position_request_token_account
+                .to_account_info(),
+            ctx.accounts.receiving_account.to_account_info(),
+            ctx.accounts.transfer_authority.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            refund_amount,
+        )?;
+    }
+
+    Perpetuals::close_token_account(
+        ctx.accounts.owner.to_account_info(),
+        ctx.accounts
+            .position_request_token_account
+            .to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        ctx.accounts.transfer_authority.to_account_info(),
+        &[&[b"transfer_authority", &[perpetuals.transfer_authority_bump]]],
+    )?;
+
+    // return the position rent deposit
+    let position = &ctx.accounts.position;
+    let rent_deposit = position.try_lamports()?;
+    if ctx.accounts.position_request.request_type == PositionRequestType::Open
+        && position.data_is_empty()
+        && rent_deposit > 0
+    {
+        msg!("Refund position rent: {}", rent_deposit);
+        let request = ctx.accounts.position_request.as_ref();
+        let owner_key = ctx.accounts.owner.key();
+        let pool_key = ctx.accounts.pool.key();
+        let side = [request.side as u8];
+        let position_id = request.position_id.to_le_bytes();
+        let bump = [ctx.bumps.position];
+        let cpi_accounts = anchor_lang::system_program::Transfer {
+            from: position.to_account_info(),
+            to: ctx.accounts.owner.to_account_info(),
+        };
+        anchor_lang::system_program::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.system_program.to_account_info(),
+                cpi_accounts,
+                &[&[
+                    b"position",
+                    owner_key.as_ref(),
+                    pool_key.as_ref(),
+                    request.custody.as_ref(),
+                    &side,
+                    &position_id,
+                    &bump,
+                ]],
+            ),
+            rent_deposit,
+        )?;
+    }
+
+    Ok(())
+}
@@ -3,13 +3,12 @@
 use {
     crate::{
         error::PerpetualsError,
-        math,
         state::{
             custody::Custody,
-            oracle::OraclePrice,
             perpetuals::Perpetuals,
             pool::Pool,
-            position::{Position, Side},
+            position::Position,
+            trade::{Trade, TradePrices},
         },
     },
     anchor_lang::prelude::*,
@@ -23,7 +22,7 @@ pub struct ClosePosition<'info> {
 
     #[account(
         mut,
-        constraint = receiving_account.mint == custody.mint,
+        constraint = receiving_account.mint == collateral_custody.mint,
         has_one = owner
     )]
     pub receiving_account: Box<Account<'info, TokenAccount>>,
@@ -52,11 +51,13 @@ pub struct ClosePosition<'info> {
     #[account(
         mut,
         has_one = owner,
+        has_one = collateral_custody,
         seeds = [b"position",
                  owner.key().as_ref(),
                  pool.key().as_ref(),
                  custody.key().as_ref(),
-                 &[position.side as u8]],
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
         bump = position.bump,
         close = owner
     )]
@@ -71,20 +72,35 @@ pub struct ClosePosition<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
 
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
     #[account(
         mut,
         seeds = [b"custody_token_account",
                  pool.key().as_ref(),
-                 custody.mint.as_ref()],
-        bump = custody.token_account_bump
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
     )]
-    pub custody_token_account: Box<Account<'info, TokenAccount>>,
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
 
     token_program: Program<'info, Token>,
 }
@@ -99,6 +115,7 @@ pub fn close_position(ctx: Context<ClosePosition>, params: &ClosePositionParams)
     msg!("Check permissions");
     let perpetuals = ctx.accounts.perpetuals.as_mut();
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
     require!(
         perpetuals.permissions.allow_close_position && custody.permissions.allow_close_position,
         PerpetualsError::InstructionNotAllowed
@@ -114,105 +131,76 @@ pub fn close_position(ctx: Context<ClosePosition>, params: &ClosePositionParams)
 
     // compute exit price
     let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_close_position(curtime)?;
 
-    let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
-        &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
-        curtime,
-        false,
-    )?;
-
-    let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+    // stale prices are replaced in degraded mode, which never updates the price state,
+    // fallback oracles are passed as remaining accounts
+    let (token_price, token_ema_price, degraded) = custody.get_degradable_prices(
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
+        ctx.remaining_accounts,
         curtime,
-        custody.pricing.use_ema,
     )?;
 
-    let exit_price = pool.get_exit_price(&token_price, &token_ema_price, position.side, custody)?;
-    msg!("Exit price: {}", exit_price);
-
-    if position.side == Side::Long {
-        require_gte!(exit_price, params.price, PerpetualsError::MaxPriceSlippage);
-    } else {
-        require_gte!(params.price, exit_price, PerpetualsError::MaxPriceSlippage);
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    if !degraded {
+        custody.record_price(&token_price, curtime)?;
+        if custody.key() == collateral_custody.key() {
+            collateral_custody.sync_price_state(custody);
+        }
     }
 
-    msg!("Settle position");
-    let (transfer_amount, fee_amount, profit_usd, loss_usd) = pool.get_close_amount(
-        position,
-        &token_price,
-        &token_ema_price,
+    let (collateral_token_price, collateral_token_ema_price, collateral_degraded) =
+        collateral_custody.get_degradable_prices(
+            &ctx.accounts
+                .collateral_custody_oracle_account
+                .to_account_info(),
+            ctx.remaining_accounts,
+            curtime,
+        )?;
+
+    // while the circuit breaker is tripped positions are settled at the last accepted price
+    let (token_price, token_ema_price) = if degraded {
+        (token_price, token_ema_price)
+    } else {
+        custody.get_accepted_prices(&token_price, &token_ema_price, curtime)?
+    };
+    let (collateral_token_price, collateral_token_ema_price) = if collateral_degraded {
+        (collateral_token_price, collateral_token_ema_price)
+    } else {
+        collateral_custody.get_accepted_prices(
+            &collateral_token_price,
+            &collateral_token_ema_price,
+            curtime,
+        )?
+    };
+
+    let same_custody = custody.key() == collateral_custody.key();
+    let mut trade = Trade {
+        pool,
         custody,
+        collateral_custody,
+        same_custody,
+        prices: TradePrices {
+            token_price,
+            token_ema_price,
+            collateral_token_price,
+            collateral_token_ema_price,
+        },
         curtime,
-        false,
-    )?;
-
-    let protocol_fee = Pool::get_fee_amount(custody.fees.protocol_share, fee_amount)?;
-
-    msg!("Net profit: {}, loss: {}", profit_usd, loss_usd);
-    msg!("Collected fee: {}", fee_amount);
-    msg!("Amount out: {}", transfer_amount);
-
-    // unlock pool funds
-    custody.unlock_funds(position.locked_amount)?;
-
-    // check pool constraints
-    msg!("Check pool constraints");
-    require!(
-        pool.check_available_amount(transfer_amount, custody)?,
-        PerpetualsError::CustodyAmountLimit
-    );
+    };
+    let transfer_amount = trade.close_position(position, params.price)?;
 
     // transfer tokens
     msg!("Transfer tokens");
     perpetuals.transfer_tokens(
-        ctx.accounts.custody_token_account.to_account_info(),
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
         ctx.accounts.receiving_account.to_account_info(),
         ctx.accounts.transfer_authority.to_account_info(),
         ctx.accounts.token_program.to_account_info(),
         transfer_amount,
     )?;
 
-    // update custody stats
-    msg!("Update custody stats");
-    custody.collected_fees.close_position_usd = custody
-        .collected_fees
-        .close_position_usd
-        .wrapping_add(token_ema_price.get_asset_amount_usd(fee_amount, custody.decimals)?);
-
-    custody.volume_stats.close_position_usd = custody
-        .volume_stats
-        .close_position_usd
-        .wrapping_add(position.size_usd);
-
-    let amount_lost = transfer_amount.saturating_sub(position.collateral_amount);
-    custody.assets.owned = math::checked_sub(custody.assets.owned, amount_lost)?;
-    custody.assets.collateral =
-        math::checked_sub(custody.assets.collateral, position.collateral_amount)?;
-    custody.assets.protocol_fees = math::checked_add(custody.assets.protocol_fees, protocol_fee)?;
-
-    if position.side == Side::Long {
-        custody.trade_stats.oi_long_usd = custody
-            .trade_stats
-            .oi_long_usd
-            .saturating_sub(position.size_usd);
-    } else {
-        custody.trade_stats.oi_short_usd = custody
-            .trade_stats
-            .oi_short_usd
-            .saturating_sub(position.size_usd);
-    }
-
-    custody.trade_stats.profit_usd = custody.trade_stats.profit_usd.wrapping_add(profit_usd);
-    custody.trade_stats.loss_usd = custody.trade_stats.loss_usd.wrapping_add(loss_usd);
-
-    custody.remove_position(position, curtime)?;
-    custody.update_borrow_rate(curtime)?;
-
     Ok(())
 }
@@ -0,0 +1,175 @@
+//! CreateOrder instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody, order::Order, perpetuals::Perpetuals, pool::Pool, position::Side,
+        },
+    },
+    anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
+    anchor_spl::token::{Mint, Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+#[instruction(params: CreateOrderParams)]
+pub struct CreateOrder<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = funding_account.mint == collateral_custody.mint,
+        has_one = owner
+    )]
+    pub funding_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    #[account(
+        constraint = collateral_custody_token_mint.key() == collateral_custody.mint
+    )]
+    pub collateral_custody_token_mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = Order::LEN,
+        seeds = [b"order",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &params.order_id.to_le_bytes()],
+        bump
+    )]
+    pub order: Box<Account<'info, Order>>,
+
+    #[account(
+        init,
+        payer = owner,
+        token::mint = collateral_custody_token_mint,
+        token::authority = transfer_authority,
+        seeds = [b"order_token_account",
+                 order.key().as_ref()],
+        bump
+    )]
+    pub order_token_account: Box<Account<'info, TokenAccount>>,
+
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct CreateOrderParams {
+    pub order_id: u64,
+    pub position_id: u64,
+    pub side: Side,
+    pub trigger_price: u64,
+    pub collateral: u64,
+    pub size: u64,
+    pub expiration_time: i64,
+}
+
+pub fn create_order(ctx: Context<CreateOrder>, params: &CreateOrderParams) -> Result<()> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_open_position
+            && custody.permissions.allow_open_position
+            && !custody.is_stable,
+        PerpetualsError::InstructionNotAllowed
+    );
+
+    // validate inputs
+    msg!("Validate inputs");
+    let curtime = perpetuals.get_time()?;
+    if params.trigger_price == 0
+        || params.collateral == 0
+        || params.size == 0
+        || params.side == Side::None
+        || (params.expiration_time != 0 && params.expiration_time <= curtime)
+    {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+    // same collateral rules as open_position
+    if params.side == Side::Long && !custody.is_synthetic {
+        require_keys_eq!(
+            custody.key(),
+            collateral_custody.key(),
+            PerpetualsError::InvalidCollateralCustody
+        );
+    } else {
+        require!(
+            collateral_custody.is_stable,
+            PerpetualsError::InvalidCollateralCustody
+        );
+    }
+
+    // record order data
+    msg!("Initialize new order");
+    let order = ctx.accounts.order.as_mut();
+    order.owner = ctx.accounts.owner.key();
+    order.pool = ctx.accounts.pool.key();
+    order.custody = custody.key();
+    order.collateral_custody = collateral_custody.key();
+    order.order_id = params.order_id;
+    order.position_id = params.position_id;
+    order.side = params.side;
+    order.trigger_price = params.trigger_price;
+    order.collateral = params.collateral;
+    order.size = params.size;
+    order.create_time = curtime;
+    order.expiration_time = params.expiration_time;
+    order.bump = ctx.bumps.order;
+    order.token_account_bump = ctx.bumps.order_token_account;
+
+    // escrow collateral, fees are taken from it on execution
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens_from_user(
+        ctx.accounts.funding_account.to_account_info(),
+        ctx.accounts.order_token_account.to_account_info(),
+        ctx.accounts.owner.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        params.collateral,
+    )?;
+
+    Ok(())
+}
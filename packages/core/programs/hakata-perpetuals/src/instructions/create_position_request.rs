@@ -0,0 +1,251 @@
+//! CreatePositionRequest instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody,
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position::{Position, Side},
+            position_request::{PositionRequest, PositionRequestType},
+        },
+        try_from,
+    },
+    anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
+    anchor_spl::token::{Mint, Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+#[instruction(params: CreatePositionRequestParams)]
+pub struct CreatePositionRequest<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = funding_account.mint == collateral_custody.mint,
+        has_one = owner
+    )]
+    pub funding_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    /// CHECK: position the request applies to, doesn't exist yet for Open requests
+    /// and receives the owner's rent deposit instead
+    #[account(
+        mut,
+        seeds = [b"position",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[params.side as u8],
+                 &params.position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    #[account(
+        constraint = collateral_custody_token_mint.key() == collateral_custody.mint
+    )]
+    pub collateral_custody_token_mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = PositionRequest::LEN,
+        seeds = [b"position_request",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[params.side as u8],
+                 &params.position_id.to_le_bytes()],
+        bump
+    )]
+    pub position_request: Box<Account<'info, PositionRequest>>,
+
+    #[account(
+        init,
+        payer = owner,
+        token::mint = collateral_custody_token_mint,
+        token::authority = transfer_authority,
+        seeds = [b"position_request_token_account",
+                 position_request.key().as_ref()],
+        bump
+    )]
+    pub position_request_token_account: Box<Account<'info, TokenAccount>>,
+
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct CreatePositionRequestParams {
+    pub request_type: PositionRequestType,
+    pub position_id: u64,
+    pub side: Side,
+    pub price: u64,
+    pub collateral: u64,
+    pub collateral_usd: u64,
+    pub size: u64,
+}
+
+pub fn create_position_request(
+    ctx: Context<CreatePositionRequest>,
+    params: &CreatePositionRequestParams,
+) -> Result<()> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    let allowed = match params.request_type {
+        PositionRequestType::Open => {
+            perpetuals.permissions.allow_open_position
+                && custody.permissions.allow_open_position
+                && !custody.is_stable
+        }
+        PositionRequestType::Close => {
+            perpetuals.permissions.allow_close_position && custody.permissions.allow_close_position
+        }
+        PositionRequestType::AddCollateral => true,
+        PositionRequestType::RemoveCollateral => {
+            perpetuals.permissions.allow_collateral_withdrawal
+                && custody.permissions.allow_collateral_withdrawal
+        }
+    };
+    require!(allowed, PerpetualsError::InstructionNotAllowed);
+
+    // validate inputs
+    msg!("Validate inputs");
+    if params.side == Side::None {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+    if params.request_type == PositionRequestType::Open {
+        if params.price == 0 || params.collateral == 0 || params.size == 0 {
+            return Err(ProgramError::InvalidArgument.into());
+        }
+        require!(
+            Perpetuals::is_empty_account(&ctx.accounts.position)?,
+            PerpetualsError::InvalidPositionState
+        );
+        // same collateral rules as open_position
+        if params.side == Side::Long && !custody.is_synthetic {
+            require_keys_eq!(
+                custody.key(),
+                collateral_custody.key(),
+                PerpetualsError::InvalidCollateralCustody
+            );
+        } else {
+            require!(
+                collateral_custody.is_stable,
+                PerpetualsError::InvalidCollateralCustody
+            );
+        }
+    } else {
+        let position = try_from!(Account<Position>, ctx.accounts.position)?;
+        require_keys_eq!(
+            position.collateral_custody,
+            collateral_custody.key(),
+            PerpetualsError::InvalidCollateralCustody
+        );
+        let valid = match params.request_type {
+            PositionRequestType::Close => params.price > 0,
+            PositionRequestType::AddCollateral => params.collateral > 0,
+            _ => params.collateral_usd > 0 && params.collateral_usd < position.collateral_usd,
+        };
+        if !valid {
+            return Err(ProgramError::InvalidArgument.into());
+        }
+    }
+    let escrow_amount = match params.request_type {
+        PositionRequestType::Open | PositionRequestType::AddCollateral => params.collateral,
+        _ => 0,
+    };
+
+    // record request data
+    msg!("Initialize new position request");
+    let position_request = ctx.accounts.position_request.as_mut();
+    position_request.owner = ctx.accounts.owner.key();
+    position_request.pool = ctx.accounts.pool.key();
+    position_request.custody = custody.key();
+    position_request.collateral_custody = collateral_custody.key();
+    position_request.position_id = params.position_id;
+    position_request.side = params.side;
+    position_request.request_type = params.request_type;
+    position_request.price = params.price;
+    position_request.collateral = escrow_amount;
+    position_request.collateral_usd = params.collateral_usd;
+    position_request.size = params.size;
+    position_request.request_time = perpetuals.get_time()?;
+    position_request.request_slot = Clock::get()?.slot;
+    position_request.bump = ctx.bumps.position_request;
+    position_request.token_account_bump = ctx.bumps.position_request_token_account;
+
+    // deposit the position rent, so keepers don't pay for the accounts they create
+    if params.request_type == PositionRequestType::Open {
+        let rent_deposit = Rent::get()?
+            .minimum_balance(Position::LEN)
+            .saturating_sub(ctx.accounts.position.try_lamports()?);
+        if rent_deposit > 0 {
+            msg!("Deposit position rent: {}", rent_deposit);
+            Perpetuals::transfer_sol(
+                ctx.accounts.owner.to_account_info(),
+                ctx.accounts.position.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+                rent_deposit,
+            )?;
+        }
+    }
+
+    // escrow collateral until the request is filled or cancelled
+    if escrow_amount > 0 {
+        msg!("Transfer tokens");
+        perpetuals.transfer_tokens_from_user(
+            ctx.accounts.funding_account.to_account_info(),
+            ctx.accounts
+                .position_request_token_account
+                .to_account_info(),
+            ctx.accounts.owner.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            escrow_amount,
+        )?;
+    }
+
+    Ok(())
+}
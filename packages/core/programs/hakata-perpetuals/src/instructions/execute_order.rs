@@ -0,0 +1,372 @@
+//! ExecuteOrder instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        math,
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            order::Order,
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position::{Position, Side},
+        },
+    },
+    anchor_lang::prelude::*,
+    anchor_spl::token::{Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+pub struct ExecuteOrder<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = keeper_receiving_account.mint == collateral_custody.mint,
+        constraint = keeper_receiving_account.owner == keeper.key()
+    )]
+    pub keeper_receiving_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        mut,
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        has_one = pool,
+        has_one = custody,
+        has_one = collateral_custody,
+        seeds = [b"order",
+                 order.owner.as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &order.order_id.to_le_bytes()],
+        bump = order.bump,
+        close = keeper
+    )]
+    pub order: Box<Account<'info, Order>>,
+
+    #[account(
+        mut,
+        seeds = [b"order_token_account",
+                 order.key().as_ref()],
+        bump = order.token_account_bump
+    )]
+    pub order_token_account: Box<Account<'info, TokenAccount>>,
+
+    // keeper pays for the position and is refunded with the order accounts rent
+    #[account(
+        init,
+        payer = keeper,
+        space = Position::LEN,
+        seeds = [b"position",
+                 order.owner.as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[order.side as u8],
+                 &order.position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the position token
+    #[account(
+        constraint = custody_oracle_account.key() == custody.oracle.oracle_account
+    )]
+    pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
+    )]
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
+
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct ExecuteOrderParams {}
+
+pub fn execute_order(ctx: Context<ExecuteOrder>, _params: &ExecuteOrderParams) -> Result<()> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_open_position
+            && custody.permissions.allow_open_position
+            && !custody.is_stable,
+        PerpetualsError::InstructionNotAllowed
+    );
+
+    // validate inputs
+    msg!("Validate inputs");
+    let order = ctx.accounts.order.as_mut();
+    let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_open(curtime)?;
+    require!(!order.is_expired(curtime), PerpetualsError::OrderExpired);
+    let same_custody = custody.key() == collateral_custody.key();
+    let position = ctx.accounts.position.as_mut();
+    let pool = ctx.accounts.pool.as_mut();
+
+    // compute position price
+    let token_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    let price_accepted = custody.record_trade_price(&token_price, curtime)?;
+    if custody.key() == collateral_custody.key() {
+        collateral_custody.sync_price_state(custody);
+    }
+    // the order is closed on success, so a tripping price fails execution and leaves the
+    // order open, the update_twap crank records the trip
+    require!(price_accepted, PerpetualsError::CustodyCloseOnly);
+
+    let token_ema_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        custody.pricing.use_ema,
+    )?;
+
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
+    let min_price = if token_price < token_ema_price {
+        token_price
+    } else {
+        token_ema_price
+    };
+
+    let min_collateral_price = if collateral_token_price < collateral_token_ema_price {
+        collateral_token_price
+    } else {
+        collateral_token_ema_price
+    };
+
+    let position_price =
+        pool.get_entry_price(&token_price, &token_ema_price, order.side, custody, curtime)?;
+    msg!("Entry price: {}", position_price);
+
+    require!(
+        order.is_triggered(position_price),
+        PerpetualsError::OrderNotTriggered
+    );
+
+    // compute fees, both are paid from the escrowed collateral
+    let size_usd = min_price.get_asset_amount_usd(order.size, custody.decimals)?;
+    let size_amount =
+        min_collateral_price.get_token_amount(size_usd, collateral_custody.decimals)?;
+    let locked_amount =
+        pool.get_locked_amount(size_usd, custody, &min_collateral_price, collateral_custody)?;
+    let fee_amount = pool.get_entry_fee(
+        custody.fees.open_position,
+        size_amount,
+        locked_amount,
+        collateral_custody,
+    )?;
+    let keeper_fee = Pool::get_fee_amount(custody.fees.order_execution, size_amount)?;
+    msg!("Collected fee: {}", fee_amount);
+    msg!("Keeper fee: {}", keeper_fee);
+
+    require!(
+        order.collateral > math::checked_add(fee_amount, keeper_fee)?,
+        PerpetualsError::InsufficientAmountReturned
+    );
+    let collateral =
+        math::checked_sub(order.collateral, math::checked_add(fee_amount, keeper_fee)?)?;
+
+    // compute amount to transfer
+    let transfer_amount = math::checked_add(collateral, fee_amount)?;
+    msg!("Amount in: {}", transfer_amount);
+
+    // init new position
+    msg!("Initialize new position");
+    let collateral_usd =
+        min_collateral_price.get_asset_amount_usd(collateral, collateral_custody.decimals)?;
+
+    position.owner = order.owner;
+    position.pool = pool.key();
+    position.custody = custody.key();
+    position.collateral_custody = collateral_custody.key();
+    position.position_id = order.position_id;
+    position.open_time = curtime;
+    position.update_time = 0;
+    position.side = order.side;
+    position.price = position_price;
+    position.size_usd = size_usd;
+    position.collateral_usd = collateral_usd;
+    position.unrealized_profit_usd = 0;
+    position.unrealized_loss_usd = 0;
+    position.cumulative_interest_snapshot = collateral_custody.get_cumulative_interest(curtime)?;
+    position.cumulative_funding_snapshot =
+        custody.get_cumulative_funding(position.side, curtime)?;
+    position.locked_amount = locked_amount;
+    position.collateral_amount = collateral;
+    position.bump = ctx.bumps.position;
+
+    // check position risk
+    msg!("Check position risks");
+    require!(
+        position.locked_amount > 0,
+        PerpetualsError::InsufficientAmountReturned
+    );
+    require!(
+        pool.check_leverage(
+            position,
+            &token_ema_price,
+            custody,
+            &collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+            true
+        )?,
+        PerpetualsError::MaxLeverage
+    );
+
+    // lock funds for potential profit payoff
+    collateral_custody.lock_funds(position.locked_amount)?;
+
+    // transfer tokens, the keeper also receives anything sent to the escrow on top of
+    // the order collateral so the escrow can be closed
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens(
+        ctx.accounts.order_token_account.to_account_info(),
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
+        ctx.accounts.transfer_authority.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        transfer_amount,
+    )?;
+
+    perpetuals.transfer_tokens(
+        ctx.accounts.order_token_account.to_account_info(),
+        ctx.accounts.keeper_receiving_account.to_account_info(),
+        ctx.accounts.transfer_authority.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        math::checked_sub(ctx.accounts.order_token_account.amount, transfer_amount)?,
+    )?;
+
+    Perpetuals::close_token_account(
+        ctx.accounts.keeper.to_account_info(),
+        ctx.accounts.order_token_account.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        ctx.accounts.transfer_authority.to_account_info(),
+        &[&[b"transfer_authority", &[perpetuals.transfer_authority_bump]]],
+    )?;
+
+    // update custody stats
+    msg!("Update custody stats");
+    collateral_custody.collected_fees.open_position_usd = collateral_custody
+        .collected_fees
+        .open_position_usd
+        .wrapping_add(
+            collateral_token_ema_price
+                .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+        );
+
+    collateral_custody.assets.collateral =
+        math::checked_add(collateral_custody.assets.collateral, collateral)?;
+
+    collateral_custody.collect_fee(fee_amount)?;
+
+    // collateral_custody is serialized last, so if both are the same account
+    // position token stats must be recorded there
+    let (custody, position_collateral_custody) = if same_custody {
+        (&mut **collateral_custody, None)
+    } else {
+        (&mut **custody, Some(&**collateral_custody))
+    };
+
+    custody.volume_stats.open_position_usd = custody
+        .volume_stats
+        .open_position_usd
+        .wrapping_add(size_usd);
+
+    if position.side == Side::Long {
+        custody.trade_stats.oi_long_usd =
+            math::checked_add(custody.trade_stats.oi_long_usd, size_usd)?;
+    } else {
+        custody.trade_stats.oi_short_usd =
+            math::checked_add(custody.trade_stats.oi_short_usd, size_usd)?;
+    }
+
+    custody.add_position(
+        position,
+        &collateral_token_ema_price,
+        curtime,
+        position_collateral_custody,
+    )?;
+    custody.update_funding_rate(curtime)?;
+    collateral_custody.update_borrow_rate(curtime)?;
+
+    Ok(())
+}
@@ -0,0 +1,582 @@
+//! ExecutePositionRequest instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        math,
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position::Position,
+            position_request::{PositionRequest, PositionRequestType},
+            trade::{Trade, TradePrices},
+        },
+    },
+    anchor_lang::prelude::*,
+    anchor_spl::token::{Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+pub struct ExecutePositionRequest<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    /// CHECK: request owner, receives the position rent on close
+    #[account(
+        mut,
+        constraint = owner.key() == position_request.owner
+    )]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = receiving_account.mint == collateral_custody.mint,
+        has_one = owner
+    )]
+    pub receiving_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        mut,
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    // request accounts rent pays the keeper
+    #[account(
+        mut,
+        has_one = pool,
+        has_one = custody,
+        has_one = collateral_custody,
+        seeds = [b"position_request",
+                 position_request.owner.as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[position_request.side as u8],
+                 &position_request.position_id.to_le_bytes()],
+        bump = position_request.bump,
+        close = keeper
+    )]
+    pub position_request: Box<Account<'info, PositionRequest>>,
+
+    #[account(
+        mut,
+        seeds = [b"position_request_token_account",
+                 position_request.key().as_ref()],
+        bump = position_request.token_account_bump
+    )]
+    pub position_request_token_account: Box<Account<'info, TokenAccount>>,
+
+    // only created by Open requests, funded by the rent the owner deposited with the request,
+    // the keeper covers any shortfall as the owner doesn't sign
+    #[account(
+        init_if_needed,
+        payer = keeper,
+        space = Position::LEN,
+        seeds = [b"position",
+                 position_request.owner.as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[position_request.side as u8],
+                 &position_request.position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the position token
+    #[account(
+        constraint = custody_oracle_account.key() == custody.oracle.oracle_account
+    )]
+    pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
+    )]
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
+
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct ExecutePositionRequestParams {}
+
+pub fn execute_position_request(
+    ctx: Context<ExecutePositionRequest>,
+    _params: &ExecutePositionRequestParams,
+) -> Result<()> {
+    // validate inputs
+    msg!("Validate inputs");
+    let request = ctx.accounts.position_request.as_ref().clone();
+    let curtime = ctx.accounts.perpetuals.get_time()?;
+    require!(
+        !request.is_expired(
+            curtime,
+            ctx.accounts.perpetuals.get_position_request_timeout()
+        )?,
+        PerpetualsError::PositionRequestExpired
+    );
+
+    let position = ctx.accounts.position.as_ref();
+    if request.request_type == PositionRequestType::Open {
+        require_keys_eq!(
+            position.owner,
+            Pubkey::default(),
+            PerpetualsError::InvalidPositionState
+        );
+    } else {
+        require_keys_eq!(
+            position.owner,
+            request.owner,
+            PerpetualsError::InvalidPositionState
+        );
+        require_keys_eq!(
+            position.collateral_custody,
+            request.collateral_custody,
+            PerpetualsError::InvalidCollateralCustody
+        );
+    }
+
+    // closes and collateral additions accept stale prices in degraded mode, like
+    // close_position and add_collateral, fallback oracles are passed as remaining accounts
+    let custody = ctx.accounts.custody.as_ref();
+    let collateral_custody = ctx.accounts.collateral_custody.as_ref();
+    let (prices, degraded, collateral_degraded) = if matches!(
+        request.request_type,
+        PositionRequestType::Close | PositionRequestType::AddCollateral
+    ) {
+        let (token_price, token_ema_price, degraded) = custody.get_degradable_prices(
+            &ctx.accounts.custody_oracle_account.to_account_info(),
+            ctx.remaining_accounts,
+            curtime,
+        )?;
+        let (collateral_token_price, collateral_token_ema_price, collateral_degraded) =
+            collateral_custody.get_degradable_prices(
+                &ctx.accounts
+                    .collateral_custody_oracle_account
+                    .to_account_info(),
+                ctx.remaining_accounts,
+                curtime,
+            )?;
+        let prices = TradePrices {
+            token_price,
+            token_ema_price,
+            collateral_token_price,
+            collateral_token_ema_price,
+        };
+        (prices, degraded, collateral_degraded)
+    } else {
+        let prices = TradePrices {
+            token_price: OraclePrice::new_from_oracle(
+                &custody.oracle,
+                &ctx.accounts.custody_oracle_account.to_account_info(),
+                curtime,
+                false,
+            )?,
+            token_ema_price: OraclePrice::new_from_oracle(
+                &custody.oracle,
+                &ctx.accounts.custody_oracle_account.to_account_info(),
+                curtime,
+                custody.pricing.use_ema,
+            )?,
+            collateral_token_price: OraclePrice::new_from_oracle(
+                &collateral_custody.oracle,
+                &ctx.accounts
+                    .collateral_custody_oracle_account
+                    .to_account_info(),
+                curtime,
+                false,
+            )?,
+            collateral_token_ema_price: OraclePrice::new_from_oracle(
+                &collateral_custody.oracle,
+                &ctx.accounts
+                    .collateral_custody_oracle_account
+                    .to_account_info(),
+                curtime,
+                collateral_custody.pricing.use_ema,
+            )?,
+        };
+        (prices, false, false)
+    };
+
+    // only prices the requester couldn't have seen are accepted,
+    // the oracle price is stale and not used in degraded mode
+    if !degraded {
+        let publish_time = OraclePrice::get_publish_time(
+            &custody.oracle,
+            &ctx.accounts.custody_oracle_account.to_account_info(),
+        )?;
+        let posted_slot = OraclePrice::get_posted_slot(
+            &custody.oracle,
+            &ctx.accounts.custody_oracle_account.to_account_info(),
+        )?;
+        require!(
+            request.is_valid_price_update(publish_time, posted_slot),
+            PerpetualsError::OraclePriceBeforeRequest
+        );
+    }
+
+    let escrow_used = match request.request_type {
+        PositionRequestType::Open => {
+            execute_open(ctx.accounts, &request, &prices, curtime, ctx.bumps.position)?
+        }
+        PositionRequestType::Close => execute_close(
+            ctx.accounts,
+            &request,
+            &prices,
+            curtime,
+            degraded,
+            collateral_degraded,
+        )?,
+        PositionRequestType::AddCollateral => {
+            execute_add_collateral(ctx.accounts, &request, &prices, curtime)?
+        }
+        PositionRequestType::RemoveCollateral => {
+            execute_remove_collateral(ctx.accounts, &request, &prices, curtime)?
+        }
+    };
+
+    // return anything left in escrow to the owner and release it
+    msg!("Close position request");
+    let perpetuals = ctx.accounts.perpetuals.as_ref();
+    let refund_amount = math::checked_sub(
+        ctx.accounts.position_request_token_account.amount,
+        escrow_used,
+    )?;
+    if refund_amount > 0 {
+        perpetuals.transfer_tokens(
+            ctx.accounts
+                .position_request_token_account
+                .to_account_info(),
+            ctx.accounts.receiving_account.to_account_info(),
+            ctx.accounts.transfer_authority.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            refund_amount,
+        )?;
+    }
+
+    Perpetuals::close_token_account(
+        ctx.accounts.keeper.to_account_info(),
+        ctx.accounts
+            .position_request_token_account
+            .to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        ctx.accounts.transfer_authority.to_account_info(),
+        &[&[b"transfer_authority", &[perpetuals.transfer_authority_bump]]],
+    )?;
+
+    Ok(())
+}
+
+/// Opens a new position, returns the amount taken from escrow
+fn execute_open(
+    accounts: &mut ExecutePositionRequest,
+    request: &PositionRequest,
+    prices: &TradePrices,
+    curtime: i64,
+    position_bump: u8,
+) -> Result<u64> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = accounts.perpetuals.as_mut();
+    let custody = accounts.custody.as_mut();
+    let collateral_custody = accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_open_position
+            && custody.permissions.allow_open_position
+            && !custody.is_stable,
+        PerpetualsError::InstructionNotAllowed
+    );
+    custody.market_hours.check_open(curtime)?;
+    let same_custody = custody.key() == collateral_custody.key();
+
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    let price_accepted = custody.record_trade_price(&prices.token_price, curtime)?;
+    if same_custody {
+        collateral_custody.sync_price_state(custody);
+    }
+    if !price_accepted {
+        // keep the tripped circuit breaker, the request is closed and the escrow refunded,
+        // clients tell this apart from a fill by the CircuitBreakerEvent
+        accounts.position.close(accounts.owner.to_account_info())?;
+        return Ok(0);
+    }
+
+    // init new position
+    let position = accounts.position.as_mut();
+    let pool = accounts.pool.as_mut();
+    position.owner = request.owner;
+    position.pool = pool.key();
+    position.custody = custody.key();
+    position.collateral_custody = collateral_custody.key();
+    position.position_id = request.position_id;
+    position.bump = position_bump;
+
+    let mut trade = Trade {
+        pool,
+        custody,
+        collateral_custody,
+        same_custody,
+        prices: *prices,
+        curtime,
+    };
+
+    // fee is paid from the escrowed collateral
+    let fee_amount = trade.get_open_fee(request.size)?;
+    require!(
+        request.collateral > fee_amount,
+        PerpetualsError::InsufficientAmountReturned
+    );
+    msg!("Amount in: {}", request.collateral);
+    trade.open_position(
+        position,
+        request.side,
+        request.price,
+        request.size,
+        math::checked_sub(request.collateral, fee_amount)?,
+    )?;
+
+    // transfer tokens
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens(
+        accounts.position_request_token_account.to_account_info(),
+        accounts.collateral_custody_token_account.to_account_info(),
+        accounts.transfer_authority.to_account_info(),
+        accounts.token_program.to_account_info(),
+        request.collateral,
+    )?;
+
+    Ok(request.collateral)
+}
+
+/// Closes the whole position, returns the amount taken from escrow
+fn execute_close(
+    accounts: &mut ExecutePositionRequest,
+    request: &PositionRequest,
+    prices: &TradePrices,
+    curtime: i64,
+    degraded: bool,
+    collateral_degraded: bool,
+) -> Result<u64> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = accounts.perpetuals.as_mut();
+    let custody = accounts.custody.as_mut();
+    let collateral_custody = accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_close_position && custody.permissions.allow_close_position,
+        PerpetualsError::InstructionNotAllowed
+    );
+    custody.market_hours.check_close_position(curtime)?;
+    let same_custody = custody.key() == collateral_custody.key();
+
+    // degraded mode never updates the price state,
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    if !degraded {
+        custody.record_price(&prices.token_price, curtime)?;
+        if same_custody {
+            collateral_custody.sync_price_state(custody);
+        }
+    }
+
+    // while the circuit breaker is tripped positions are settled at the last accepted price
+    let (token_price, token_ema_price) = if degraded {
+        (prices.token_price, prices.token_ema_price)
+    } else {
+        custody.get_accepted_prices(&prices.token_price, &prices.token_ema_price, curtime)?
+    };
+    let (collateral_token_price, collateral_token_ema_price) = if collateral_degraded {
+        (
+            prices.collateral_token_price,
+            prices.collateral_token_ema_price,
+        )
+    } else {
+        collateral_custody.get_accepted_prices(
+            &prices.collateral_token_price,
+            &prices.collateral_token_ema_price,
+            curtime,
+        )?
+    };
+
+    let mut trade = Trade {
+        pool: accounts.pool.as_mut(),
+        custody,
+        collateral_custody,
+        same_custody,
+        prices: TradePrices {
+            token_price,
+            token_ema_price,
+            collateral_token_price,
+            collateral_token_ema_price,
+        },
+        curtime,
+    };
+    let transfer_amount = trade.close_position(&accounts.position, request.price)?;
+
+    // transfer tokens
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens(
+        accounts.collateral_custody_token_account.to_account_info(),
+        accounts.receiving_account.to_account_info(),
+        accounts.transfer_authority.to_account_info(),
+        accounts.token_program.to_account_info(),
+        transfer_amount,
+    )?;
+
+    accounts.position.close(accounts.owner.to_account_info())?;
+
+    Ok(0)
+}
+
+/// Adds escrowed collateral to the position, returns the amount taken from escrow
+fn execute_add_collateral(
+    accounts: &mut ExecutePositionRequest,
+    request: &PositionRequest,
+    prices: &TradePrices,
+    curtime: i64,
+) -> Result<u64> {
+    let perpetuals = accounts.perpetuals.as_mut();
+    let custody = accounts.custody.as_mut();
+    let collateral_custody = accounts.collateral_custody.as_mut();
+    custody.market_hours.check_add_collateral(curtime)?;
+    let same_custody = custody.key() == collateral_custody.key();
+    let pool = accounts.pool.as_mut();
+    let token_id = pool.get_token_id(&collateral_custody.key())?;
+
+    let mut trade = Trade {
+        pool,
+        custody,
+        collateral_custody,
+        same_custody,
+        prices: *prices,
+        curtime,
+    };
+
+    // fee is paid from the escrowed collateral, anything left over is refunded
+    let max_fee_amount = trade.pool.get_add_liquidity_fee(
+        token_id,
+        request.collateral,
+        trade.collateral_custody,
+        &prices.collateral_token_ema_price,
+    )?;
+    require!(
+        request.collateral > max_fee_amount,
+        PerpetualsError::InsufficientAmountReturned
+    );
+    let collateral = math::checked_sub(request.collateral, max_fee_amount)?;
+    let fee_amount = trade.add_collateral(&mut accounts.position, token_id, collateral)?;
+
+    let transfer_amount = math::checked_add(collateral, fee_amount)?;
+    require_gte!(
+        request.collateral,
+        transfer_amount,
+        PerpetualsError::InsufficientAmountReturned
+    );
+    msg!("Amount in: {}", transfer_amount);
+
+    // transfer tokens
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens(
+        accounts.position_request_token_account.to_account_info(),
+        accounts.collateral_custody_token_account.to_account_info(),
+        accounts.transfer_authority.to_account_info(),
+        accounts.token_program.to_account_info(),
+        transfer_amount,
+    )?;
+
+    Ok(transfer_amount)
+}
+
+/// Withdraws collateral from the position, returns the amount taken from escrow
+fn execute_remove_collateral(
+    accounts: &mut ExecutePositionRequest,
+    request: &PositionRequest,
+    prices: &TradePrices,
+    curtime: i64,
+) -> Result<u64> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = accounts.perpetuals.as_mut();
+    let custody = accounts.custody.as_mut();
+    let collateral_custody = accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_collateral_withdrawal
+            && custody.permissions.allow_collateral_withdrawal,
+        PerpetualsError::InstructionNotAllowed
+    );
+    require!(
+        !custody.is_close_only(curtime),
+        PerpetualsError::CustodyCloseOnly
+    );
+    let same_custody = custody.key() == collateral_custody.key();
+    let pool = accounts.pool.as_mut();
+    let token_id = pool.get_token_id(&collateral_custody.key())?;
+
+    // position may have changed since the request was made, the amount is checked again
+    let mut trade = Trade {
+        pool,
+        custody,
+        collateral_custody,
+        same_custody,
+        prices: *prices,
+        curtime,
+    };
+    let transfer_amount =
+        trade.remove_collateral(&mut accounts.position, token_id, request.collateral_usd)?;
+
+    // transfer tokens
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens(
+        accounts.collateral_custody_token_account.to_account_info(),
+        accounts.receiving_account.to_account_info(),
+        accounts.transfer_authority.to_account_info(),
+        accounts.token_program.to_account_info(),
+        transfer_amount,
+    )?;
+
+    Ok(0)
+}
@@ -0,0 +1,374 @@
+//! ExecuteTrigger instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        math,
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position::{Position, Side},
+        },
+    },
+    anchor_lang::prelude::*,
+    anchor_spl::token::{Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+pub struct ExecuteTrigger<'info> {
+    #[account()]
+    pub keeper: Signer<'info>,
+
+    /// CHECK: position owner, receives the position rent on full close
+    #[account(mut)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = receiving_account.mint == collateral_custody.mint,
+        has_one = owner
+    )]
+    pub receiving_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        mut,
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = collateral_custody,
+        seeds = [b"position",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the position token
+    #[account(
+        constraint = custody_oracle_account.key() == custody.oracle.oracle_account
+    )]
+    pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
+    )]
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
+
+    token_program: Program<'info, Token>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct ExecuteTriggerParams {}
+
+pub fn execute_trigger(ctx: Context<ExecuteTrigger>, _params: &ExecuteTriggerParams) -> Result<()> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    let position = ctx.accounts.position.as_mut();
+    let full_close =
+        position.trigger_size_usd == 0 || position.trigger_size_usd >= position.size_usd;
+    require!(
+        perpetuals.permissions.allow_close_position
+            && custody.permissions.allow_close_position
+            && (full_close
+                || (perpetuals.permissions.allow_size_change
+                    && custody.permissions.allow_size_change)),
+        PerpetualsError::InstructionNotAllowed
+    );
+    let pool = ctx.accounts.pool.as_mut();
+
+    // compute exit price
+    let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_close_position(curtime)?;
+
+    let token_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    custody.record_price(&token_price, curtime)?;
+    if custody.key() == collateral_custody.key() {
+        collateral_custody.sync_price_state(custody);
+    }
+
+    let token_ema_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        custody.pricing.use_ema,
+    )?;
+
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
+    // while the circuit breaker is tripped positions are settled at the last accepted price
+    let (token_price, token_ema_price) =
+        custody.get_accepted_prices(&token_price, &token_ema_price, curtime)?;
+    let (collateral_token_price, collateral_token_ema_price) = collateral_custody
+        .get_accepted_prices(
+            &collateral_token_price,
+            &collateral_token_ema_price,
+            curtime,
+        )?;
+
+    let exit_price = pool.get_exit_price(
+        &token_price,
+        &token_ema_price,
+        position.side,
+        custody,
+        curtime,
+    )?;
+    msg!("Exit price: {}", exit_price);
+
+    // check trigger and slippage bounds set by the owner
+    msg!("Check position triggers");
+    let Some(trigger_price) = position.get_trigger_price(exit_price) else {
+        return err!(PerpetualsError::TriggerNotReached);
+    };
+    msg!("Trigger price: {}", trigger_price);
+
+    let exit_bound = position.get_trigger_exit_bound(trigger_price)?;
+    if position.side == Side::Long {
+        require_gte!(exit_price, exit_bound, PerpetualsError::MaxPriceSlippage);
+    } else {
+        require_gte!(exit_bound, exit_price, PerpetualsError::MaxPriceSlippage);
+    }
+
+    msg!("Settle position");
+    let close_size_usd = if full_close {
+        position.size_usd
+    } else {
+        position.trigger_size_usd
+    };
+    let closed_position = position.get_partial(close_size_usd)?;
+    let (transfer_amount, fee_amount, profit_usd, loss_usd) = pool.get_close_amount(
+        &closed_position,
+        &token_price,
+        &token_ema_price,
+        custody,
+        &collateral_token_price,
+        &collateral_token_ema_price,
+        collateral_custody,
+        curtime,
+        false,
+    )?;
+
+    msg!("Net profit: {}, loss: {}", profit_usd, loss_usd);
+    msg!("Collected fee: {}", fee_amount);
+    msg!("Amount out: {}", transfer_amount);
+
+    // remove the position from custody stats before it is modified,
+    // collateral_custody is serialized last so it holds the stats if both are the same account
+    let same_custody = custody.key() == collateral_custody.key();
+    if same_custody {
+        collateral_custody.remove_position(position, curtime, None)?;
+    } else {
+        custody.remove_position(position, curtime, Some(collateral_custody))?;
+    }
+
+    // unlock pool funds
+    collateral_custody.unlock_funds(closed_position.locked_amount)?;
+
+    // check pool constraints
+    msg!("Check pool constraints");
+    require!(
+        pool.check_available_amount(transfer_amount, collateral_custody)?,
+        PerpetualsError::CustodyAmountLimit
+    );
+
+    if !full_close {
+        // update remaining position, triggers fire once
+        msg!("Update existing position");
+        position.remove_partial(&closed_position)?;
+
+        let interest_usd = collateral_custody.get_interest_amount_usd(position, curtime)?;
+        let (funding_paid_usd, funding_received_usd) =
+            custody.get_funding_amount_usd(position, curtime)?;
+        position.unrealized_loss_usd = math::checked_add(
+            position.unrealized_loss_usd,
+            math::checked_add(interest_usd, funding_paid_usd)?,
+        )?;
+        position.unrealized_profit_usd =
+            math::checked_add(position.unrealized_profit_usd, funding_received_usd)?;
+        position.cumulative_interest_snapshot =
+            collateral_custody.get_cumulative_interest(curtime)?;
+        position.cumulative_funding_snapshot =
+            custody.get_cumulative_funding(position.side, curtime)?;
+        position.update_time = curtime;
+        position.clear_triggers();
+
+        // check position risk
+        msg!("Check position risks");
+        require!(
+            pool.check_leverage(
+                position,
+                &token_ema_price,
+                custody,
+                &collateral_token_ema_price,
+                collateral_custody,
+                curtime,
+                false
+            )?,
+            PerpetualsError::MaxLeverage
+        );
+    }
+
+    // transfer tokens
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens(
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
+        ctx.accounts.receiving_account.to_account_info(),
+        ctx.accounts.transfer_authority.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        transfer_amount,
+    )?;
+
+    // update custody stats
+    msg!("Update custody stats");
+    collateral_custody.collected_fees.close_position_usd = collateral_custody
+        .collected_fees
+        .close_position_usd
+        .wrapping_add(
+            collateral_token_ema_price
+                .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+        );
+
+    let reserved_amount = collateral_custody.reserve_fee(fee_amount)?;
+    collateral_custody.settle_collateral(
+        closed_position.collateral_amount,
+        transfer_amount,
+        reserved_amount,
+    )?;
+
+    // loss in excess of collateral is covered by the insurance fund first
+    let bad_debt_usd = loss_usd.saturating_sub(closed_position.collateral_usd);
+    let (covered_usd, socialized_usd) =
+        collateral_custody.cover_bad_debt(bad_debt_usd, &collateral_token_ema_price, curtime)?;
+    if bad_debt_usd > 0 {
+        msg!(
+            "Bad debt covered: {}, socialized: {}",
+            covered_usd,
+            socialized_usd
+        );
+    }
+    pool.bad_debt_stats.record(covered_usd, socialized_usd, curtime);
+
+    let (custody, position_collateral_custody) = if same_custody {
+        (&mut **collateral_custody, None)
+    } else {
+        (&mut **custody, Some(&**collateral_custody))
+    };
+
+    custody.volume_stats.close_position_usd = custody
+        .volume_stats
+        .close_position_usd
+        .wrapping_add(closed_position.size_usd);
+
+    if position.side == Side::Long {
+        custody.trade_stats.oi_long_usd = custody
+            .trade_stats
+            .oi_long_usd
+            .saturating_sub(closed_position.size_usd);
+    } else {
+        custody.trade_stats.oi_short_usd = custody
+            .trade_stats
+            .oi_short_usd
+            .saturating_sub(closed_position.size_usd);
+    }
+
+    custody.trade_stats.profit_usd = custody.trade_stats.profit_usd.wrapping_add(profit_usd);
+    custody.trade_stats.loss_usd = custody.trade_stats.loss_usd.wrapping_add(loss_usd);
+
+    if !full_close {
+        custody.add_position(
+            position,
+            &collateral_token_ema_price,
+            curtime,
+            position_collateral_custody,
+        )?;
+    }
+    custody.update_funding_rate(curtime)?;
+    collateral_custody.update_borrow_rate(curtime)?;
+
+    if full_close {
+        ctx.accounts
+            .position
+            .close(ctx.accounts.owner.to_account_info())?;
+    }
+
+    Ok(())
+}
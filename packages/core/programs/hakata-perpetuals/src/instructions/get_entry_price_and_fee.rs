@@ -1,12 +1,15 @@
 //! GetEntryPriceAndFee instruction handler
 
 use {
-    crate::state::{
-        custody::Custody,
-        oracle::OraclePrice,
-        perpetuals::{NewPositionPricesAndFee, Perpetuals},
-        pool::Pool,
-        position::{Position, Side},
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            perpetuals::{NewPositionPricesAndFee, Perpetuals},
+            pool::Pool,
+            position::{Position, Side},
+        },
     },
     anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
 };
@@ -34,11 +37,25 @@ pub struct GetEntryPriceAndFee<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize)]
@@ -58,52 +75,106 @@ pub fn get_entry_price_and_fee(
     }
     let pool = &ctx.accounts.pool;
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    if params.side == Side::Long && !custody.is_synthetic {
+        require_keys_eq!(
+            custody.key(),
+            collateral_custody.key(),
+            PerpetualsError::InvalidCollateralCustody
+        );
+    } else {
+        require!(
+            collateral_custody.is_stable,
+            PerpetualsError::InvalidCollateralCustody
+        );
+    }
 
     // compute position price
     let curtime = ctx.accounts.perpetuals.get_time()?;
 
     let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         custody.pricing.use_ema,
     )?;
 
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
     let min_price = if token_price < token_ema_price {
         token_price
     } else {
         token_ema_price
     };
 
-    let entry_price = pool.get_entry_price(&token_price, &token_ema_price, params.side, custody)?;
+    let min_collateral_price = if collateral_token_price < collateral_token_ema_price {
+        collateral_token_price
+    } else {
+        collateral_token_ema_price
+    };
+
+    let entry_price = pool.get_entry_price(
+        &token_price,
+        &token_ema_price,
+        params.side,
+        custody,
+        curtime,
+    )?;
 
     let size_usd = min_price.get_asset_amount_usd(params.size, custody.decimals)?;
-    let collateral_usd = min_price.get_asset_amount_usd(params.collateral, custody.decimals)?;
+    let collateral_usd = min_collateral_price
+        .get_asset_amount_usd(params.collateral, collateral_custody.decimals)?;
+    let locked_amount =
+        pool.get_locked_amount(size_usd, custody, &min_collateral_price, collateral_custody)?;
 
     let position = Position {
         side: params.side,
         price: entry_price,
         size_usd,
         collateral_usd,
-        cumulative_interest_snapshot: custody.get_cumulative_interest(curtime)?,
+        cumulative_interest_snapshot: collateral_custody.get_cumulative_interest(curtime)?,
+        locked_amount,
+        collateral_amount: params.collateral,
         ..Position::default()
     };
 
-    let liquidation_price =
-        pool.get_liquidation_price(&position, &token_ema_price, custody, curtime)?;
+    let liquidation_price = pool.get_liquidation_price(
+        &position,
+        custody,
+        &collateral_token_ema_price,
+        collateral_custody,
+        curtime,
+    )?;
 
-    let fee = pool.get_entry_fee(params.size, custody)?;
+    let fee = pool.get_entry_fee(
+        custody.fees.open_position,
+        min_collateral_price.get_token_amount(size_usd, collateral_custody.decimals)?,
+        locked_amount,
+        collateral_custody,
+    )?;
 
     Ok(NewPositionPricesAndFee {
         entry_price,
@@ -27,11 +27,13 @@ pub struct GetExitPriceAndFee<'info> {
     pub pool: Box<Account<'info, Pool>>,
 
     #[account(
+        has_one = collateral_custody,
         seeds = [b"position",
                  position.owner.as_ref(),
                  pool.key().as_ref(),
                  custody.key().as_ref(),
-                 &[position.side as u8]],
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
         bump = position.bump
     )]
     pub position: Box<Account<'info, Position>>,
@@ -44,11 +46,25 @@ pub struct GetExitPriceAndFee<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize)]
@@ -63,28 +79,42 @@ pub fn get_exit_price_and_fee(
     let pool = &ctx.accounts.pool;
     let curtime = ctx.accounts.perpetuals.get_time()?;
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
 
     let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         custody.pricing.use_ema,
     )?;
 
-    let price = pool.get_exit_price(&token_price, &token_ema_price, position.side, custody)?;
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
+    let price = pool.get_exit_price(
+        &token_price,
+        &token_ema_price,
+        position.side,
+        custody,
+        curtime,
+    )?;
 
-    let size = token_ema_price.get_token_amount(position.size_usd, custody.decimals)?;
+    // fees are paid in collateral tokens
+    let size = collateral_token_ema_price
+        .get_token_amount(position.size_usd, collateral_custody.decimals)?;
 
     let fee = pool.get_exit_fee(size, custody)?;
 
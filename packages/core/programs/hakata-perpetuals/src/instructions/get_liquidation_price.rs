@@ -27,11 +27,13 @@ pub struct GetLiquidationPrice<'info> {
     pub pool: Box<Account<'info, Pool>>,
 
     #[account(
+        has_one = collateral_custody,
         seeds = [b"position",
                  position.owner.as_ref(),
                  pool.key().as_ref(),
                  custody.key().as_ref(),
-                 &[position.side as u8]],
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
         bump = position.bump
     )]
     pub position: Box<Account<'info, Position>>,
@@ -44,11 +46,25 @@ pub struct GetLiquidationPrice<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize)]
@@ -62,45 +78,46 @@ pub fn get_liquidation_price(
     params: &GetLiquidationPriceParams,
 ) -> Result<u64> {
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
     let curtime = ctx.accounts.perpetuals.get_time()?;
 
-    let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
-        &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
         curtime,
         false,
     )?;
 
-    let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
-        &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
         curtime,
-        custody.pricing.use_ema,
+        collateral_custody.pricing.use_ema,
     )?;
 
-    let min_price = if token_price < token_ema_price {
-        token_price
+    let min_collateral_price = if collateral_token_price < collateral_token_ema_price {
+        collateral_token_price
     } else {
-        token_ema_price
+        collateral_token_ema_price
     };
 
     let mut position = ctx.accounts.position.clone();
     position.update_time = ctx.accounts.perpetuals.get_time()?;
 
     if params.add_collateral > 0 {
-        let collateral_usd =
-            min_price.get_asset_amount_usd(params.add_collateral, custody.decimals)?;
+        let collateral_usd = min_collateral_price
+            .get_asset_amount_usd(params.add_collateral, collateral_custody.decimals)?;
         position.collateral_usd = math::checked_add(position.collateral_usd, collateral_usd)?;
         position.collateral_amount =
             math::checked_add(position.collateral_amount, params.add_collateral)?;
     }
     if params.remove_collateral > 0 {
-        let collateral_usd =
-            min_price.get_asset_amount_usd(params.remove_collateral, custody.decimals)?;
+        let collateral_usd = min_collateral_price
+            .get_asset_amount_usd(params.remove_collateral, collateral_custody.decimals)?;
         if collateral_usd >= position.collateral_usd
             || params.remove_collateral >= position.collateral_amount
         {
@@ -111,7 +128,11 @@ pub fn get_liquidation_price(
             math::checked_sub(position.collateral_amount, params.remove_collateral)?;
     }
 
-    ctx.accounts
-        .pool
-        .get_liquidation_price(&position, &token_ema_price, custody, curtime)
+    ctx.accounts.pool.get_liquidation_price(
+        &position,
+        custody,
+        &collateral_token_ema_price,
+        collateral_custody,
+        curtime,
+    )
 }
@@ -48,10 +48,8 @@ pub fn get_oracle_price(
     let curtime = ctx.accounts.perpetuals.get_time()?;
 
     let price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         params.ema,
     )?;
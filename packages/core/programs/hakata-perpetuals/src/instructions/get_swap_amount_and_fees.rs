@@ -82,45 +82,37 @@ pub fn get_swap_amount_and_fees(
     let dispensing_custody = ctx.accounts.dispensing_custody.as_mut();
 
     let received_token_price = OraclePrice::new_from_oracle(
-        receiving_custody.oracle.oracle_type,
+        &receiving_custody.oracle,
         &ctx.accounts
             .receiving_custody_oracle_account
             .to_account_info(),
-        receiving_custody.oracle.max_price_error,
-        receiving_custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let received_token_ema_price = OraclePrice::new_from_oracle(
-        receiving_custody.oracle.oracle_type,
+        &receiving_custody.oracle,
         &ctx.accounts
             .receiving_custody_oracle_account
             .to_account_info(),
-        receiving_custody.oracle.max_price_error,
-        receiving_custody.oracle.max_price_age_sec,
         curtime,
         receiving_custody.pricing.use_ema,
     )?;
 
     let dispensed_token_price = OraclePrice::new_from_oracle(
-        dispensing_custody.oracle.oracle_type,
+        &dispensing_custody.oracle,
         &ctx.accounts
             .dispensing_custody_oracle_account
             .to_account_info(),
-        dispensing_custody.oracle.max_price_error,
-        dispensing_custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let dispensed_token_ema_price = OraclePrice::new_from_oracle(
-        dispensing_custody.oracle.oracle_type,
+        &dispensing_custody.oracle,
         &ctx.accounts
             .dispensing_custody_oracle_account
             .to_account_info(),
-        dispensing_custody.oracle.max_price_error,
-        dispensing_custody.oracle.max_price_age_sec,
         curtime,
         dispensing_custody.pricing.use_ema,
     )?;
@@ -0,0 +1,68 @@
+//! GetUserPositions instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            perpetuals::{Perpetuals, UserPosition},
+            pool::Pool,
+            position::Position,
+        },
+        try_from,
+    },
+    anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
+};
+
+#[derive(Accounts)]
+pub struct GetUserPositions<'info> {
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+    // remaining accounts:
+    //   position accounts of the user in this pool (read-only, unsigned)
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct GetUserPositionsParams {
+    pub owner: Pubkey,
+}
+
+pub fn get_user_positions(
+    ctx: Context<GetUserPositions>,
+    params: &GetUserPositionsParams,
+) -> Result<Vec<UserPosition>> {
+    if ctx.remaining_accounts.len() > Perpetuals::MAX_USER_POSITIONS {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+
+    let pool_key = ctx.accounts.pool.key();
+    let mut positions = Vec::with_capacity(ctx.remaining_accounts.len());
+    for account in ctx.remaining_accounts {
+        let position = try_from!(Account<Position>, account)?;
+        require!(
+            position.owner == params.owner && position.pool == pool_key,
+            PerpetualsError::InvalidPositionState
+        );
+
+        positions.push(UserPosition {
+            position: account.key(),
+            custody: position.custody,
+            position_id: position.position_id,
+            side: position.side,
+            price: position.price,
+            size_usd: position.size_usd,
+            collateral_usd: position.collateral_usd,
+        });
+    }
+
+    Ok(positions)
+}
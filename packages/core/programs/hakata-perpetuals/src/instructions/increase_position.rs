@@ -0,0 +1,351 @@
+//! IncreasePosition instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        math,
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position::{Position, Side},
+        },
+    },
+    anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
+    anchor_spl::token::{Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+#[instruction(params: IncreasePositionParams)]
+pub struct IncreasePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = funding_account.mint == collateral_custody.mint,
+        has_one = owner
+    )]
+    pub funding_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        mut,
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = collateral_custody,
+        seeds = [b"position",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the position token
+    #[account(
+        constraint = custody_oracle_account.key() == custody.oracle.oracle_account
+    )]
+    pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
+    )]
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
+
+    token_program: Program<'info, Token>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct IncreasePositionParams {
+    pub price: u64,
+    pub collateral: u64,
+    pub size: u64,
+}
+
+pub fn increase_position(
+    ctx: Context<IncreasePosition>,
+    params: &IncreasePositionParams,
+) -> Result<()> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_open_position
+            && perpetuals.permissions.allow_size_change
+            && custody.permissions.allow_open_position
+            && custody.permissions.allow_size_change
+            && !custody.is_stable,
+        PerpetualsError::InstructionNotAllowed
+    );
+
+    // validate inputs
+    msg!("Validate inputs");
+    if params.price == 0 || params.size == 0 {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+    let position = ctx.accounts.position.as_mut();
+    let pool = ctx.accounts.pool.as_mut();
+
+    // compute position price
+    let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_open(curtime)?;
+
+    let token_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    let price_accepted = custody.record_trade_price(&token_price, curtime)?;
+    if custody.key() == collateral_custody.key() {
+        collateral_custody.sync_price_state(custody);
+    }
+    if !price_accepted {
+        // keep the tripped circuit breaker
+        return Ok(());
+    }
+
+    let token_ema_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        custody.pricing.use_ema,
+    )?;
+
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
+    let min_price = if token_price < token_ema_price {
+        token_price
+    } else {
+        token_ema_price
+    };
+
+    let min_collateral_price = if collateral_token_price < collateral_token_ema_price {
+        collateral_token_price
+    } else {
+        collateral_token_ema_price
+    };
+
+    let entry_price = pool.get_entry_price(
+        &token_price,
+        &token_ema_price,
+        position.side,
+        custody,
+        curtime,
+    )?;
+    msg!("Entry price: {}", entry_price);
+
+    if position.side == Side::Long {
+        require_gte!(params.price, entry_price, PerpetualsError::MaxPriceSlippage);
+    } else {
+        require_gte!(entry_price, params.price, PerpetualsError::MaxPriceSlippage);
+    }
+
+    // compute fee
+    let size_usd = min_price.get_asset_amount_usd(params.size, custody.decimals)?;
+    let locked_amount =
+        pool.get_locked_amount(size_usd, custody, &min_collateral_price, collateral_custody)?;
+    let fee_amount = pool.get_entry_fee(
+        custody.fees.open_position,
+        min_collateral_price.get_token_amount(size_usd, collateral_custody.decimals)?,
+        locked_amount,
+        collateral_custody,
+    )?;
+    msg!("Collected fee: {}", fee_amount);
+
+    // compute amount to transfer
+    let transfer_amount = math::checked_add(params.collateral, fee_amount)?;
+    msg!("Amount in: {}", transfer_amount);
+
+    // remove the position from custody stats before it is modified,
+    // collateral_custody is serialized last so it holds the stats if both are the same account
+    let same_custody = custody.key() == collateral_custody.key();
+    if same_custody {
+        collateral_custody.remove_position(position, curtime, None)?;
+    } else {
+        custody.remove_position(position, curtime, Some(collateral_custody))?;
+    }
+
+    // update existing position
+    msg!("Update existing position");
+    let collateral_usd = min_collateral_price
+        .get_asset_amount_usd(params.collateral, collateral_custody.decimals)?;
+
+    // settle interest accrued so far, the snapshot is reset for the new size
+    let interest_usd = collateral_custody.get_interest_amount_usd(position, curtime)?;
+    let (funding_paid_usd, funding_received_usd) =
+        custody.get_funding_amount_usd(position, curtime)?;
+    msg!("Settled interest: {}", interest_usd);
+    msg!(
+        "Settled funding paid: {}, received: {}",
+        funding_paid_usd,
+        funding_received_usd
+    );
+
+    position.update_time = curtime;
+    position.price = position.get_average_price(size_usd, entry_price)?;
+    position.size_usd = math::checked_add(position.size_usd, size_usd)?;
+    position.collateral_usd = math::checked_add(position.collateral_usd, collateral_usd)?;
+    position.unrealized_loss_usd = math::checked_add(
+        position.unrealized_loss_usd,
+        math::checked_add(interest_usd, funding_paid_usd)?,
+    )?;
+    position.unrealized_profit_usd =
+        math::checked_add(position.unrealized_profit_usd, funding_received_usd)?;
+    position.cumulative_interest_snapshot = collateral_custody.get_cumulative_interest(curtime)?;
+    position.cumulative_funding_snapshot =
+        custody.get_cumulative_funding(position.side, curtime)?;
+    position.locked_amount = math::checked_add(position.locked_amount, locked_amount)?;
+    position.collateral_amount = math::checked_add(position.collateral_amount, params.collateral)?;
+    msg!("Average price: {}", position.price);
+
+    // check position risk
+    msg!("Check position risks");
+    require!(
+        locked_amount > 0,
+        PerpetualsError::InsufficientAmountReturned
+    );
+    require!(
+        pool.check_leverage(
+            position,
+            &token_ema_price,
+            custody,
+            &collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+            true
+        )?,
+        PerpetualsError::MaxLeverage
+    );
+
+    // lock funds for potential profit payoff
+    collateral_custody.lock_funds(locked_amount)?;
+
+    // transfer tokens
+    msg!("Transfer tokens");
+    perpetuals.transfer_tokens_from_user(
+        ctx.accounts.funding_account.to_account_info(),
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
+        ctx.accounts.owner.to_account_info(),
+        ctx.accounts.token_program.to_account_info(),
+        transfer_amount,
+    )?;
+
+    // update custody stats
+    msg!("Update custody stats");
+    collateral_custody.collected_fees.open_position_usd = collateral_custody
+        .collected_fees
+        .open_position_usd
+        .wrapping_add(
+            collateral_token_ema_price
+                .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+        );
+
+    collateral_custody.assets.collateral =
+        math::checked_add(collateral_custody.assets.collateral, params.collateral)?;
+
+    collateral_custody.collect_fee(fee_amount)?;
+
+    let (custody, position_collateral_custody) = if same_custody {
+        (&mut **collateral_custody, None)
+    } else {
+        (&mut **custody, Some(&**collateral_custody))
+    };
+
+    custody.volume_stats.open_position_usd = custody
+        .volume_stats
+        .open_position_usd
+        .wrapping_add(size_usd);
+
+    if position.side == Side::Long {
+        custody.trade_stats.oi_long_usd =
+            math::checked_add(custody.trade_stats.oi_long_usd, size_usd)?;
+    } else {
+        custody.trade_stats.oi_short_usd =
+            math::checked_add(custody.trade_stats.oi_short_usd, size_usd)?;
+    }
+
+    custody.add_position(
+        position,
+        &collateral_token_ema_price,
+        curtime,
+        position_collateral_custody,
+    )?;
+    custody.update_funding_rate(curtime)?;
+    collateral_custody.update_borrow_rate(curtime)?;
+
+    Ok(())
+}
@@ -68,6 +68,7 @@ pub struct InitParams {
     pub allow_pnl_withdrawal: bool,
     pub allow_collateral_withdrawal: bool,
     pub allow_size_change: bool,
+    pub position_request_timeout_sec: u32,
 }
 
 pub fn init(ctx: Context<Init>, params: &InitParams) -> Result<()> {
@@ -92,6 +93,7 @@ pub fn init(ctx: Context<Init>, params: &InitParams) -> Result<()> {
     perpetuals.transfer_authority_bump = ctx.bumps.transfer_authority;
     perpetuals.perpetuals_bump = ctx.bumps.perpetuals;
     perpetuals.inception_time = perpetuals.get_time()?;
+    perpetuals.position_request_timeout_sec = params.position_request_timeout_sec;
 
     if !perpetuals.validate() {
         return err!(PerpetualsError::InvalidPerpetualsConfig);
@@ -23,14 +23,14 @@ pub struct Liquidate<'info> {
 
     #[account(
         mut,
-        constraint = receiving_account.mint == custody.mint,
+        constraint = receiving_account.mint == collateral_custody.mint,
         constraint = receiving_account.owner == position.owner
     )]
     pub receiving_account: Box<Account<'info, TokenAccount>>,
 
     #[account(
         mut,
-        constraint = rewards_receiving_account.mint == custody.mint,
+        constraint = rewards_receiving_account.mint == collateral_custody.mint,
         constraint = rewards_receiving_account.owner == signer.key()
     )]
     pub rewards_receiving_account: Box<Account<'info, TokenAccount>>,
@@ -58,13 +58,14 @@ pub struct Liquidate<'info> {
 
     #[account(
         mut,
+        has_one = collateral_custody,
         seeds = [b"position",
                  position.owner.as_ref(),
                  pool.key().as_ref(),
                  custody.key().as_ref(),
-                 &[position.side as u8]],
-        bump = position.bump,
-        close = signer
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
+        bump = position.bump
     )]
     pub position: Box<Account<'info, Position>>,
 
@@ -77,20 +78,35 @@ pub struct Liquidate<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
 
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
     #[account(
         mut,
         seeds = [b"custody_token_account",
                  pool.key().as_ref(),
-                 custody.mint.as_ref()],
-        bump = custody.token_account_bump
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
     )]
-    pub custody_token_account: Box<Account<'info, TokenAccount>>,
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
 
     token_program: Program<'info, Token>,
 }
@@ -98,11 +114,27 @@ pub struct Liquidate<'info> {
 #[derive(AnchorSerialize, AnchorDeserialize)]
 pub struct LiquidateParams {}
 
+pub struct LiquidationPrices {
+    pub token_price: OraclePrice,
+    pub token_ema_price: OraclePrice,
+    pub collateral_token_price: OraclePrice,
+    pub collateral_token_ema_price: OraclePrice,
+}
+
+pub struct LiquidationTokenAccounts<'info> {
+    pub collateral_custody_token_account: AccountInfo<'info>,
+    pub receiving_account: AccountInfo<'info>,
+    pub rewards_receiving_account: AccountInfo<'info>,
+    pub transfer_authority: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+}
+
 pub fn liquidate(ctx: Context<Liquidate>, _params: &LiquidateParams) -> Result<()> {
     // check permissions
     msg!("Check permissions");
     let perpetuals = ctx.accounts.perpetuals.as_mut();
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
     require!(
         perpetuals.permissions.allow_close_position && custody.permissions.allow_close_position,
         PerpetualsError::InstructionNotAllowed
@@ -114,112 +146,404 @@ pub fn liquidate(ctx: Context<Liquidate>, _params: &LiquidateParams) -> Result<(
     // check if position can be liquidated
     msg!("Check position state");
     let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_liquidation(curtime)?;
 
     let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    custody.record_price(&token_price, curtime)?;
+    if custody.key() == collateral_custody.key() {
+        collateral_custody.sync_price_state(custody);
+    }
+
     let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         custody.pricing.use_ema,
     )?;
 
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
+    // while the circuit breaker is tripped positions are settled at the last accepted price
+    let (token_price, token_ema_price) =
+        custody.get_accepted_prices(&token_price, &token_ema_price, curtime)?;
+    let (collateral_token_price, collateral_token_ema_price) = collateral_custody
+        .get_accepted_prices(
+            &collateral_token_price,
+            &collateral_token_ema_price,
+            curtime,
+        )?;
+
+    let prices = LiquidationPrices {
+        token_price,
+        token_ema_price,
+        collateral_token_price,
+        collateral_token_ema_price,
+    };
+
     require!(
-        !pool.check_leverage(position, &token_ema_price, custody, curtime, false)?,
+        !pool.check_leverage(
+            position,
+            &prices.token_ema_price,
+            custody,
+            &prices.collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+            false
+        )?,
         PerpetualsError::InvalidPositionState
     );
 
-    msg!("Settle position");
-    let (total_amount_out, fee_amount, profit_usd, loss_usd) = pool.get_close_amount(
+    let token_accounts = LiquidationTokenAccounts {
+        collateral_custody_token_account: ctx
+            .accounts
+            .collateral_custody_token_account
+            .to_account_info(),
+        receiving_account: ctx.accounts.receiving_account.to_account_info(),
+        rewards_receiving_account: ctx.accounts.rewards_receiving_account.to_account_info(),
+        transfer_authority: ctx.accounts.transfer_authority.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+    };
+
+    let closed = liquidate_position(
+        perpetuals,
+        pool,
         position,
-        &token_price,
-        &token_ema_price,
         custody,
+        collateral_custody,
+        &prices,
+        &token_accounts,
+        curtime,
+    )?;
+
+    if closed {
+        ctx.accounts
+            .position
+            .close(ctx.accounts.signer.to_account_info())?;
+    }
+
+    Ok(())
+}
+
+/// Returns the position left after `close_size_usd` is liquidated, what is left of
+/// the closed share is retained as collateral and interest and funding accrued on the
+/// remaining share are moved to its unrealized pnl.
+fn get_remaining_position(
+    pool: &Pool,
+    position: &Position,
+    close_size_usd: u64,
+    custody: &Custody,
+    collateral_custody: &Custody,
+    prices: &LiquidationPrices,
+    curtime: i64,
+) -> Result<Position> {
+    let closed_position = position.get_partial(close_size_usd)?;
+    let (retained_amount, _, _, _) = pool.get_close_amount(
+        &closed_position,
+        &prices.token_price,
+        &prices.token_ema_price,
+        custody,
+        &prices.collateral_token_price,
+        &prices.collateral_token_ema_price,
+        collateral_custody,
         curtime,
         true,
     )?;
+    let min_collateral_price = if prices.collateral_token_price < prices.collateral_token_ema_price
+    {
+        prices.collateral_token_price
+    } else {
+        prices.collateral_token_ema_price
+    };
+
+    let mut remaining_position = position.clone();
+    remaining_position.remove_partial(&closed_position)?;
+
+    let interest_usd = collateral_custody.get_interest_amount_usd(&remaining_position, curtime)?;
+    let (funding_paid_usd, funding_received_usd) =
+        custody.get_funding_amount_usd(&remaining_position, curtime)?;
+    remaining_position.unrealized_loss_usd = math::checked_add(
+        remaining_position.unrealized_loss_usd,
+        math::checked_add(interest_usd, funding_paid_usd)?,
+    )?;
+    remaining_position.unrealized_profit_usd = math::checked_add(
+        remaining_position.unrealized_profit_usd,
+        funding_received_usd,
+    )?;
+    remaining_position.cumulative_interest_snapshot =
+        collateral_custody.get_cumulative_interest(curtime)?;
+    remaining_position.cumulative_funding_snapshot =
+        custody.get_cumulative_funding(remaining_position.side, curtime)?;
+    remaining_position.collateral_amount =
+        math::checked_add(remaining_position.collateral_amount, retained_amount)?;
+    remaining_position.collateral_usd = math::checked_add(
+        remaining_position.collateral_usd,
+        min_collateral_price.get_asset_amount_usd(retained_amount, collateral_custody.decimals)?,
+    )?;
+    remaining_position.update_time = curtime;
 
-    let protocol_fee = Pool::get_fee_amount(custody.fees.protocol_share, fee_amount)?;
+    Ok(remaining_position)
+}
 
-    msg!("Net profit: {}, loss: {}", profit_usd, loss_usd);
-    msg!("Collected fee: {}", fee_amount);
+/// Liquidates an unhealthy position, only down to the target leverage if the custody
+/// allows partial liquidations and the remaining position is under max leverage.
+/// Returns true if the position has been closed in full, in which case the caller
+/// must release the position account.
+#[allow(clippy::too_many_arguments)]
+pub fn liquidate_position<'info>(
+    perpetuals: &Perpetuals,
+    pool: &mut Pool,
+    position: &mut Position,
+    custody: &mut Account<'info, Custody>,
+    collateral_custody: &mut Account<'info, Custody>,
+    prices: &LiquidationPrices,
+    token_accounts: &LiquidationTokenAccounts<'info>,
+    curtime: i64,
+) -> Result<bool> {
+    // large positions are only reduced down to the target leverage,
+    // full liquidation is the fallback once collateral is exhausted or if the
+    // remaining position would still be over max leverage
+    let close_size_usd = pool.get_partial_liquidation_size(
+        position,
+        &prices.token_price,
+        &prices.token_ema_price,
+        custody,
+        &prices.collateral_token_price,
+        &prices.collateral_token_ema_price,
+        collateral_custody,
+        curtime,
+    )?;
+    let remaining_position = if close_size_usd > 0 {
+        let remaining_position = get_remaining_position(
+            pool,
+            position,
+            close_size_usd,
+            custody,
+            collateral_custody,
+            prices,
+            curtime,
+        )?;
+        if pool.check_leverage(
+            &remaining_position,
+            &prices.token_ema_price,
+            custody,
+            &prices.collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+            false,
+        )? {
+            Some(remaining_position)
+        } else {
+            msg!("Remaining position is over max leverage");
+            None
+        }
+    } else {
+        None
+    };
+    let partial = remaining_position.is_some();
+    let closed_position = if partial {
+        msg!("Settle partial position");
+        position.get_partial(close_size_usd)?
+    } else {
+        msg!("Settle position");
+        position.get_partial(position.size_usd)?
+    };
+
+    let (total_amount_out, fee_amount, profit_usd, loss_usd) = pool.get_close_amount(
+        &closed_position,
+        &prices.token_price,
+        &prices.token_ema_price,
+        custody,
+        &prices.collateral_token_price,
+        &prices.collateral_token_ema_price,
+        collateral_custody,
+        curtime,
+        true,
+    )?;
+
+    // the penalty is only collected up to what is left of the collateral
+    let fee_usd = prices
+        .collateral_token_ema_price
+        .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?;
+    let unpaid_usd = loss_usd.saturating_sub(closed_position.collateral_usd);
+    let fee_amount = if unpaid_usd >= fee_usd {
+        0
+    } else {
+        math::checked_as_u64(math::checked_div(
+            math::checked_mul(
+                fee_amount as u128,
+                math::checked_sub(fee_usd, unpaid_usd)? as u128,
+            )?,
+            fee_usd as u128,
+        )?)?
+    };
+
+    let (reward, insurance_amount) = pool.get_liquidation_penalty_split(
+        fee_amount,
+        custody,
+        &prices.collateral_token_ema_price,
+        collateral_custody,
+    )?;
+    let protocol_fee = Pool::get_fee_amount(
+        collateral_custody.fees.protocol_share,
+        math::checked_sub(fee_amount, math::checked_add(reward, insurance_amount)?)?,
+    )?;
 
-    let reward = Pool::get_fee_amount(custody.fees.liquidation, total_amount_out)?;
-    let user_amount = math::checked_sub(total_amount_out, reward)?;
+    // on partial liquidation what is left of the closed share stays as collateral
+    let (user_amount_out, retained_amount) = if partial {
+        (0, total_amount_out)
+    } else {
+        (total_amount_out, 0)
+    };
 
-    msg!("Amount out: {}", user_amount);
+    msg!("Net profit: {}, loss: {}", profit_usd, loss_usd);
+    msg!("Collected fee: {}", fee_amount);
+    msg!("Amount out: {}", user_amount_out);
+    msg!("Retained collateral: {}", retained_amount);
     msg!("Reward: {}", reward);
+    msg!("Insurance fund share: {}", insurance_amount);
+
+    // remove the position from custody stats before it is modified,
+    // collateral_custody is serialized last so it holds the stats if both are the same account
+    let same_custody = custody.key() == collateral_custody.key();
+    if same_custody {
+        collateral_custody.remove_position(position, curtime, None)?;
+    } else {
+        custody.remove_position(position, curtime, Some(collateral_custody))?;
+    }
 
     // unlock pool funds
-    custody.unlock_funds(position.locked_amount)?;
+    collateral_custody.unlock_funds(closed_position.locked_amount)?;
 
     // check pool constraints
     msg!("Check pool constraints");
+    let transfer_amount = math::checked_add(user_amount_out, reward)?;
     require!(
-        pool.check_available_amount(total_amount_out, custody)?,
+        pool.check_available_amount(transfer_amount, collateral_custody)?,
         PerpetualsError::CustodyAmountLimit
     );
 
+    if let Some(remaining_position) = remaining_position {
+        msg!("Update existing position");
+        *position = remaining_position;
+    }
+
     // transfer tokens
     msg!("Transfer tokens");
     perpetuals.transfer_tokens(
-        ctx.accounts.custody_token_account.to_account_info(),
-        ctx.accounts.receiving_account.to_account_info(),
-        ctx.accounts.transfer_authority.to_account_info(),
-        ctx.accounts.token_program.to_account_info(),
-        user_amount,
+        token_accounts.collateral_custody_token_account.clone(),
+        token_accounts.receiving_account.clone(),
+        token_accounts.transfer_authority.clone(),
+        token_accounts.token_program.clone(),
+        user_amount_out,
     )?;
 
     perpetuals.transfer_tokens(
-        ctx.accounts.custody_token_account.to_account_info(),
-        ctx.accounts.rewards_receiving_account.to_account_info(),
-        ctx.accounts.transfer_authority.to_account_info(),
-        ctx.accounts.token_program.to_account_info(),
+        token_accounts.collateral_custody_token_account.clone(),
+        token_accounts.rewards_receiving_account.clone(),
+        token_accounts.transfer_authority.clone(),
+        token_accounts.token_program.clone(),
         reward,
     )?;
 
     // update custody stats
     msg!("Update custody stats");
-    custody.collected_fees.liquidation_usd = custody
+    collateral_custody.collected_fees.liquidation_usd = collateral_custody
         .collected_fees
         .liquidation_usd
-        .wrapping_add(token_ema_price.get_asset_amount_usd(fee_amount, custody.decimals)?);
+        .wrapping_add(
+            prices
+                .collateral_token_ema_price
+                .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+        );
+
+    collateral_custody.assets.protocol_fees =
+        math::checked_add(collateral_custody.assets.protocol_fees, protocol_fee)?;
+    collateral_custody.assets.insurance_fund =
+        math::checked_add(collateral_custody.assets.insurance_fund, insurance_amount)?;
+    collateral_custody.settle_collateral(
+        closed_position.collateral_amount,
+        math::checked_add(transfer_amount, retained_amount)?,
+        math::checked_add(protocol_fee, insurance_amount)?,
+    )?;
+    collateral_custody.assets.collateral =
+        math::checked_add(collateral_custody.assets.collateral, retained_amount)?;
+
+    // loss in excess of collateral is covered by the insurance fund first,
+    // the uncollected part of the penalty is not owed to the pool
+    let bad_debt_usd = unpaid_usd.saturating_sub(fee_usd);
+    let (covered_usd, socialized_usd) = collateral_custody.cover_bad_debt(
+        bad_debt_usd,
+        &prices.collateral_token_ema_price,
+        curtime,
+    )?;
+    if bad_debt_usd > 0 {
+        msg!(
+            "Bad debt covered: {}, socialized: {}",
+            covered_usd,
+            socialized_usd
+        );
+    }
+    pool.bad_debt_stats.record(covered_usd, socialized_usd, curtime);
 
-    custody.volume_stats.liquidation_usd =
-        math::checked_add(custody.volume_stats.liquidation_usd, position.size_usd)?;
+    let (custody, position_collateral_custody) = if same_custody {
+        (&mut **collateral_custody, None)
+    } else {
+        (&mut **custody, Some(&**collateral_custody))
+    };
 
-    let amount_lost = total_amount_out.saturating_sub(position.collateral_amount);
-    custody.assets.owned = math::checked_sub(custody.assets.owned, amount_lost)?;
-    custody.assets.collateral =
-        math::checked_sub(custody.assets.collateral, position.collateral_amount)?;
-    custody.assets.protocol_fees = math::checked_add(custody.assets.protocol_fees, protocol_fee)?;
+    custody.volume_stats.liquidation_usd = math::checked_add(
+        custody.volume_stats.liquidation_usd,
+        closed_position.size_usd,
+    )?;
 
     if position.side == Side::Long {
         custody.trade_stats.oi_long_usd = custody
             .trade_stats
             .oi_long_usd
-            .saturating_sub(position.size_usd);
+            .saturating_sub(closed_position.size_usd);
     } else {
         custody.trade_stats.oi_short_usd = custody
             .trade_stats
             .oi_short_usd
-            .saturating_sub(position.size_usd);
+            .saturating_sub(closed_position.size_usd);
     }
 
     custody.trade_stats.profit_usd = custody.trade_stats.profit_usd.wrapping_add(profit_usd);
     custody.trade_stats.loss_usd = custody.trade_stats.loss_usd.wrapping_add(loss_usd);
 
-    custody.remove_position(position, curtime)?;
-    custody.update_borrow_rate(curtime)?;
+    if partial {
+        custody.add_position(
+            position,
+            &prices.collateral_token_ema_price,
+            curtime,
+            position_collateral_custody,
+        )?;
+    }
+    custody.update_funding_rate(curtime)?;
+    collateral_custody.update_borrow_rate(curtime)?;
 
-    Ok(())
+    Ok(!partial)
 }
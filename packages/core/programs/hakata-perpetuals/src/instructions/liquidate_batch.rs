@@ -0,0 +1,268 @@
+//! LiquidateBatch instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        instructions::liquidate::{
+            liquidate_position, LiquidationPrices, LiquidationTokenAccounts,
+        },
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            perpetuals::{LiquidationSummary, Perpetuals},
+            pool::Pool,
+            position::Position,
+        },
+        try_from,
+    },
+    anchor_lang::prelude::*,
+    anchor_spl::token::{Token, TokenAccount},
+};
+
+#[derive(Accounts)]
+pub struct LiquidateBatch<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = rewards_receiving_account.mint == collateral_custody.mint,
+        constraint = rewards_receiving_account.owner == signer.key()
+    )]
+    pub rewards_receiving_account: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: empty PDA, authority for token accounts
+    #[account(
+        seeds = [b"transfer_authority"],
+        bump = perpetuals.transfer_authority_bump
+    )]
+    pub transfer_authority: AccountInfo<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        mut,
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the position token
+    #[account(
+        constraint = custody_oracle_account.key() == custody.oracle.oracle_account
+    )]
+    pub custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"custody_token_account",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
+    )]
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
+
+    token_program: Program<'info, Token>,
+    // remaining accounts:
+    //   (position, receiving_account) pairs of positions in this market (write, unsigned)
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LiquidateBatchParams {}
+
+pub fn liquidate_batch<'info>(
+    ctx: Context<'_, '_, '_, 'info, LiquidateBatch<'info>>,
+    _params: &LiquidateBatchParams,
+) -> Result<LiquidationSummary> {
+    // check permissions
+    msg!("Check permissions");
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
+    require!(
+        perpetuals.permissions.allow_close_position && custody.permissions.allow_close_position,
+        PerpetualsError::InstructionNotAllowed
+    );
+
+    // validate inputs
+    msg!("Validate inputs");
+    if !ctx.remaining_accounts.len().is_multiple_of(2)
+        || ctx.remaining_accounts.len() > Perpetuals::MAX_LIQUIDATION_BATCH * 2
+    {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+    let pool = ctx.accounts.pool.as_mut();
+
+    // prices are shared by all positions of the market
+    let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_liquidation(curtime)?;
+
+    let token_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    custody.record_price(&token_price, curtime)?;
+    if custody.key() == collateral_custody.key() {
+        collateral_custody.sync_price_state(custody);
+    }
+
+    let token_ema_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        custody.pricing.use_ema,
+    )?;
+
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
+
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
+
+    // while the circuit breaker is tripped positions are settled at the last accepted price
+    let (token_price, token_ema_price) =
+        custody.get_accepted_prices(&token_price, &token_ema_price, curtime)?;
+    let (collateral_token_price, collateral_token_ema_price) = collateral_custody
+        .get_accepted_prices(
+            &collateral_token_price,
+            &collateral_token_ema_price,
+            curtime,
+        )?;
+
+    let prices = LiquidationPrices {
+        token_price,
+        token_ema_price,
+        collateral_token_price,
+        collateral_token_ema_price,
+    };
+
+    let same_custody = custody.key() == collateral_custody.key();
+    let mut summary = LiquidationSummary::default();
+
+    for accounts in ctx.remaining_accounts.chunks(2) {
+        let position_account = &accounts[0];
+        let receiving_account = &accounts[1];
+        require!(
+            position_account.is_writable && receiving_account.is_writable,
+            ErrorCode::ConstraintMut
+        );
+
+        let mut position = try_from!(Account<Position>, position_account)?;
+        require!(
+            position.pool == pool.key()
+                && position.custody == custody.key()
+                && position.collateral_custody == collateral_custody.key(),
+            PerpetualsError::InvalidPositionState
+        );
+
+        let receiving_token_account = try_from!(Account<TokenAccount>, receiving_account)?;
+        require_keys_eq!(
+            receiving_token_account.mint,
+            collateral_custody.mint,
+            ErrorCode::ConstraintTokenMint
+        );
+        require_keys_eq!(
+            receiving_token_account.owner,
+            position.owner,
+            ErrorCode::ConstraintTokenOwner
+        );
+
+        // healthy positions are skipped so the rest of the batch still goes through
+        if pool.check_leverage(
+            &position,
+            &prices.token_ema_price,
+            custody,
+            &prices.collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+            false,
+        )? {
+            msg!("Skip healthy position {}", position_account.key());
+            summary.skipped += 1;
+            continue;
+        }
+
+        msg!("Liquidate position {}", position_account.key());
+        let token_accounts = LiquidationTokenAccounts {
+            collateral_custody_token_account: ctx
+                .accounts
+                .collateral_custody_token_account
+                .to_account_info(),
+            receiving_account: receiving_account.clone(),
+            rewards_receiving_account: ctx.accounts.rewards_receiving_account.to_account_info(),
+            transfer_authority: ctx.accounts.transfer_authority.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+
+        let closed = liquidate_position(
+            perpetuals,
+            pool,
+            &mut position,
+            custody,
+            collateral_custody,
+            &prices,
+            &token_accounts,
+            curtime,
+        )?;
+
+        // stats of a shared custody are only kept up to date in collateral_custody
+        if same_custody {
+            **custody = (**collateral_custody).clone();
+        }
+
+        if closed {
+            position.close(ctx.accounts.signer.to_account_info())?;
+            summary.liquidated.push(position_account.key());
+        } else {
+            position.exit(&crate::ID)?;
+            summary.partially_liquidated.push(position_account.key());
+        }
+    }
+
+    Ok(summary)
+}
@@ -10,6 +10,7 @@ use {
             perpetuals::Perpetuals,
             pool::Pool,
             position::{Position, Side},
+            trade::{Trade, TradePrices},
         },
     },
     anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
@@ -24,7 +25,7 @@ pub struct OpenPosition<'info> {
 
     #[account(
         mut,
-        constraint = funding_account.mint == custody.mint,
+        constraint = funding_account.mint == collateral_custody.mint,
         has_one = owner
     )]
     pub funding_account: Box<Account<'info, TokenAccount>>,
@@ -58,7 +59,8 @@ pub struct OpenPosition<'info> {
                  owner.key().as_ref(),
                  pool.key().as_ref(),
                  custody.key().as_ref(),
-                 &[params.side as u8]],
+                 &[params.side as u8],
+                 &params.position_id.to_le_bytes()],
         bump
     )]
     pub position: Box<Account<'info, Position>>,
@@ -72,20 +74,35 @@ pub struct OpenPosition<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
 
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
     #[account(
         mut,
         seeds = [b"custody_token_account",
                  pool.key().as_ref(),
-                 custody.mint.as_ref()],
-        bump = custody.token_account_bump
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
     )]
-    pub custody_token_account: Box<Account<'info, TokenAccount>>,
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
 
     system_program: Program<'info, System>,
     token_program: Program<'info, Token>,
@@ -97,6 +114,7 @@ pub struct OpenPositionParams {
     pub collateral: u64,
     pub size: u64,
     pub side: Side,
+    pub position_id: u64,
 }
 
 pub fn open_position(ctx: Context<OpenPosition>, params: &OpenPositionParams) -> Result<()> {
@@ -104,6 +122,7 @@ pub fn open_position(ctx: Context<OpenPosition>, params: &OpenPositionParams) ->
     msg!("Check permissions");
     let perpetuals = ctx.accounts.perpetuals.as_mut();
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
     require!(
         perpetuals.permissions.allow_open_position
             && custody.permissions.allow_open_position
@@ -117,138 +136,112 @@ pub fn open_position(ctx: Context<OpenPosition>, params: &OpenPositionParams) ->
     {
         return Err(ProgramError::InvalidArgument.into());
     }
-    let position = ctx.accounts.position.as_mut();
-    let pool = ctx.accounts.pool.as_mut();
-
+    // longs are backed by the position token, shorts and synthetic longs by a stablecoin
+    let same_custody = custody.key() == collateral_custody.key();
+    if params.side == Side::Long && !custody.is_synthetic {
+        require!(same_custody, PerpetualsError::InvalidCollateralCustody);
+    } else {
+        require!(
+            collateral_custody.is_stable,
+            PerpetualsError::InvalidCollateralCustody
+        );
+    }
     // compute position price
     let curtime = perpetuals.get_time()?;
+    custody.market_hours.check_open(curtime)?;
 
     let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
+    // collateral_custody is serialized last, keep its copy in sync if both are the same account
+    let price_accepted = custody.record_trade_price(&token_price, curtime)?;
+    if custody.key() == collateral_custody.key() {
+        collateral_custody.sync_price_state(custody);
+    }
+    if !price_accepted {
+        // keep the tripped circuit breaker, the position is not opened and clients
+        // tell this apart from a fill by the CircuitBreakerEvent
+        ctx.accounts
+            .position
+            .close(ctx.accounts.owner.to_account_info())?;
+        return Ok(());
+    }
+    let position = ctx.accounts.position.as_mut();
+    let pool = ctx.accounts.pool.as_mut();
+
     let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         custody.pricing.use_ema,
     )?;
 
-    let min_price = if token_price < token_ema_price {
-        token_price
-    } else {
-        token_ema_price
-    };
-
-    let position_price =
-        pool.get_entry_price(&token_price, &token_ema_price, params.side, custody)?;
-    msg!("Entry price: {}", position_price);
-
-    if params.side == Side::Long {
-        require_gte!(
-            params.price,
-            position_price,
-            PerpetualsError::MaxPriceSlippage
-        );
-    } else {
-        require_gte!(
-            position_price,
-            params.price,
-            PerpetualsError::MaxPriceSlippage
-        );
-    }
-
-    // compute fee
-    let fee_amount = pool.get_entry_fee(params.size, custody)?;
-    msg!("Collected fee: {}", fee_amount);
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
 
-    // compute amount to transfer
-    let transfer_amount = math::checked_add(params.collateral, fee_amount)?;
-    msg!("Amount in: {}", transfer_amount);
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
 
     // init new position
-    msg!("Initialize new position");
-    let size_usd = min_price.get_asset_amount_usd(params.size, custody.decimals)?;
-    let collateral_usd = min_price.get_asset_amount_usd(params.collateral, custody.decimals)?;
-
     position.owner = ctx.accounts.owner.key();
     position.pool = pool.key();
     position.custody = custody.key();
-    position.open_time = perpetuals.get_time()?;
-    position.update_time = 0;
-    position.side = params.side;
-    position.price = position_price;
-    position.size_usd = size_usd;
-    position.collateral_usd = collateral_usd;
-    position.unrealized_profit_usd = 0;
-    position.unrealized_loss_usd = 0;
-    position.cumulative_interest_snapshot = custody.get_cumulative_interest(curtime)?;
-    position.locked_amount = math::checked_as_u64(math::checked_div(
-        math::checked_mul(params.size as u128, custody.pricing.max_payoff_mult as u128)?,
-        Perpetuals::BPS_POWER,
-    )?)?;
-
-    position.collateral_amount = params.collateral;
+    position.collateral_custody = collateral_custody.key();
+    position.position_id = params.position_id;
     position.bump = ctx.bumps.position;
 
-    // check position risk
-    msg!("Check position risks");
-    require!(
-        position.locked_amount > 0,
-        PerpetualsError::InsufficientAmountReturned
-    );
-    require!(
-        pool.check_leverage(position, &token_ema_price, custody, curtime, true)?,
-        PerpetualsError::MaxLeverage
-    );
+    let mut trade = Trade {
+        pool,
+        custody,
+        collateral_custody,
+        same_custody,
+        prices: TradePrices {
+            token_price,
+            token_ema_price,
+            collateral_token_price,
+            collateral_token_ema_price,
+        },
+        curtime,
+    };
+    let fee_amount = trade.open_position(
+        position,
+        params.side,
+        params.price,
+        params.size,
+        params.collateral,
+    )?;
 
-    // lock funds for potential profit payoff
-    custody.lock_funds(position.locked_amount)?;
+    // compute amount to transfer
+    let transfer_amount = math::checked_add(params.collateral, fee_amount)?;
+    msg!("Amount in: {}", transfer_amount);
 
     // transfer tokens
     msg!("Transfer tokens");
     perpetuals.transfer_tokens_from_user(
         ctx.accounts.funding_account.to_account_info(),
-        ctx.accounts.custody_token_account.to_account_info(),
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
         ctx.accounts.owner.to_account_info(),
         ctx.accounts.token_program.to_account_info(),
         transfer_amount,
     )?;
 
-    // update custody stats
-    msg!("Update custody stats");
-    custody.collected_fees.open_position_usd = custody
-        .collected_fees
-        .open_position_usd
-        .wrapping_add(token_ema_price.get_asset_amount_usd(fee_amount, custody.decimals)?);
-
-    custody.volume_stats.open_position_usd = custody
-        .volume_stats
-        .open_position_usd
-        .wrapping_add(size_usd);
-
-    custody.assets.collateral = math::checked_add(custody.assets.collateral, params.collateral)?;
-
-    let protocol_fee = Pool::get_fee_amount(custody.fees.protocol_share, fee_amount)?;
-    custody.assets.protocol_fees = math::checked_add(custody.assets.protocol_fees, protocol_fee)?;
-
-    if params.side == Side::Long {
-        custody.trade_stats.oi_long_usd =
-            math::checked_add(custody.trade_stats.oi_long_usd, size_usd)?;
-    } else {
-        custody.trade_stats.oi_short_usd =
-            math::checked_add(custody.trade_stats.oi_short_usd, size_usd)?;
-    }
-
-    custody.add_position(position, &token_ema_price, curtime)?;
-    custody.update_borrow_rate(curtime)?;
-
     Ok(())
 }
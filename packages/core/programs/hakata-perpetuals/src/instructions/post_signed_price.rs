@@ -0,0 +1,119 @@
+//! PostSignedPrice instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody,
+            oracle::{OracleType, SignedOracle, SignedPriceMessage},
+            perpetuals::Perpetuals,
+            pool::Pool,
+        },
+    },
+    anchor_lang::{
+        prelude::*,
+        solana_program::sysvar::instructions::{
+            load_current_index_checked, load_instruction_at_checked,
+        },
+    },
+};
+
+#[derive(Accounts)]
+pub struct PostSignedPrice<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = SignedOracle::LEN,
+        constraint = signed_oracle.key() == custody.oracle.oracle_account,
+        seeds = [b"signed_oracle",
+                 custody.key().as_ref()],
+        bump
+    )]
+    pub signed_oracle: Box<Account<'info, SignedOracle>>,
+
+    /// CHECK: instructions sysvar, the ed25519 signature check must precede this instruction
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+
+    system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct PostSignedPriceParams {}
+
+pub fn post_signed_price(
+    ctx: Context<PostSignedPrice>,
+    _params: &PostSignedPriceParams,
+) -> Result<()> {
+    // validate inputs
+    msg!("Validate inputs");
+    let custody = ctx.accounts.custody.as_mut();
+    require!(
+        custody.oracle.oracle_type == OracleType::Signed,
+        PerpetualsError::UnsupportedOracle
+    );
+
+    // read the message verified by the preceding ed25519 instruction
+    msg!("Verify signed price");
+    let instructions = &ctx.accounts.instructions;
+    let current_index = load_current_index_checked(instructions)?;
+    require!(current_index > 0, PerpetualsError::InvalidSignedPrice);
+    let ed25519_instruction =
+        load_instruction_at_checked(current_index as usize - 1, instructions)?;
+    let message = SignedPriceMessage::try_from_ed25519_instruction(
+        &ed25519_instruction,
+        &custody.oracle.publisher,
+    )?;
+    require!(
+        message.feed_id == custody.oracle.feed_id,
+        PerpetualsError::OracleFeedMismatch
+    );
+    let curtime = ctx.accounts.perpetuals.get_time()?;
+    message.check_publish_time(custody.last_signed_price_time, curtime)?;
+    msg!(
+        "Signed price: {}, expo: {}, conf: {}, publish time: {}",
+        message.price,
+        message.expo,
+        message.conf,
+        message.publish_time
+    );
+
+    // record signed price
+    msg!("Record signed price");
+    custody.last_signed_price_time = message.publish_time;
+
+    let signed_oracle = ctx.accounts.signed_oracle.as_mut();
+    signed_oracle.custody = custody.key();
+    signed_oracle.price = message.price;
+    signed_oracle.expo = message.expo;
+    signed_oracle.conf = message.conf;
+    signed_oracle.publish_time = message.publish_time;
+    signed_oracle.bump = ctx.bumps.signed_oracle;
+
+    Ok(())
+}
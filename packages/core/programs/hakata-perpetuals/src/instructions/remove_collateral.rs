@@ -3,10 +3,13 @@
 use {
     crate::{
         error::PerpetualsError,
-        math,
         state::{
-            custody::Custody, oracle::OraclePrice, perpetuals::Perpetuals, pool::Pool,
+            custody::Custody,
+            oracle::OraclePrice,
+            perpetuals::Perpetuals,
+            pool::Pool,
             position::Position,
+            trade::{Trade, TradePrices},
         },
     },
     anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
@@ -21,7 +24,7 @@ pub struct RemoveCollateral<'info> {
 
     #[account(
         mut,
-        constraint = receiving_account.mint == custody.mint,
+        constraint = receiving_account.mint == collateral_custody.mint,
         has_one = owner
     )]
     pub receiving_account: Box<Account<'info, TokenAccount>>,
@@ -50,11 +53,13 @@ pub struct RemoveCollateral<'info> {
     #[account(
         mut,
         has_one = owner,
+        has_one = collateral_custody,
         seeds = [b"position",
                  owner.key().as_ref(),
                  pool.key().as_ref(),
                  custody.key().as_ref(),
-                 &[position.side as u8]],
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
         bump = position.bump
     )]
     pub position: Box<Account<'info, Position>>,
@@ -68,20 +73,35 @@ pub struct RemoveCollateral<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
-    /// CHECK: oracle account for the collateral token
+    /// CHECK: oracle account for the position token
     #[account(
         constraint = custody_oracle_account.key() == custody.oracle.oracle_account
     )]
     pub custody_oracle_account: AccountInfo<'info>,
 
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.bump
+    )]
+    pub collateral_custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the collateral token
+    #[account(
+        constraint = collateral_custody_oracle_account.key() == collateral_custody.oracle.oracle_account
+    )]
+    pub collateral_custody_oracle_account: AccountInfo<'info>,
+
     #[account(
         mut,
         seeds = [b"custody_token_account",
                  pool.key().as_ref(),
-                 custody.mint.as_ref()],
-        bump = custody.token_account_bump
+                 collateral_custody.mint.as_ref()],
+        bump = collateral_custody.token_account_bump
     )]
-    pub custody_token_account: Box<Account<'info, TokenAccount>>,
+    pub collateral_custody_token_account: Box<Account<'info, TokenAccount>>,
 
     token_program: Program<'info, Token>,
 }
@@ -99,6 +119,7 @@ pub fn remove_collateral(
     msg!("Check permissions");
     let perpetuals = ctx.accounts.perpetuals.as_mut();
     let custody = ctx.accounts.custody.as_mut();
+    let collateral_custody = ctx.accounts.collateral_custody.as_mut();
     require!(
         perpetuals.permissions.allow_collateral_withdrawal
             && custody.permissions.allow_collateral_withdrawal,
@@ -112,84 +133,74 @@ pub fn remove_collateral(
         return Err(ProgramError::InvalidArgument.into());
     }
     let pool = ctx.accounts.pool.as_mut();
-    let token_id = pool.get_token_id(&custody.key())?;
+    let token_id = pool.get_token_id(&collateral_custody.key())?;
 
     // compute position price
     let curtime = perpetuals.get_time()?;
+    require!(
+        !custody.is_close_only(curtime),
+        PerpetualsError::CustodyCloseOnly
+    );
 
     let token_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let token_ema_price = OraclePrice::new_from_oracle(
-        custody.oracle.oracle_type,
+        &custody.oracle,
         &ctx.accounts.custody_oracle_account.to_account_info(),
-        custody.oracle.max_price_error,
-        custody.oracle.max_price_age_sec,
         curtime,
         custody.pricing.use_ema,
     )?;
 
-    let max_price = if token_price > token_ema_price {
-        token_price
-    } else {
-        token_ema_price
-    };
-
-    // compute fee
-    let collateral = max_price.get_token_amount(params.collateral_usd, custody.decimals)?;
-    let fee_amount =
-        pool.get_remove_liquidity_fee(token_id, collateral, custody, &token_ema_price)?;
-    msg!("Collected fee: {}", fee_amount);
-
-    // compute amount to transfer
-    if collateral > position.collateral_amount {
-        return Err(ProgramError::InsufficientFunds.into());
-    }
-    let transfer_amount = math::checked_sub(collateral, fee_amount)?;
-    msg!("Amount out: {}", transfer_amount);
+    let collateral_token_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        false,
+    )?;
 
-    // update existing position
-    msg!("Update existing position");
-    position.update_time = perpetuals.get_time()?;
-    position.collateral_usd = math::checked_sub(position.collateral_usd, params.collateral_usd)?;
-    position.collateral_amount = math::checked_sub(position.collateral_amount, collateral)?;
+    let collateral_token_ema_price = OraclePrice::new_from_oracle(
+        &collateral_custody.oracle,
+        &ctx.accounts
+            .collateral_custody_oracle_account
+            .to_account_info(),
+        curtime,
+        collateral_custody.pricing.use_ema,
+    )?;
 
-    // check position risk
-    msg!("Check position risks");
-    require!(
-        pool.check_leverage(position, &token_ema_price, custody, curtime, true)?,
-        PerpetualsError::MaxLeverage
-    );
+    let same_custody = custody.key() == collateral_custody.key();
+    let mut trade = Trade {
+        pool,
+        custody,
+        collateral_custody,
+        same_custody,
+        prices: TradePrices {
+            token_price,
+            token_ema_price,
+            collateral_token_price,
+            collateral_token_ema_price,
+        },
+        curtime,
+    };
+    let transfer_amount = trade.remove_collateral(position, token_id, params.collateral_usd)?;
 
     // transfer tokens
     msg!("Transfer tokens");
     perpetuals.transfer_tokens(
-        ctx.accounts.custody_token_account.to_account_info(),
+        ctx.accounts
+            .collateral_custody_token_account
+            .to_account_info(),
         ctx.accounts.receiving_account.to_account_info(),
         ctx.accounts.transfer_authority.to_account_info(),
         ctx.accounts.token_program.to_account_info(),
         transfer_amount,
     )?;
 
-    // update custody stats
-    msg!("Update custody stats");
-    custody.collected_fees.open_position_usd = custody
-        .collected_fees
-        .open_position_usd
-        .wrapping_add(token_ema_price.get_asset_amount_usd(fee_amount, custody.decimals)?);
-
-    custody.assets.collateral = math::checked_sub(custody.assets.collateral, collateral)?;
-
-    let protocol_fee = Pool::get_fee_amount(custody.fees.protocol_share, fee_amount)?;
-    custody.assets.protocol_fees = math::checked_add(custody.assets.protocol_fees, protocol_fee)?;
-
-    custody.remove_collateral(position.side, params.collateral_usd)?;
-
     Ok(())
 }
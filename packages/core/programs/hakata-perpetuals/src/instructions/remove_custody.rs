@@ -62,6 +62,7 @@ pub struct RemoveCustody<'info> {
     )]
     pub custody: Box<Account<'info, Custody>>,
 
+    // not used by synthetic custodies
     #[account(
         mut,
         seeds = [b"custody_token_account",
@@ -69,7 +70,7 @@ pub struct RemoveCustody<'info> {
                  custody.mint.as_ref()],
         bump = custody.token_account_bump,
     )]
-    pub custody_token_account: Box<Account<'info, TokenAccount>>,
+    pub custody_token_account: Option<Box<Account<'info, TokenAccount>>>,
 
     system_program: Program<'info, System>,
     token_program: Program<'info, Token>,
@@ -108,9 +109,15 @@ pub fn remove_custody<'info>(
     }
 
     require!(
-        ctx.accounts.custody_token_account.amount == 0,
+        ctx.accounts.custody.is_synthetic == ctx.accounts.custody_token_account.is_none(),
         PerpetualsError::InvalidCustodyState
     );
+    if let Some(custody_token_account) = &ctx.accounts.custody_token_account {
+        require!(
+            custody_token_account.amount == 0,
+            PerpetualsError::InvalidCustodyState
+        );
+    }
 
     // remove token from the list
     let pool = ctx.accounts.pool.as_mut();
@@ -121,16 +128,18 @@ pub fn remove_custody<'info>(
         return err!(PerpetualsError::InvalidPoolConfig);
     }
 
-    Perpetuals::close_token_account(
-        ctx.accounts.transfer_authority.to_account_info(),
-        ctx.accounts.custody_token_account.to_account_info(),
-        ctx.accounts.token_program.to_account_info(),
-        ctx.accounts.transfer_authority.to_account_info(),
-        &[&[
-            b"transfer_authority",
-            &[ctx.accounts.perpetuals.transfer_authority_bump],
-        ]],
-    )?;
+    if let Some(custody_token_account) = &ctx.accounts.custody_token_account {
+        Perpetuals::close_token_account(
+            ctx.accounts.transfer_authority.to_account_info(),
+            custody_token_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.transfer_authority.to_account_info(),
+            &[&[
+                b"transfer_authority",
+                &[ctx.accounts.perpetuals.transfer_authority_bump],
+            ]],
+        )?;
+    }
 
     Ok(0)
 }
@@ -0,0 +1,69 @@
+//! ResetCircuitBreaker instruction handler
+
+use {
+    crate::state::{
+        custody::{CircuitBreakerState, Custody},
+        multisig::{AdminInstruction, Multisig},
+        pool::Pool,
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+pub struct ResetCircuitBreaker<'info> {
+    #[account()]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.load()?.bump
+    )]
+    pub multisig: AccountLoader<'info, Multisig>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct ResetCircuitBreakerParams {}
+
+pub fn reset_circuit_breaker<'info>(
+    ctx: Context<'_, '_, '_, 'info, ResetCircuitBreaker<'info>>,
+    params: &ResetCircuitBreakerParams,
+) -> Result<u8> {
+    // validate signatures
+    let mut multisig = ctx.accounts.multisig.load_mut()?;
+
+    let signatures_left = multisig.sign_multisig(
+        &ctx.accounts.admin,
+        &Multisig::get_account_infos(&ctx)[1..],
+        &Multisig::get_instruction_data(AdminInstruction::ResetCircuitBreaker, params)?,
+    )?;
+    if signatures_left > 0 {
+        msg!(
+            "Instruction has been signed but more signatures are required: {}",
+            signatures_left
+        );
+        return Ok(signatures_left);
+    }
+
+    // the next oracle price becomes the new reference
+    let custody = ctx.accounts.custody.as_mut();
+    custody.circuit_breaker_state = CircuitBreakerState::default();
+
+    Ok(0)
+}
@@ -4,7 +4,11 @@ use {
     crate::{
         error::PerpetualsError,
         state::{
-            custody::{BorrowRateParams, Custody, Fees, OracleParams, PricingParams},
+            custody::{
+                BorrowRateParams, CircuitBreakerParams, Custody, DegradedModeParams, Fees,
+                FundingRateParams, OracleParams, PricingParams, TwapParams, TwapState,
+            },
+            market_hours::MarketHours,
             multisig::{AdminInstruction, Multisig},
             perpetuals::Permissions,
             pool::{Pool, TokenRatios},
@@ -51,6 +55,11 @@ pub struct SetCustodyConfigParams {
     pub permissions: Permissions,
     pub fees: Fees,
     pub borrow_rate: BorrowRateParams,
+    pub funding_rate: FundingRateParams,
+    pub market_hours: MarketHours,
+    pub circuit_breaker: CircuitBreakerParams,
+    pub twap: TwapParams,
+    pub degraded_mode: DegradedModeParams,
     pub ratios: Vec<TokenRatios>,
 }
 
@@ -94,8 +103,17 @@ pub fn set_custody_config<'info>(
     custody.permissions = params.permissions;
     custody.fees = params.fees;
     custody.borrow_rate = params.borrow_rate;
+    custody.funding_rate = params.funding_rate;
+    custody.market_hours = params.market_hours;
+    custody.circuit_breaker = params.circuit_breaker;
+    // observations were sampled for the previous window
+    if custody.twap.window_sec != params.twap.window_sec {
+        custody.twap_state = TwapState::default();
+    }
+    custody.twap = params.twap;
+    custody.degraded_mode = params.degraded_mode;
 
-    if !custody.validate() {
+    if !custody.validate() || !pool.quote.validate_custody(custody) {
         err!(PerpetualsError::InvalidCustodyConfig)
     } else {
         Ok(0)
@@ -0,0 +1,68 @@
+//! SetPerpetualsConfig instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            multisig::{AdminInstruction, Multisig},
+            perpetuals::Perpetuals,
+        },
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+pub struct SetPerpetualsConfig<'info> {
+    #[account()]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.load()?.bump
+    )]
+    pub multisig: AccountLoader<'info, Multisig>,
+
+    #[account(
+        mut,
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SetPerpetualsConfigParams {
+    pub position_request_timeout_sec: u32,
+}
+
+pub fn set_perpetuals_config<'info>(
+    ctx: Context<'_, '_, '_, 'info, SetPerpetualsConfig<'info>>,
+    params: &SetPerpetualsConfigParams,
+) -> Result<u8> {
+    // validate signatures
+    let mut multisig = ctx.accounts.multisig.load_mut()?;
+
+    let signatures_left = multisig.sign_multisig(
+        &ctx.accounts.admin,
+        &Multisig::get_account_infos(&ctx)[1..],
+        &Multisig::get_instruction_data(AdminInstruction::SetPerpetualsConfig, params)?,
+    )?;
+    if signatures_left > 0 {
+        msg!(
+            "Instruction has been signed but more signatures are required: {}",
+            signatures_left
+        );
+        return Ok(signatures_left);
+    }
+
+    // update config
+    let perpetuals = ctx.accounts.perpetuals.as_mut();
+    perpetuals.position_request_timeout_sec = params.position_request_timeout_sec;
+
+    if !perpetuals.validate() {
+        err!(PerpetualsError::InvalidPerpetualsConfig)
+    } else {
+        Ok(0)
+    }
+}
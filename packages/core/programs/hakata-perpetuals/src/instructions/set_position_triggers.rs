@@ -0,0 +1,83 @@
+//! SetPositionTriggers instruction handler
+
+use {
+    crate::state::{
+        perpetuals::Perpetuals,
+        pool::Pool,
+        position::{Position, Side},
+    },
+    anchor_lang::{prelude::*, solana_program::program_error::ProgramError},
+};
+
+#[derive(Accounts)]
+pub struct SetPositionTriggers<'info> {
+    #[account()]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = pool,
+        seeds = [b"position",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 position.custody.as_ref(),
+                 &[position.side as u8],
+                 &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct SetPositionTriggersParams {
+    pub stop_loss_price: u64,
+    pub take_profit_price: u64,
+    pub size_usd: u64,
+    pub slippage: u64,
+}
+
+pub fn set_position_triggers(
+    ctx: Context<SetPositionTriggers>,
+    params: &SetPositionTriggersParams,
+) -> Result<()> {
+    // validate inputs
+    msg!("Validate inputs");
+    let position = ctx.accounts.position.as_mut();
+    if params.slippage as u128 > Perpetuals::BPS_POWER || params.size_usd > position.size_usd {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+    // stop loss must be on the losing side of take profit
+    if params.stop_loss_price > 0 && params.take_profit_price > 0 {
+        let valid = if position.side == Side::Long {
+            params.stop_loss_price < params.take_profit_price
+        } else {
+            params.stop_loss_price > params.take_profit_price
+        };
+        if !valid {
+            return Err(ProgramError::InvalidArgument.into());
+        }
+    }
+
+    // update position
+    msg!("Update position triggers");
+    position.stop_loss_price = params.stop_loss_price;
+    position.take_profit_price = params.take_profit_price;
+    position.trigger_size_usd = params.size_usd;
+    position.trigger_slippage = params.slippage;
+
+    Ok(())
+}
@@ -131,53 +131,56 @@ pub fn swap(ctx: Context<Swap>, params: &SwapParams) -> Result<()> {
     // compute token amount returned to the user
     let pool = ctx.accounts.pool.as_mut();
     let curtime = perpetuals.get_time()?;
+    receiving_custody.market_hours.check_open(curtime)?;
+    dispensing_custody.market_hours.check_open(curtime)?;
     let token_id_in = pool.get_token_id(&receiving_custody.key())?;
     let token_id_out = pool.get_token_id(&dispensing_custody.key())?;
 
     let received_token_price = OraclePrice::new_from_oracle(
-        receiving_custody.oracle.oracle_type,
+        &receiving_custody.oracle,
         &ctx.accounts
             .receiving_custody_oracle_account
             .to_account_info(),
-        receiving_custody.oracle.max_price_error,
-        receiving_custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let received_token_ema_price = OraclePrice::new_from_oracle(
-        receiving_custody.oracle.oracle_type,
+        &receiving_custody.oracle,
         &ctx.accounts
             .receiving_custody_oracle_account
             .to_account_info(),
-        receiving_custody.oracle.max_price_error,
-        receiving_custody.oracle.max_price_age_sec,
         curtime,
         receiving_custody.pricing.use_ema,
     )?;
 
     let dispensed_token_price = OraclePrice::new_from_oracle(
-        dispensing_custody.oracle.oracle_type,
+        &dispensing_custody.oracle,
         &ctx.accounts
             .dispensing_custody_oracle_account
             .to_account_info(),
-        dispensing_custody.oracle.max_price_error,
-        dispensing_custody.oracle.max_price_age_sec,
         curtime,
         false,
     )?;
 
     let dispensed_token_ema_price = OraclePrice::new_from_oracle(
-        dispensing_custody.oracle.oracle_type,
+        &dispensing_custody.oracle,
         &ctx.accounts
             .dispensing_custody_oracle_account
             .to_account_info(),
-        dispensing_custody.oracle.max_price_error,
-        dispensing_custody.oracle.max_price_age_sec,
         curtime,
         dispensing_custody.pricing.use_ema,
     )?;
 
+    let received_price_accepted =
+        receiving_custody.record_trade_price(&received_token_price, curtime)?;
+    let dispensed_price_accepted =
+        dispensing_custody.record_trade_price(&dispensed_token_price, curtime)?;
+    if !received_price_accepted || !dispensed_price_accepted {
+        // keep the tripped circuit breaker
+        return Ok(());
+    }
+
     msg!("Compute swap amount");
     let amount_out = pool.get_swap_amount(
         &received_token_price,
@@ -58,6 +58,7 @@ pub struct TestInitParams {
     pub allow_pnl_withdrawal: bool,
     pub allow_collateral_withdrawal: bool,
     pub allow_size_change: bool,
+    pub position_request_timeout_sec: u32,
 }
 
 pub fn test_init(ctx: Context<TestInit>, params: &TestInitParams) -> Result<()> {
@@ -90,6 +91,7 @@ pub fn test_init(ctx: Context<TestInit>, params: &TestInitParams) -> Result<()>
     } else {
         perpetuals.get_time()?
     };
+    perpetuals.position_request_timeout_sec = params.position_request_timeout_sec;
 
     if !perpetuals.validate() {
         return err!(PerpetualsError::InvalidPerpetualsConfig);
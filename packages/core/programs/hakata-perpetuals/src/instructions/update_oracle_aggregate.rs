@@ -0,0 +1,146 @@
+//! UpdateOracleAggregate instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody,
+            oracle::{DeviationPolicy, OracleAggregate, OraclePrice, OracleType},
+            perpetuals::Perpetuals,
+            pool::Pool,
+        },
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+pub struct UpdateOracleAggregate<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = OracleAggregate::LEN,
+        constraint = oracle_aggregate.key() == custody.oracle.oracle_account,
+        seeds = [b"oracle_aggregate",
+                 custody.key().as_ref()],
+        bump
+    )]
+    pub oracle_aggregate: Box<Account<'info, OracleAggregate>>,
+
+    system_program: Program<'info, System>,
+    // remaining accounts:
+    //   oracle accounts of the configured custody.oracle.sources, in order (read-only, unsigned)
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct UpdateOracleAggregateParams {}
+
+pub fn update_oracle_aggregate(
+    ctx: Context<UpdateOracleAggregate>,
+    _params: &UpdateOracleAggregateParams,
+) -> Result<()> {
+    // validate inputs
+    msg!("Validate inputs");
+    let custody = ctx.accounts.custody.as_mut();
+    require!(
+        custody.oracle.oracle_type == OracleType::Aggregate,
+        PerpetualsError::UnsupportedOracle
+    );
+    let sources = custody
+        .oracle
+        .sources
+        .iter()
+        .filter(|source| source.oracle_type != OracleType::None)
+        .collect::<Vec<_>>();
+    if ctx.remaining_accounts.len() != sources.len() {
+        return Err(ProgramError::NotEnoughAccountKeys.into());
+    }
+
+    // read sources, unavailable ones are skipped
+    msg!("Read oracle sources");
+    let curtime = ctx.accounts.perpetuals.get_time()?;
+    let mut prices = Vec::with_capacity(sources.len());
+    let mut ema_prices = Vec::with_capacity(sources.len());
+    let mut publish_time = curtime;
+
+    for (source, oracle_account) in sources.iter().zip(ctx.remaining_accounts) {
+        require_keys_eq!(
+            oracle_account.key(),
+            source.oracle_account,
+            PerpetualsError::InvalidOracleAccount
+        );
+        let params = custody.oracle.get_source_params(source);
+        let (Ok(price), Ok(ema_price), Ok(source_publish_time)) = (
+            OraclePrice::new_from_oracle(&params, oracle_account, curtime, false),
+            OraclePrice::new_from_oracle(&params, oracle_account, curtime, true),
+            OraclePrice::get_publish_time(&params, oracle_account),
+        ) else {
+            msg!("Skip unavailable oracle source {}", oracle_account.key());
+            continue;
+        };
+        prices.push(price);
+        ema_prices.push(ema_price);
+        publish_time = std::cmp::min(publish_time, source_publish_time);
+    }
+
+    // too few available sources are handled like deviating ones
+    let quorum_met = custody.oracle.check_quorum(prices.len())?;
+
+    // compute aggregate price
+    msg!("Compute aggregate price");
+    let price = OracleAggregate::combine(&prices, custody.oracle.aggregation)?;
+    let ema_price = OracleAggregate::combine(&ema_prices, custody.oracle.aggregation)?;
+    let deviation = OracleAggregate::get_deviation(&prices, &price)?;
+    msg!(
+        "Aggregate price: {}, sources: {}, deviation: {}",
+        price.price,
+        prices.len(),
+        deviation
+    );
+
+    let sources_deviate = !quorum_met || deviation > custody.oracle.max_source_deviation;
+    if sources_deviate && custody.oracle.deviation_policy == DeviationPolicy::Reject {
+        return err!(PerpetualsError::OracleSourcesDeviate);
+    }
+    if sources_deviate != custody.oracle_sources_deviate {
+        msg!("Close-only mode: {}", sources_deviate);
+        custody.oracle_sources_deviate = sources_deviate;
+    }
+
+    // record aggregate price
+    msg!("Record aggregate price");
+    let oracle_aggregate = ctx.accounts.oracle_aggregate.as_mut();
+    oracle_aggregate.custody = custody.key();
+    oracle_aggregate.price = price.price;
+    oracle_aggregate.ema_price = ema_price.price;
+    oracle_aggregate.expo = price.exponent;
+    oracle_aggregate.publish_time = publish_time;
+    oracle_aggregate.num_sources = prices.len() as u8;
+    oracle_aggregate.bump = ctx.bumps.oracle_aggregate;
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! UpdateOracleComposite instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody,
+            oracle::{OracleComposite, OraclePrice, OracleType},
+            perpetuals::Perpetuals,
+            pool::Pool,
+        },
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+pub struct UpdateOracleComposite<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = OracleComposite::LEN,
+        constraint = oracle_composite.key() == custody.oracle.oracle_account,
+        seeds = [b"oracle_composite",
+                 custody.key().as_ref()],
+        bump
+    )]
+    pub oracle_composite: Box<Account<'info, OracleComposite>>,
+
+    system_program: Program<'info, System>,
+    // remaining accounts:
+    //   oracle accounts of the configured custody.oracle.sources, in order (read-only, unsigned)
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct UpdateOracleCompositeParams {}
+
+pub fn update_oracle_composite(
+    ctx: Context<UpdateOracleComposite>,
+    _params: &UpdateOracleCompositeParams,
+) -> Result<()> {
+    // validate inputs
+    msg!("Validate inputs");
+    let custody = ctx.accounts.custody.as_ref();
+    require!(
+        custody.oracle.oracle_type == OracleType::Composite,
+        PerpetualsError::UnsupportedOracle
+    );
+    let sources = custody
+        .oracle
+        .sources
+        .iter()
+        .take_while(|source| source.oracle_type != OracleType::None)
+        .collect::<Vec<_>>();
+    if ctx.remaining_accounts.len() != sources.len() {
+        return Err(ProgramError::NotEnoughAccountKeys.into());
+    }
+
+    // read sources, every one of them is required
+    msg!("Read oracle sources");
+    let curtime = ctx.accounts.perpetuals.get_time()?;
+    let mut prices = Vec::with_capacity(sources.len());
+    let mut ema_prices = Vec::with_capacity(sources.len());
+    let mut publish_time = curtime;
+
+    for (source, oracle_account) in sources.iter().zip(ctx.remaining_accounts) {
+        require_keys_eq!(
+            oracle_account.key(),
+            source.oracle_account,
+            PerpetualsError::InvalidOracleAccount
+        );
+        let params = custody.oracle.get_source_params(source);
+        prices.push(OraclePrice::new_from_oracle(
+            &params,
+            oracle_account,
+            curtime,
+            false,
+        )?);
+        ema_prices.push(OraclePrice::new_from_oracle(
+            &params,
+            oracle_account,
+            curtime,
+            true,
+        )?);
+        publish_time = std::cmp::min(
+            publish_time,
+            OraclePrice::get_publish_time(&params, oracle_account)?,
+        );
+    }
+
+    // compute composite price
+    msg!("Compute composite price");
+    let price = OracleComposite::compose(&prices, custody.oracle.composition)?;
+    let ema_price = OracleComposite::compose(&ema_prices, custody.oracle.composition)?;
+    msg!("Composite price: {}, conf: {}", price.price, price.conf);
+
+    // record composite price
+    msg!("Record composite price");
+    let oracle_composite = ctx.accounts.oracle_composite.as_mut();
+    oracle_composite.custody = custody.key();
+    oracle_composite.price = price.price;
+    oracle_composite.ema_price = ema_price.price;
+    oracle_composite.expo = price.exponent;
+    oracle_composite.conf = price.conf;
+    oracle_composite.ema_conf = ema_price.conf;
+    oracle_composite.publish_time = publish_time;
+    oracle_composite.bump = ctx.bumps.oracle_composite;
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! UpdateTwap instruction handler
+
+use {
+    crate::state::{custody::Custody, oracle::OraclePrice, perpetuals::Perpetuals, pool::Pool},
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+pub struct UpdateTwap<'info> {
+    #[account()]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    #[account(
+        mut,
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    /// CHECK: oracle account for the custody token
+    #[account(
+        constraint = custody_oracle_account.key() == custody.oracle.oracle_account
+    )]
+    pub custody_oracle_account: AccountInfo<'info>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct UpdateTwapParams {}
+
+pub fn update_twap(ctx: Context<UpdateTwap>, _params: &UpdateTwapParams) -> Result<()> {
+    // record the price like trades do, this also saves circuit breaker trips
+    // and skips accumulation while the breaker is tripped
+    msg!("Update TWAP accumulator");
+    let custody = ctx.accounts.custody.as_mut();
+    let curtime = ctx.accounts.perpetuals.get_time()?;
+    let token_price = OraclePrice::new_from_oracle(
+        &custody.oracle,
+        &ctx.accounts.custody_oracle_account.to_account_info(),
+        curtime,
+        false,
+    )?;
+    custody.record_price(&token_price, curtime)?;
+
+    if custody.is_circuit_breaker_tripped(curtime) {
+        msg!("Circuit breaker tripped");
+    } else if custody.twap.window_sec > 0 {
+        msg!("TWAP: {}", custody.get_twap_price(curtime)?.price);
+    }
+
+    Ok(())
+}
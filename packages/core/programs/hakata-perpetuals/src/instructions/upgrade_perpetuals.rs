@@ -0,0 +1,94 @@
+//! UpgradePerpetuals instruction handler
+
+use {
+    crate::state::{
+        multisig::{AdminInstruction, Multisig},
+        perpetuals::{DeprecatedPerpetuals, Perpetuals},
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+pub struct UpgradePerpetuals<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.load()?.bump
+    )]
+    pub multisig: AccountLoader<'info, Multisig>,
+
+    #[account(
+        mut,
+        seeds = [b"perpetuals"],
+        bump
+    )]
+    /// CHECK: Deprecated perpetuals account
+    pub perpetuals: AccountInfo<'info>,
+
+    system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct UpgradePerpetualsParams {}
+
+pub fn upgrade_perpetuals<'info>(
+    ctx: Context<'_, '_, '_, 'info, UpgradePerpetuals<'info>>,
+    params: &UpgradePerpetualsParams,
+) -> Result<u8> {
+    // validate signatures
+    let mut multisig = ctx.accounts.multisig.load_mut()?;
+
+    let signatures_left = multisig.sign_multisig(
+        &ctx.accounts.admin,
+        &Multisig::get_account_infos(&ctx)[1..],
+        &Multisig::get_instruction_data(AdminInstruction::UpgradePerpetuals, params)?,
+    )?;
+    if signatures_left > 0 {
+        msg!(
+            "Instruction has been signed but more signatures are required: {}",
+            signatures_left
+        );
+        return Ok(signatures_left);
+    }
+
+    // load deprecated perpetuals data
+    msg!("Load deprecated perpetuals");
+    let perpetuals_account = &ctx.accounts.perpetuals;
+    if perpetuals_account.owner != &crate::ID {
+        return Err(ProgramError::IllegalOwner.into());
+    }
+    let deprecated_perpetuals = DeprecatedPerpetuals::try_deserialize_unchecked(
+        &mut &perpetuals_account.try_borrow_data()?[..],
+    )?;
+
+    // perpetuals are resized by the exact pools size, upgraded ones are larger
+    let pools_len = deprecated_perpetuals.pools.len() * std::mem::size_of::<Pubkey>();
+    if perpetuals_account.try_data_len()? != DeprecatedPerpetuals::LEN + pools_len {
+        return Err(ProgramError::InvalidAccountData.into());
+    }
+
+    // appended fields start from their defaults, the request timeout falls back to
+    // DEFAULT_POSITION_REQUEST_TIMEOUT_SEC and auto-deleveraging stays disabled
+    let perpetuals_data = Perpetuals {
+        permissions: deprecated_perpetuals.permissions,
+        pools: deprecated_perpetuals.pools.clone(),
+        transfer_authority_bump: deprecated_perpetuals.transfer_authority_bump,
+        perpetuals_bump: deprecated_perpetuals.perpetuals_bump,
+        inception_time: deprecated_perpetuals.inception_time,
+        ..Default::default()
+    };
+
+    msg!("Resize and re-initialize perpetuals");
+    Perpetuals::upgrade_account(
+        ctx.accounts.admin.to_account_info(),
+        perpetuals_account.clone(),
+        ctx.accounts.system_program.to_account_info(),
+        Perpetuals::LEN + pools_len,
+        &perpetuals_data,
+    )?;
+
+    Ok(0)
+}
@@ -0,0 +1,104 @@
+//! UpgradePool instruction handler
+
+use {
+    crate::state::{
+        multisig::{AdminInstruction, Multisig},
+        perpetuals::Perpetuals,
+        pool::{DeprecatedPool, Pool, TokenRatios},
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+pub struct UpgradePool<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"multisig"],
+        bump = multisig.load()?.bump
+    )]
+    pub multisig: AccountLoader<'info, Multisig>,
+
+    #[account(mut)]
+    /// CHECK: Deprecated pool account
+    pub pool: AccountInfo<'info>,
+
+    system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct UpgradePoolParams {}
+
+pub fn upgrade_pool<'info>(
+    ctx: Context<'_, '_, '_, 'info, UpgradePool<'info>>,
+    params: &UpgradePoolParams,
+) -> Result<u8> {
+    // validate signatures
+    let mut multisig = ctx.accounts.multisig.load_mut()?;
+
+    let signatures_left = multisig.sign_multisig(
+        &ctx.accounts.admin,
+        &Multisig::get_account_infos(&ctx)[1..],
+        &Multisig::get_instruction_data(AdminInstruction::UpgradePool, params)?,
+    )?;
+    if signatures_left > 0 {
+        msg!(
+            "Instruction has been signed but more signatures are required: {}",
+            signatures_left
+        );
+        return Ok(signatures_left);
+    }
+
+    // load deprecated pool data
+    msg!("Load deprecated pool");
+    let pool_account = &ctx.accounts.pool;
+    if pool_account.owner != &crate::ID {
+        return Err(ProgramError::IllegalOwner.into());
+    }
+    let deprecated_pool =
+        DeprecatedPool::try_deserialize_unchecked(&mut &pool_account.try_borrow_data()?[..])?;
+    let pool_key = Pubkey::create_program_address(
+        &[
+            b"pool",
+            deprecated_pool.name.as_bytes(),
+            &[deprecated_pool.bump],
+        ],
+        &crate::ID,
+    )
+    .map_err(|_| ProgramError::InvalidSeeds)?;
+    if pool_key != pool_account.key() {
+        return Err(ProgramError::InvalidSeeds.into());
+    }
+
+    // pools are resized by the exact custody and ratio sizes, upgraded ones are larger
+    let custodies_len = deprecated_pool.custodies.len() * std::mem::size_of::<Pubkey>()
+        + deprecated_pool.ratios.len() * std::mem::size_of::<TokenRatios>();
+    if pool_account.try_data_len()? != DeprecatedPool::LEN + custodies_len {
+        return Err(ProgramError::InvalidAccountData.into());
+    }
+
+    // appended fields start from their defaults, the quote currency is USD
+    let pool_data = Pool {
+        name: deprecated_pool.name.clone(),
+        custodies: deprecated_pool.custodies.clone(),
+        ratios: deprecated_pool.ratios.clone(),
+        aum_usd: deprecated_pool.aum_usd,
+        bump: deprecated_pool.bump,
+        lp_token_bump: deprecated_pool.lp_token_bump,
+        inception_time: deprecated_pool.inception_time,
+        ..Default::default()
+    };
+
+    msg!("Resize and re-initialize the pool");
+    Perpetuals::upgrade_account(
+        ctx.accounts.admin.to_account_info(),
+        pool_account.clone(),
+        ctx.accounts.system_program.to_account_info(),
+        Pool::LEN + custodies_len,
+        &pool_data,
+    )?;
+
+    Ok(0)
+}
@@ -0,0 +1,134 @@
+//! UpgradePosition instruction handler
+
+use {
+    crate::{
+        error::PerpetualsError,
+        state::{
+            custody::Custody,
+            perpetuals::Perpetuals,
+            pool::Pool,
+            position::{DeprecatedPosition, Position, Side},
+        },
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Accounts)]
+#[instruction(params: UpgradePositionParams)]
+pub struct UpgradePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [b"perpetuals"],
+        bump = perpetuals.perpetuals_bump
+    )]
+    pub perpetuals: Box<Account<'info, Perpetuals>>,
+
+    #[account(
+        seeds = [b"pool",
+                 pool.name.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    // custodies are upgraded first with upgrade_custody
+    #[account(
+        seeds = [b"custody",
+                 pool.key().as_ref(),
+                 custody.mint.as_ref()],
+        bump = custody.bump
+    )]
+    pub custody: Box<Account<'info, Custody>>,
+
+    #[account(mut)]
+    /// CHECK: Deprecated position account, closed to the owner
+    pub deprecated_position: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = Position::LEN,
+        seeds = [b"position",
+                 owner.key().as_ref(),
+                 pool.key().as_ref(),
+                 custody.key().as_ref(),
+                 &[params.side as u8],
+                 &params.position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct UpgradePositionParams {
+    pub side: Side,
+    pub position_id: u64,
+}
+
+pub fn upgrade_position(
+    ctx: Context<UpgradePosition>,
+    params: &UpgradePositionParams,
+) -> Result<()> {
+    // load deprecated position data
+    msg!("Load deprecated position");
+    let deprecated_account = &ctx.accounts.deprecated_position;
+    if deprecated_account.owner != &crate::ID {
+        return Err(ProgramError::IllegalOwner.into());
+    }
+    if deprecated_account.try_data_len()? != DeprecatedPosition::LEN {
+        return Err(ProgramError::InvalidAccountData.into());
+    }
+    let deprecated_position = DeprecatedPosition::try_deserialize_unchecked(
+        &mut &deprecated_account.try_borrow_data()?[..],
+    )?;
+
+    let owner = ctx.accounts.owner.key();
+    let pool = ctx.accounts.pool.key();
+    let custody = ctx.accounts.custody.as_ref();
+    require!(
+        deprecated_position.owner == owner
+            && deprecated_position.pool == pool
+            && deprecated_position.custody == custody.key()
+            && deprecated_position.side == params.side,
+        PerpetualsError::InvalidPositionState
+    );
+    let deprecated_key = Pubkey::create_program_address(
+        &[
+            b"position",
+            owner.as_ref(),
+            pool.as_ref(),
+            custody.key().as_ref(),
+            &[deprecated_position.side as u8],
+            &[deprecated_position.bump],
+        ],
+        &crate::ID,
+    )
+    .map_err(|_| ProgramError::InvalidSeeds)?;
+    if deprecated_key != deprecated_account.key() {
+        return Err(ProgramError::InvalidSeeds.into());
+    }
+
+    // funding didn't exist at launch, it accrues from now on
+    let curtime = ctx.accounts.perpetuals.get_time()?;
+    let position = ctx.accounts.position.as_mut();
+    **position = deprecated_position.upgrade(
+        params.position_id,
+        custody.get_cumulative_funding(deprecated_position.side, curtime)?,
+        ctx.bumps.position,
+    );
+
+    // the owner paid for the new position and gets the deprecated rent back
+    msg!("Close deprecated position");
+    Perpetuals::transfer_sol_from_owned(
+        deprecated_account.clone(),
+        ctx.accounts.owner.to_account_info(),
+        deprecated_account.try_lamports()?,
+    )?;
+    deprecated_account.assign(&System::id());
+    deprecated_account
+        .realloc(0, false)
+        .map_err(|_| ProgramError::InvalidRealloc.into())
+}
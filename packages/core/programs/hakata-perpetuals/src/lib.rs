@@ -9,7 +9,8 @@ use {
     anchor_lang::prelude::*,
     instructions::*,
     state::perpetuals::{
-        AmountAndFee, NewPositionPricesAndFee, PriceAndFee, ProfitAndLoss, SwapAmountAndFees,
+        AmountAndFee, LiquidationSummary, NewPositionPricesAndFee, PriceAndFee, ProfitAndLoss,
+        SwapAmountAndFees, UserPosition,
     },
 };
 
@@ -53,6 +54,13 @@ pub mod hakata_perpetuals {
         instructions::add_custody(ctx, &params)
     }
 
+    pub fn add_synthetic_custody<'info>(
+        ctx: Context<'_, '_, '_, 'info, AddSyntheticCustody<'info>>,
+        params: AddSyntheticCustodyParams,
+    ) -> Result<u8> {
+        instructions::add_synthetic_custody(ctx, &params)
+    }
+
     pub fn testing_edit_custody<'info>(
         ctx: Context<'_, '_, '_, 'info, TestingEditCustody<'info>>,
         params: EditCustodyParams,
@@ -88,6 +96,20 @@ pub mod hakata_perpetuals {
         instructions::set_permissions(ctx, &params)
     }
 
+    pub fn set_perpetuals_config<'info>(
+        ctx: Context<'_, '_, '_, 'info, SetPerpetualsConfig<'info>>,
+        params: SetPerpetualsConfigParams,
+    ) -> Result<u8> {
+        instructions::set_perpetuals_config(ctx, &params)
+    }
+
+    pub fn set_pool_config<'info>(
+        ctx: Context<'_, '_, '_, 'info, SetPoolConfig<'info>>,
+        params: SetPoolConfigParams,
+    ) -> Result<u8> {
+        instructions::set_pool_config(ctx, &params)
+    }
+
     pub fn withdraw_fees<'info>(
         ctx: Context<'_, '_, '_, 'info, WithdrawFees<'info>>,
         params: WithdrawFeesParams,
@@ -102,12 +124,33 @@ pub mod hakata_perpetuals {
         instructions::withdraw_sol_fees(ctx, &params)
     }
 
-    // pub fn upgrade_custody<'a, 'b, 'c, 'info>(
-    //     ctx: Context<'a, 'b, 'c, 'info, UpgradeCustody<'info>>,
-    //     params: UpgradeCustodyParams,
-    // ) -> Result<u8> {
-    //     instructions::upgrade_custody(ctx, &params)
-    // }
+    pub fn reset_circuit_breaker<'info>(
+        ctx: Context<'_, '_, '_, 'info, ResetCircuitBreaker<'info>>,
+        params: ResetCircuitBreakerParams,
+    ) -> Result<u8> {
+        instructions::reset_circuit_breaker(ctx, &params)
+    }
+
+    pub fn upgrade_custody<'info>(
+        ctx: Context<'_, '_, '_, 'info, UpgradeCustody<'info>>,
+        params: UpgradeCustodyParams,
+    ) -> Result<u8> {
+        instructions::upgrade_custody(ctx, &params)
+    }
+
+    pub fn upgrade_pool<'info>(
+        ctx: Context<'_, '_, '_, 'info, UpgradePool<'info>>,
+        params: UpgradePoolParams,
+    ) -> Result<u8> {
+        instructions::upgrade_pool(ctx, &params)
+    }
+
+    pub fn upgrade_perpetuals<'info>(
+        ctx: Context<'_, '_, '_, 'info, UpgradePerpetuals<'info>>,
+        params: UpgradePerpetualsParams,
+    ) -> Result<u8> {
+        instructions::upgrade_perpetuals(ctx, &params)
+    }
 
     // test instructions    
 
@@ -150,6 +193,13 @@ pub mod hakata_perpetuals {
         instructions::open_position(ctx, &params)
     }
 
+    pub fn increase_position(
+        ctx: Context<IncreasePosition>,
+        params: IncreasePositionParams,
+    ) -> Result<()> {
+        instructions::increase_position(ctx, &params)
+    }
+
     pub fn add_collateral(ctx: Context<AddCollateral>, params: AddCollateralParams) -> Result<()> {
         instructions::add_collateral(ctx, &params)
     }
@@ -161,14 +211,114 @@ pub mod hakata_perpetuals {
         instructions::remove_collateral(ctx, &params)
     }
 
+    pub fn create_order(ctx: Context<CreateOrder>, params: CreateOrderParams) -> Result<()> {
+        instructions::create_order(ctx, &params)
+    }
+
+    pub fn execute_order(ctx: Context<ExecuteOrder>, params: ExecuteOrderParams) -> Result<()> {
+        instructions::execute_order(ctx, &params)
+    }
+
+    pub fn cancel_order(ctx: Context<CancelOrder>, params: CancelOrderParams) -> Result<()> {
+        instructions::cancel_order(ctx, &params)
+    }
+
+    pub fn create_position_request(
+        ctx: Context<CreatePositionRequest>,
+        params: CreatePositionRequestParams,
+    ) -> Result<()> {
+        instructions::create_position_request(ctx, &params)
+    }
+
+    pub fn execute_position_request(
+        ctx: Context<ExecutePositionRequest>,
+        params: ExecutePositionRequestParams,
+    ) -> Result<()> {
+        instructions::execute_position_request(ctx, &params)
+    }
+
+    pub fn cancel_position_request(
+        ctx: Context<CancelPositionRequest>,
+        params: CancelPositionRequestParams,
+    ) -> Result<()> {
+        instructions::cancel_position_request(ctx, &params)
+    }
+
+    pub fn set_position_triggers(
+        ctx: Context<SetPositionTriggers>,
+        params: SetPositionTriggersParams,
+    ) -> Result<()> {
+        instructions::set_position_triggers(ctx, &params)
+    }
+
+    pub fn execute_trigger(
+        ctx: Context<ExecuteTrigger>,
+        params: ExecuteTriggerParams,
+    ) -> Result<()> {
+        instructions::execute_trigger(ctx, &params)
+    }
+
     pub fn close_position(ctx: Context<ClosePosition>, params: ClosePositionParams) -> Result<()> {
         instructions::close_position(ctx, &params)
     }
 
+    pub fn decrease_position(
+        ctx: Context<DecreasePosition>,
+        params: DecreasePositionParams,
+    ) -> Result<()> {
+        instructions::decrease_position(ctx, &params)
+    }
+
     pub fn liquidate(ctx: Context<Liquidate>, params: LiquidateParams) -> Result<()> {
         instructions::liquidate(ctx, &params)
     }
 
+    pub fn liquidate_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, LiquidateBatch<'info>>,
+        params: LiquidateBatchParams,
+    ) -> Result<LiquidationSummary> {
+        instructions::liquidate_batch(ctx, &params)
+    }
+
+    pub fn auto_deleverage(
+        ctx: Context<AutoDeleverage>,
+        params: AutoDeleverageParams,
+    ) -> Result<()> {
+        instructions::auto_deleverage(ctx, &params)
+    }
+
+    pub fn update_oracle_aggregate(
+        ctx: Context<UpdateOracleAggregate>,
+        params: UpdateOracleAggregateParams,
+    ) -> Result<()> {
+        instructions::update_oracle_aggregate(ctx, &params)
+    }
+
+    pub fn update_oracle_composite(
+        ctx: Context<UpdateOracleComposite>,
+        params: UpdateOracleCompositeParams,
+    ) -> Result<()> {
+        instructions::update_oracle_composite(ctx, &params)
+    }
+
+    pub fn post_signed_price(
+        ctx: Context<PostSignedPrice>,
+        params: PostSignedPriceParams,
+    ) -> Result<()> {
+        instructions::post_signed_price(ctx, &params)
+    }
+
+    pub fn update_twap(ctx: Context<UpdateTwap>, params: UpdateTwapParams) -> Result<()> {
+        instructions::update_twap(ctx, &params)
+    }
+
+    pub fn upgrade_position(
+        ctx: Context<UpgradePosition>,
+        params: UpgradePositionParams,
+    ) -> Result<()> {
+        instructions::upgrade_position(ctx, &params)
+    }
+
     pub fn get_add_liquidity_amount_and_fee(
         ctx: Context<GetAddLiquidityAmountAndFee>,
         params: GetAddLiquidityAmountAndFeeParams,
@@ -235,4 +385,11 @@ pub mod hakata_perpetuals {
     ) -> Result<u128> {
         instructions::get_assets_under_management(ctx, &params)
     }
+
+    pub fn get_user_positions<'info>(
+        ctx: Context<'_, '_, '_, 'info, GetUserPositions<'info>>,
+        params: GetUserPositionsParams,
+    ) -> Result<Vec<UserPosition>> {
+        instructions::get_user_positions(ctx, &params)
+    }
 }
@@ -1,8 +1,12 @@
 // Program state handling.
 
 pub mod custody;
+pub mod market_hours;
 pub mod multisig;
 pub mod oracle;
+pub mod order;
 pub mod perpetuals;
 pub mod pool;
 pub mod position;
+pub mod position_request;
+pub mod trade;
@@ -3,8 +3,13 @@ use {
         error::PerpetualsError,
         math,
         state::{
-            oracle::{OraclePrice, OracleType},
+            market_hours::MarketHours,
+            oracle::{
+                DeviationPolicy, OracleAggregation, OracleComposition, OraclePrice, OracleSource,
+                OracleType,
+            },
             perpetuals::{Permissions, Perpetuals},
+            pool::Pool,
             position::{Position, Side},
         },
     },
@@ -31,8 +36,19 @@ pub struct Fees {
     pub remove_liquidity: u64,
     pub open_position: u64,
     pub close_position: u64,
+    // penalty charged on the size of liquidated positions
     pub liquidation: u64,
+    // share of the liquidation penalty paid to the liquidator
+    pub liquidator_share: u64,
+    // share of the liquidation penalty reserved for the insurance fund
+    pub liquidation_insurance_share: u64,
+    // share of position fees reserved for the insurance fund
+    pub fee_insurance_share: u64,
+    // paid to the keeper that executes an order
+    pub order_execution: u64,
     pub protocol_share: u64,
+    // cap on the liquidator reward, has implied USD_DECIMALS decimals
+    pub max_liquidator_reward_usd: u64,
 }
 
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
@@ -64,6 +80,15 @@ pub struct TradeStats {
     pub oi_short_usd: u64,
 }
 
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct BadDebtStats {
+    // losses in excess of position collateral, split by who absorbed them
+    pub covered_usd: u64,
+    pub socialized_usd: u64,
+    pub last_bad_debt_time: i64,
+    pub last_socialized_time: i64,
+}
+
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
 pub struct Assets {
     // collateral debt
@@ -74,6 +99,8 @@ pub struct Assets {
     pub owned: u64,
     // locked funds for pnl payoff
     pub locked: u64,
+    // insurance_fund is the part of fees and liquidation penalties reserved to cover bad debt
+    pub insurance_fund: u64,
 }
 
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
@@ -82,6 +109,20 @@ pub struct OracleParams {
     pub oracle_type: OracleType,
     pub max_price_error: u64,
     pub max_price_age_sec: u32,
+    // price feed id, only used by pull oracles
+    pub feed_id: [u8; 32],
+    // price sources of an aggregate or composite oracle, unused slots have OracleType::None
+    pub sources: [OracleSource; 3],
+    pub aggregation: OracleAggregation,
+    pub composition: OracleComposition,
+    // max spread between source prices, with implied BPS_DECIMALS decimals
+    pub max_source_deviation: u64,
+    pub deviation_policy: DeviationPolicy,
+    // signer of prices, only used by signed oracles
+    pub publisher: Pubkey,
+    // sources that must be available to update an aggregate oracle, fewer sources
+    // are handled by the deviation policy
+    pub min_sources: u8,
 }
 
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
@@ -96,12 +137,17 @@ pub struct PricingParams {
     pub min_initial_leverage: u64,
     pub max_initial_leverage: u64,
     pub max_leverage: u64,
+    // liquidations only reduce positions down to this leverage, zero closes them in full
+    pub liquidation_target_leverage: u64,
     // max_user_profit = position_size * max_payoff_mult
     pub max_payoff_mult: u64,
     pub max_utilization: u64,
     // USD denominated values always have implied USD_DECIMALS decimals
     pub max_position_locked_usd: u64,
     pub max_total_locked_usd: u64,
+    // multiple of the oracle confidence added to the trade spread, with implied
+    // BPS_DECIMALS decimals, 0 prices trades without regard to confidence
+    pub conf_mult: u64,
 }
 
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
@@ -121,6 +167,92 @@ pub struct BorrowRateState {
     pub last_update: i64,
 }
 
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct FundingRateParams {
+    // hourly rate paid by the majority side when all open interest is on one side,
+    // has implied RATE_DECIMALS decimals
+    pub max_rate: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct FundingRateState {
+    // funding rates have implied RATE_DECIMALS decimals, positive rates are paid
+    // and negative ones received by positions of the side
+    pub long_rate: i64,
+    pub short_rate: i64,
+    pub cumulative_long_funding: i128,
+    pub cumulative_short_funding: i128,
+    pub last_update: i64,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct CircuitBreakerParams {
+    // max price move from the last accepted price per window_sec, with implied
+    // BPS_DECIMALS decimals, 0 disables the circuit breaker. The allowed move grows
+    // linearly when the last accepted price is older than the window.
+    pub max_price_move: u64,
+    pub window_sec: u32,
+    // time after which a tripped breaker resets by itself, 0 requires an admin reset
+    pub cooldown_sec: u32,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct CircuitBreakerState {
+    pub last_price: OraclePrice,
+    pub last_price_time: i64,
+    // 0 if the breaker is not tripped
+    pub tripped_time: i64,
+}
+
+#[event]
+pub struct CircuitBreakerEvent {
+    pub pool: Pubkey,
+    pub mint: Pubkey,
+    pub price: OraclePrice,
+    pub last_price: OraclePrice,
+    pub tripped_time: i64,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct DegradedModeParams {
+    // allows closes and collateral additions while the oracle is stale,
+    // opens, swaps and liquidity deposits stay blocked
+    pub enabled: bool,
+    // oracle that may be passed instead of the primary one, OracleType::None if unset
+    pub fallback_oracle: OracleSource,
+    // max age of the last accepted price used in place of a stale oracle price
+    pub max_last_price_age_sec: u32,
+    // band around degraded prices, with implied BPS_DECIMALS decimals
+    pub spread: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct TwapParams {
+    // averaging window of the time-weighted average price, 0 disables the accumulator
+    pub window_sec: u32,
+    // whether trades are priced against the TWAP instead of the oracle EMA price
+    pub use_twap: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct TwapObservation {
+    pub cumulative_price: u128,
+    // 0 if the slot is unused
+    pub time: i64,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct TwapState {
+    // prices have implied PRICE_DECIMALS decimals,
+    // cumulative_price is the sum of price * seconds since the first update
+    pub last_price: u64,
+    pub cumulative_price: u128,
+    pub last_update: i64,
+    // ring buffer of accumulator snapshots taken at least window_sec / 7 apart
+    pub observations: [TwapObservation; 8],
+    pub observation_idx: u8,
+}
+
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
 pub struct PositionStats {
     pub open_positions: u64,
@@ -142,39 +274,92 @@ pub struct Custody {
     pub token_account: Pubkey,
     pub decimals: u8,
     pub is_stable: bool,
+    // price-only custody without a token account, traded against stablecoin collateral
+    pub is_synthetic: bool,
     pub oracle: OracleParams,
     pub pricing: PricingParams,
     pub permissions: Permissions,
     pub fees: Fees,
     pub borrow_rate: BorrowRateParams,
+    pub funding_rate: FundingRateParams,
+    pub market_hours: MarketHours,
+    pub circuit_breaker: CircuitBreakerParams,
+    pub twap: TwapParams,
+    pub degraded_mode: DegradedModeParams,
 
     // dynamic variables
     pub assets: Assets,
     pub collected_fees: FeesStats,
     pub volume_stats: VolumeStats,
     pub trade_stats: TradeStats,
+    pub bad_debt_stats: BadDebtStats,
     pub long_positions: PositionStats,
     pub short_positions: PositionStats,
     pub borrow_rate_state: BorrowRateState,
+    pub funding_rate_state: FundingRateState,
+    // publish time of the last accepted signed price, prevents replays
+    pub last_signed_price_time: i64,
+    pub circuit_breaker_state: CircuitBreakerState,
+    pub twap_state: TwapState,
+    // last oracle price accepted by a trade, used in degraded mode
+    pub last_accepted_price: OraclePrice,
+    pub last_accepted_price_time: i64,
+    // set when aggregate oracle sources disagree under DeviationPolicy::CloseOnly
+    pub oracle_sources_deviate: bool,
 
     // bumps for address validation
     pub bump: u8,
     pub token_account_bump: u8,
 }
 
+// launch layouts of the custody parameters that have been extended since, see upgrade_custody
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct DeprecatedFees {
+    pub mode: FeesMode,
+    pub ratio_mult: u64,
+    pub utilization_mult: u64,
+    pub swap_in: u64,
+    pub swap_out: u64,
+    pub stable_swap_in: u64,
+    pub stable_swap_out: u64,
+    pub add_liquidity: u64,
+    pub remove_liquidity: u64,
+    pub open_position: u64,
+    pub close_position: u64,
+    pub liquidation: u64,
+    pub protocol_share: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct DeprecatedAssets {
+    pub collateral: u64,
+    pub protocol_fees: u64,
+    pub owned: u64,
+    pub locked: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct DeprecatedOracleParams {
+    pub oracle_account: Pubkey,
+    pub oracle_type: OracleType,
+    pub max_price_error: u64,
+    pub max_price_age_sec: u32,
+}
+
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
 pub struct DeprecatedPricingParams {
     pub use_ema: bool,
-    // whether to account for unrealized pnl in assets under management calculations
     pub use_unrealized_pnl_in_aum: bool,
-    // pricing params have implied BPS_DECIMALS decimals
     pub trade_spread_long: u64,
     pub trade_spread_short: u64,
     pub swap_spread: u64,
     pub min_initial_leverage: u64,
+    pub max_initial_leverage: u64,
     pub max_leverage: u64,
-    // max_user_profit = position_size * max_payoff_mult
     pub max_payoff_mult: u64,
+    pub max_utilization: u64,
+    pub max_position_locked_usd: u64,
+    pub max_total_locked_usd: u64,
 }
 
 #[account]
@@ -186,14 +371,14 @@ pub struct DeprecatedCustody {
     pub token_account: Pubkey,
     pub decimals: u8,
     pub is_stable: bool,
-    pub oracle: OracleParams,
+    pub oracle: DeprecatedOracleParams,
     pub pricing: DeprecatedPricingParams,
     pub permissions: Permissions,
-    pub fees: Fees,
+    pub fees: DeprecatedFees,
     pub borrow_rate: BorrowRateParams,
 
     // dynamic variables
-    pub assets: Assets,
+    pub assets: DeprecatedAssets,
     pub collected_fees: FeesStats,
     pub volume_stats: VolumeStats,
     pub trade_stats: TradeStats,
@@ -223,13 +408,104 @@ impl Fees {
             && self.open_position as u128 <= Perpetuals::BPS_POWER
             && self.close_position as u128 <= Perpetuals::BPS_POWER
             && self.liquidation as u128 <= Perpetuals::BPS_POWER
+            && (self.liquidator_share as u128 + self.liquidation_insurance_share as u128)
+                <= Perpetuals::BPS_POWER
+            && self.fee_insurance_share as u128 <= Perpetuals::BPS_POWER
+            && self.order_execution as u128 <= Perpetuals::BPS_POWER
             && self.protocol_share as u128 <= Perpetuals::BPS_POWER
     }
 }
 
+impl BadDebtStats {
+    pub fn record(&mut self, covered_usd: u64, socialized_usd: u64, curtime: i64) {
+        if covered_usd == 0 && socialized_usd == 0 {
+            return;
+        }
+        self.covered_usd = self.covered_usd.wrapping_add(covered_usd);
+        self.last_bad_debt_time = curtime;
+        if socialized_usd > 0 {
+            self.socialized_usd = self.socialized_usd.wrapping_add(socialized_usd);
+            self.last_socialized_time = curtime;
+        }
+    }
+}
+
 impl OracleParams {
     pub fn validate(&self) -> bool {
-        self.oracle_type == OracleType::None || self.oracle_account != Pubkey::default()
+        match self.oracle_type {
+            OracleType::None => true,
+            OracleType::PythPull => {
+                self.oracle_account != Pubkey::default() && self.feed_id != [0; 32]
+            }
+            OracleType::Signed => {
+                self.oracle_account != Pubkey::default()
+                    && self.feed_id != [0; 32]
+                    && self.publisher != Pubkey::default()
+            }
+            OracleType::Aggregate => {
+                let num_sources = self
+                    .sources
+                    .iter()
+                    .filter(|source| source.oracle_type != OracleType::None)
+                    .count();
+                self.oracle_account != Pubkey::default()
+                    && self.min_sources > 0
+                    && self.min_sources as usize <= num_sources
+                    // sources can't be aggregates themselves or signed
+                    && self.sources.iter().all(|source| {
+                        source.oracle_type == OracleType::None
+                            || (source.can_be_source() && self.get_source_params(source).validate())
+                    })
+            }
+            OracleType::Composite => {
+                let num_sources = self
+                    .sources
+                    .iter()
+                    .take_while(|source| source.oracle_type != OracleType::None)
+                    .count();
+                // at least two leading sources without gaps, as the order matters
+                self.oracle_account != Pubkey::default()
+                    && num_sources >= 2
+                    && self.sources[num_sources..]
+                        .iter()
+                        .all(|source| source.oracle_type == OracleType::None)
+                    && self.sources[..num_sources].iter().all(|source| {
+                        source.can_be_source() && self.get_source_params(source).validate()
+                    })
+            }
+            _ => self.oracle_account != Pubkey::default(),
+        }
+    }
+
+    /// Returns true if enough sources are available to update an aggregate oracle,
+    /// fails under the Reject deviation policy otherwise
+    pub fn check_quorum(&self, num_sources: usize) -> Result<bool> {
+        if num_sources >= self.min_sources as usize {
+            return Ok(true);
+        }
+        msg!(
+            "Available oracle sources: {}, required: {}",
+            num_sources,
+            self.min_sources
+        );
+        require!(
+            self.deviation_policy != DeviationPolicy::Reject,
+            PerpetualsError::OracleQuorumNotMet
+        );
+        Ok(false)
+    }
+
+    /// Returns params for reading a single source of an aggregate or composite oracle
+    pub fn get_source_params(&self, source: &OracleSource) -> OracleParams {
+        OracleParams {
+            oracle_account: source.oracle_account,
+            oracle_type: source.oracle_type,
+            max_price_error: self.max_price_error,
+            max_price_age_sec: self.max_price_age_sec,
+            feed_id: source.feed_id,
+            publisher: self.publisher,
+            ..Default::default()
+        }
     }
 }
 
@@ -238,6 +514,9 @@ impl PricingParams {
         (self.min_initial_leverage as u128) >= Perpetuals::BPS_POWER
             && self.min_initial_leverage <= self.max_initial_leverage
             && self.max_initial_leverage <= self.max_leverage
+            && (self.liquidation_target_leverage == 0
+                || ((self.liquidation_target_leverage as u128) >= Perpetuals::BPS_POWER
+                    && self.liquidation_target_leverage < self.max_leverage))
             && (self.trade_spread_long as u128) < Perpetuals::BPS_POWER
             && (self.trade_spread_short as u128) < Perpetuals::BPS_POWER
             && (self.swap_spread as u128) < Perpetuals::BPS_POWER
@@ -252,16 +531,357 @@ impl BorrowRateParams {
     }
 }
 
+impl FundingRateParams {
+    pub fn validate(&self) -> bool {
+        (self.max_rate as u128) <= Perpetuals::RATE_POWER
+    }
+}
+
+impl CircuitBreakerParams {
+    pub fn validate(&self) -> bool {
+        self.max_price_move == 0 || self.window_sec > 0
+    }
+}
+
+impl DegradedModeParams {
+    pub fn validate(&self) -> bool {
+        (self.spread as u128) < Perpetuals::BPS_POWER
+            && match self.fallback_oracle.oracle_type {
+                OracleType::None => true,
+                _ => {
+                    !self.fallback_oracle.is_derived()
+                        && self.fallback_oracle.oracle_account != Pubkey::default()
+                }
+            }
+    }
+
+    pub fn is_fallback_oracle(&self, oracle_account: &Pubkey) -> bool {
+        self.enabled
+            && self.fallback_oracle.oracle_type != OracleType::None
+            && self.fallback_oracle.oracle_account == *oracle_account
+    }
+}
+
+impl TwapParams {
+    pub fn validate(&self) -> bool {
+        !self.use_twap || self.window_sec > 0
+    }
+}
+
 impl Custody {
     pub const LEN: usize = 8 + std::mem::size_of::<Custody>();
+    pub const TWAP_OBSERVATIONS: usize = 8;
+
+    /// Returns true if only position closes and collateral additions are allowed
+    pub fn is_close_only(&self, curtime: i64) -> bool {
+        self.oracle_sources_deviate || self.is_circuit_breaker_tripped(curtime)
+    }
+
+    pub fn is_circuit_breaker_tripped(&self, curtime: i64) -> bool {
+        let state = &self.circuit_breaker_state;
+        state.tripped_time > 0
+            && (self.circuit_breaker.cooldown_sec == 0
+                || curtime < state.tripped_time + self.circuit_breaker.cooldown_sec as i64)
+    }
+
+    /// Accepts the oracle price as the new reference, or trips the circuit breaker
+    /// if it moved too far from the last accepted price for the time elapsed since
+    pub fn update_circuit_breaker(&mut self, price: &OraclePrice, curtime: i64) -> Result<()> {
+        if self.circuit_breaker.max_price_move == 0 || self.is_circuit_breaker_tripped(curtime) {
+            return Ok(());
+        }
+
+        let state = &mut self.circuit_breaker_state;
+        state.tripped_time = 0;
+
+        if state.last_price.price > 0 {
+            let window_sec = self.circuit_breaker.window_sec as u128;
+            let elapsed_sec = std::cmp::max(0, curtime - state.last_price_time) as u128;
+            let max_price_move = math::checked_div(
+                math::checked_mul(
+                    self.circuit_breaker.max_price_move as u128,
+                    std::cmp::max(elapsed_sec, window_sec),
+                )?,
+                window_sec,
+            )?;
+
+            let new_price = price.scale_to_exponent(state.last_price.exponent)?;
+            let price_move = math::checked_div(
+                math::checked_mul(
+                    new_price.price.abs_diff(state.last_price.price) as u128,
+                    Perpetuals::BPS_POWER,
+                )?,
+                state.last_price.price as u128,
+            )?;
+            if price_move > max_price_move {
+                msg!("Circuit breaker tripped, price move: {}", price_move);
+                state.tripped_time = curtime;
+                emit!(self.get_circuit_breaker_event(price));
+                return Ok(());
+            }
+        }
+
+        state.last_price = *price;
+        state.last_price_time = curtime;
+
+        Ok(())
+    }
+
+    /// Feeds a fresh oracle price to the circuit breaker, and unless the breaker is
+    /// tripped to the TWAP accumulator and as the last accepted price
+    pub fn record_price(&mut self, price: &OraclePrice, curtime: i64) -> Result<()> {
+        self.update_circuit_breaker(price, curtime)?;
+        if !self.is_circuit_breaker_tripped(curtime) {
+            self.update_twap(price, curtime)?;
+            self.last_accepted_price = *price;
+            self.last_accepted_price_time = curtime;
+        }
+        Ok(())
+    }
+
+    /// Records the price for instructions that are blocked in close-only mode. Returns
+    /// false if this price tripped the circuit breaker, callers must then return without
+    /// trading so that the trip is saved.
+    pub fn record_trade_price(&mut self, price: &OraclePrice, curtime: i64) -> Result<bool> {
+        require!(
+            !self.is_close_only(curtime),
+            PerpetualsError::CustodyCloseOnly
+        );
+        self.record_price(price, curtime)?;
+        Ok(!self.is_circuit_breaker_tripped(curtime))
+    }
+
+    /// Returns the spot and EMA prices to settle positions at once record_price has been
+    /// called, while the circuit breaker is tripped both are the last accepted price
+    pub fn get_accepted_prices(
+        &self,
+        token_price: &OraclePrice,
+        token_ema_price: &OraclePrice,
+        curtime: i64,
+    ) -> Result<(OraclePrice, OraclePrice)> {
+        if !self.is_circuit_breaker_tripped(curtime) {
+            return Ok((*token_price, *token_ema_price));
+        }
+        require!(
+            self.last_accepted_price.price > 0,
+            PerpetualsError::CircuitBreakerTripped
+        );
+        msg!(
+            "Circuit breaker tripped, last accepted price: {}",
+            self.last_accepted_price.price
+        );
+        Ok((self.last_accepted_price, self.last_accepted_price))
+    }
+
+    /// Event emitted when `price` trips the circuit breaker, so that clients can tell
+    /// trades rejected without an error from fills
+    pub fn get_circuit_breaker_event(&self, price: &OraclePrice) -> CircuitBreakerEvent {
+        CircuitBreakerEvent {
+            pool: self.pool,
+            mint: self.mint,
+            price: *price,
+            last_price: self.circuit_breaker_state.last_price,
+            tripped_time: self.circuit_breaker_state.tripped_time,
+        }
+    }
+
+    /// Copies the state updated by record_price from another copy of the same custody
+    pub fn sync_price_state(&mut self, other: &Custody) {
+        self.circuit_breaker_state = other.circuit_breaker_state;
+        self.twap_state = other.twap_state;
+        self.last_accepted_price = other.last_accepted_price;
+        self.last_accepted_price_time = other.last_accepted_price_time;
+    }
+
+    /// Returns spot and EMA prices for position closes and collateral additions, and
+    /// whether they are degraded. In degraded mode a stale oracle price is replaced by the
+    /// fallback oracle price if its account is among fallback_accounts, or by the last
+    /// accepted price, both widened into a band of degraded_mode.spread.
+    pub fn get_degradable_prices(
+        &self,
+        oracle_account: &AccountInfo,
+        fallback_accounts: &[AccountInfo],
+        curtime: i64,
+    ) -> Result<(OraclePrice, OraclePrice, bool)> {
+        let degraded_mode = &self.degraded_mode;
+        require_keys_eq!(
+            *oracle_account.key,
+            self.oracle.oracle_account,
+            PerpetualsError::InvalidOracleAccount
+        );
+        match OraclePrice::new_from_oracle(&self.oracle, oracle_account, curtime, false) {
+            Ok(price) => {
+                let ema_price = OraclePrice::new_from_oracle(
+                    &self.oracle,
+                    oracle_account,
+                    curtime,
+                    self.pricing.use_ema,
+                )?;
+                Ok((price, ema_price, false))
+            }
+            Err(err)
+                if degraded_mode.enabled && err == PerpetualsError::StaleOraclePrice.into() =>
+            {
+                if let Some(fallback_account) = fallback_accounts
+                    .iter()
+                    .find(|account| degraded_mode.is_fallback_oracle(account.key))
+                {
+                    let price = OraclePrice::new_from_oracle(
+                        &self
+                            .oracle
+                            .get_source_params(&degraded_mode.fallback_oracle),
+                        fallback_account,
+                        curtime,
+                        false,
+                    )?;
+                    msg!("Degraded mode, fallback oracle price: {}", price.price);
+                    let (max_price, min_price) = self.get_degraded_band(&price)?;
+                    return Ok((max_price, min_price, true));
+                }
+
+                require!(
+                    self.last_accepted_price_time > 0
+                        && curtime - self.last_accepted_price_time
+                            <= degraded_mode.max_last_price_age_sec as i64,
+                    PerpetualsError::StaleOraclePrice
+                );
+                msg!(
+                    "Degraded mode, last accepted price: {}",
+                    self.last_accepted_price.price
+                );
+                let (max_price, min_price) = self.get_degraded_band(&self.last_accepted_price)?;
+                Ok((max_price, min_price, true))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Accumulates the previous price over the time elapsed since the last update
+    /// and records a new observation once the sampling interval has passed
+    pub fn update_twap(&mut self, price: &OraclePrice, curtime: i64) -> Result<()> {
+        if self.twap.window_sec == 0 {
+            return Ok(());
+        }
+
+        let state = &mut self.twap_state;
+        if curtime < state.last_update {
+            return Ok(());
+        }
+        if state.last_update > 0 {
+            state.cumulative_price = math::checked_add(
+                state.cumulative_price,
+                math::checked_mul(
+                    state.last_price as u128,
+                    math::checked_sub(curtime, state.last_update)? as u128,
+                )?,
+            )?;
+        }
+        state.last_price = price
+            .scale_to_exponent(-(Perpetuals::PRICE_DECIMALS as i32))?
+            .price;
+        state.last_update = curtime;
+
+        let interval = std::cmp::max(
+            1,
+            self.twap.window_sec as i64 / (Self::TWAP_OBSERVATIONS as i64 - 1),
+        );
+        let mut idx = state.observation_idx as usize;
+        if state.observations[idx].time > 0 {
+            if curtime < state.observations[idx].time + interval {
+                return Ok(());
+            }
+            idx = (idx + 1) % Self::TWAP_OBSERVATIONS;
+        }
+        state.observations[idx] = TwapObservation {
+            cumulative_price: state.cumulative_price,
+            time: curtime,
+        };
+        state.observation_idx = idx as u8;
+
+        Ok(())
+    }
+
+    /// Returns the time-weighted average price over at least the configured window,
+    /// or over the available history if it is shorter
+    pub fn get_twap_price(&self, curtime: i64) -> Result<OraclePrice> {
+        let state = &self.twap_state;
+        require!(
+            self.twap.window_sec > 0 && state.last_update > 0,
+            PerpetualsError::TwapNotAvailable
+        );
+
+        let curtime = std::cmp::max(curtime, state.last_update);
+        let cumulative_price = math::checked_add(
+            state.cumulative_price,
+            math::checked_mul(
+                state.last_price as u128,
+                math::checked_sub(curtime, state.last_update)? as u128,
+            )?,
+        )?;
+
+        // latest observation at or before the start of the window, or the oldest one
+        let start_time = curtime - self.twap.window_sec as i64;
+        let observations = state.observations.iter().filter(|obs| obs.time > 0);
+        let start = observations
+            .clone()
+            .filter(|obs| obs.time <= start_time)
+            .max_by_key(|obs| obs.time)
+            .or_else(|| observations.min_by_key(|obs| obs.time));
+
+        let price = match start {
+            Some(start) if start.time < curtime => math::checked_as_u64(math::checked_div(
+                math::checked_sub(cumulative_price, start.cumulative_price)?,
+                math::checked_sub(curtime, start.time)? as u128,
+            )?)?,
+            _ => state.last_price,
+        };
+
+        Ok(OraclePrice::new(
+            price,
+            -(Perpetuals::PRICE_DECIMALS as i32),
+        ))
+    }
+
+    /// Returns the price compared against the spot price when pricing trades,
+    /// fails if the TWAP hasn't been updated within its window
+    pub fn get_reference_price(
+        &self,
+        token_ema_price: &OraclePrice,
+        curtime: i64,
+    ) -> Result<OraclePrice> {
+        if self.twap.use_twap {
+            require!(
+                curtime - self.twap_state.last_update <= self.twap.window_sec as i64,
+                PerpetualsError::TwapNotAvailable
+            );
+            self.get_twap_price(curtime)
+        } else {
+            Ok(*token_ema_price)
+        }
+    }
 
     pub fn validate(&self) -> bool {
-        self.token_account != Pubkey::default()
+        // synthetic custodies hold no tokens, so they can't be swapped or provide liquidity
+        if self.is_synthetic
+            && (self.is_stable
+                || self.permissions.allow_swap
+                || self.permissions.allow_add_liquidity
+                || self.permissions.allow_remove_liquidity)
+        {
+            return false;
+        }
+
+        (self.is_synthetic || self.token_account != Pubkey::default())
             && self.mint != Pubkey::default()
             && self.oracle.validate()
             && self.pricing.validate()
             && self.fees.validate()
             && self.borrow_rate.validate()
+            && self.funding_rate.validate()
+            && self.market_hours.validate()
+            && self.circuit_breaker.validate()
+            && self.twap.validate()
+            && self.degraded_mode.validate()
     }
 
     pub fn lock_funds(&mut self, amount: u64) -> Result<()> {
@@ -299,6 +919,102 @@ impl Custody {
         Ok(())
     }
 
+    /// Returns the amount by which funds locked for position payoffs exceed
+    /// owned assets, profitable positions can be auto-deleveraged while it is non-zero
+    pub fn get_deficit(&self) -> u64 {
+        self.assets.locked.saturating_sub(self.assets.owned)
+    }
+
+    /// Reserves the insurance fund and protocol shares of a position fee,
+    /// returns the reserved amount
+    pub fn reserve_fee(&mut self, fee_amount: u64) -> Result<u64> {
+        let insurance_amount = std::cmp::min(
+            Pool::get_fee_amount(self.fees.fee_insurance_share, fee_amount)?,
+            fee_amount,
+        );
+        let protocol_fee = Pool::get_fee_amount(
+            self.fees.protocol_share,
+            math::checked_sub(fee_amount, insurance_amount)?,
+        )?;
+
+        self.assets.insurance_fund =
+            math::checked_add(self.assets.insurance_fund, insurance_amount)?;
+        self.assets.protocol_fees = math::checked_add(self.assets.protocol_fees, protocol_fee)?;
+
+        math::checked_add(insurance_amount, protocol_fee)
+    }
+
+    /// Collects a position fee paid on top of collateral, the part that is not
+    /// reserved is credited to the pool
+    pub fn collect_fee(&mut self, fee_amount: u64) -> Result<()> {
+        let reserved_amount = self.reserve_fee(fee_amount)?;
+        self.assets.owned = math::checked_add(
+            self.assets.owned,
+            math::checked_sub(fee_amount, reserved_amount)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Releases collateral of a closed position. Whatever is left after the payout and
+    /// the reserved fees is credited to the pool, payouts above collateral are debited.
+    pub fn settle_collateral(
+        &mut self,
+        collateral_amount: u64,
+        transfer_amount: u64,
+        reserved_amount: u64,
+    ) -> Result<()> {
+        self.assets.collateral = math::checked_sub(self.assets.collateral, collateral_amount)?;
+
+        let amount_out = math::checked_add(transfer_amount, reserved_amount)?;
+        if collateral_amount >= amount_out {
+            self.assets.owned = math::checked_add(
+                self.assets.owned,
+                math::checked_sub(collateral_amount, amount_out)?,
+            )?;
+        } else {
+            self.assets.owned = math::checked_sub(
+                self.assets.owned,
+                math::checked_sub(amount_out, collateral_amount)?,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Covers position losses in excess of collateral from the insurance fund first,
+    /// the rest is socialized to liquidity providers. Returns covered and socialized
+    /// amounts in USD.
+    pub fn cover_bad_debt(
+        &mut self,
+        bad_debt_usd: u64,
+        token_price: &OraclePrice,
+        curtime: i64,
+    ) -> Result<(u64, u64)> {
+        if bad_debt_usd == 0 {
+            return Ok((0, 0));
+        }
+
+        let bad_debt = token_price.get_token_amount(bad_debt_usd, self.decimals)?;
+        let covered = std::cmp::min(bad_debt, self.assets.insurance_fund);
+        self.assets.insurance_fund = math::checked_sub(self.assets.insurance_fund, covered)?;
+        self.assets.owned = math::checked_add(self.assets.owned, covered)?;
+
+        let covered_usd = if covered == bad_debt {
+            bad_debt_usd
+        } else {
+            std::cmp::min(
+                token_price.get_asset_amount_usd(covered, self.decimals)?,
+                bad_debt_usd,
+            )
+        };
+        let socialized_usd = math::checked_sub(bad_debt_usd, covered_usd)?;
+        self.bad_debt_stats
+            .record(covered_usd, socialized_usd, curtime);
+
+        Ok((covered_usd, socialized_usd))
+    }
+
     pub fn get_interest_amount_usd(&self, position: &Position, curtime: i64) -> Result<u64> {
         if position.size_usd == 0 {
             return Ok(0);
@@ -394,6 +1110,104 @@ impl Custody {
         Ok(())
     }
 
+    /// Returns funding paid and received by the position since its last snapshot
+    pub fn get_funding_amount_usd(&self, position: &Position, curtime: i64) -> Result<(u64, u64)> {
+        if position.size_usd == 0 {
+            return Ok((0, 0));
+        }
+
+        let cumulative_funding = self.get_cumulative_funding(position.side, curtime)?;
+        let position_funding =
+            math::checked_sub(cumulative_funding, position.cumulative_funding_snapshot)?;
+
+        let funding_usd =
+            math::checked_mul(position_funding.unsigned_abs(), position.size_usd as u128)?;
+        if position_funding > 0 {
+            Ok((
+                math::checked_as_u64(math::checked_ceil_div(funding_usd, Perpetuals::RATE_POWER)?)?,
+                0,
+            ))
+        } else {
+            Ok((
+                0,
+                math::checked_as_u64(math::checked_div(funding_usd, Perpetuals::RATE_POWER)?)?,
+            ))
+        }
+    }
+
+    pub fn get_cumulative_funding(&self, side: Side, curtime: i64) -> Result<i128> {
+        let (rate, cumulative_funding) = if side == Side::Long {
+            (
+                self.funding_rate_state.long_rate,
+                self.funding_rate_state.cumulative_long_funding,
+            )
+        } else {
+            (
+                self.funding_rate_state.short_rate,
+                self.funding_rate_state.cumulative_short_funding,
+            )
+        };
+        if curtime > self.funding_rate_state.last_update {
+            let funding = math::checked_div(
+                math::checked_mul(
+                    math::checked_sub(curtime, self.funding_rate_state.last_update)? as i128,
+                    rate as i128,
+                )?,
+                3600,
+            )?;
+            math::checked_add(cumulative_funding, funding)
+        } else {
+            Ok(cumulative_funding)
+        }
+    }
+
+    pub fn update_funding_rate(&mut self, curtime: i64) -> Result<()> {
+        // rate = max_rate * (oi_long - oi_short) / (oi_long + oi_short)
+        //
+        // the majority side pays the rate on its size, the minority side receives
+        // it scaled by majority_oi / minority_oi, so that funding is zero-sum:
+        //   long_rate = rate * max(oi_long, oi_short) / oi_long
+        //   short_rate = -rate * max(oi_long, oi_short) / oi_short
+
+        if curtime > self.funding_rate_state.last_update {
+            // compute funding accumulated since previous update
+            self.funding_rate_state.cumulative_long_funding =
+                self.get_cumulative_funding(Side::Long, curtime)?;
+            self.funding_rate_state.cumulative_short_funding =
+                self.get_cumulative_funding(Side::Short, curtime)?;
+            self.funding_rate_state.last_update = curtime;
+        }
+
+        let oi_long = self.trade_stats.oi_long_usd as i128;
+        let oi_short = self.trade_stats.oi_short_usd as i128;
+
+        // nobody to pay if one of the sides is empty
+        if oi_long == 0 || oi_short == 0 {
+            self.funding_rate_state.long_rate = 0;
+            self.funding_rate_state.short_rate = 0;
+            return Ok(());
+        }
+
+        let hourly_rate = math::checked_div(
+            math::checked_mul(
+                self.funding_rate.max_rate as i128,
+                math::checked_sub(oi_long, oi_short)?,
+            )?,
+            math::checked_add(oi_long, oi_short)?,
+        )?;
+        // rounded towards zero, the minority side never receives more than is paid
+        let oi_majority = std::cmp::max(oi_long, oi_short);
+        let long_rate = math::checked_div(math::checked_mul(hourly_rate, oi_majority)?, oi_long)?;
+        let short_rate =
+            -math::checked_div(math::checked_mul(hourly_rate, oi_majority)?, oi_short)?;
+        self.funding_rate_state.long_rate =
+            i64::try_from(long_rate).map_err(|_| PerpetualsError::MathOverflow)?;
+        self.funding_rate_state.short_rate =
+            i64::try_from(short_rate).map_err(|_| PerpetualsError::MathOverflow)?;
+
+        Ok(())
+    }
+
     pub fn get_collective_position(&self, side: Side) -> Result<Position> {
         let stats = if side == Side::Long {
             &self.long_positions
@@ -419,15 +1233,22 @@ impl Custody {
         }
     }
 
+    /// Adds the position to the stats of the traded custody. Interest and locked
+    /// funds are owed to the collateral custody, `None` if it is this custody.
     pub fn add_position(
         &mut self,
         position: &Position,
-        token_price: &OraclePrice,
+        collateral_token_price: &OraclePrice,
         curtime: i64,
+        collateral_custody: Option<&Custody>,
     ) -> Result<()> {
+        let collateral_custody = collateral_custody.unwrap_or(&*self);
+        let collateral_decimals = collateral_custody.decimals;
+
         // compute accumulated interest
         let collective_position = self.get_collective_position(position.side)?;
-        let interest_usd = self.get_interest_amount_usd(&collective_position, curtime)?;
+        let interest_usd =
+            collateral_custody.get_interest_amount_usd(&collective_position, curtime)?;
 
         // update positions
         let stats = if position.side == Side::Long {
@@ -462,16 +1283,16 @@ impl Custody {
 
         // check limits
         if self.pricing.max_position_locked_usd > 0 {
-            let locked_amount_usd =
-                token_price.get_asset_amount_usd(position.locked_amount, self.decimals)?;
+            let locked_amount_usd = collateral_token_price
+                .get_asset_amount_usd(position.locked_amount, collateral_decimals)?;
             require!(
                 locked_amount_usd <= self.pricing.max_position_locked_usd,
                 PerpetualsError::PositionAmountLimit
             );
         }
         if self.pricing.max_total_locked_usd > 0 {
-            let locked_amount_usd =
-                token_price.get_asset_amount_usd(stats.locked_amount, self.decimals)?;
+            let locked_amount_usd = collateral_token_price
+                .get_asset_amount_usd(stats.locked_amount, collateral_decimals)?;
             require!(
                 locked_amount_usd <= self.pricing.max_total_locked_usd,
                 PerpetualsError::CustodyAmountLimit
@@ -481,12 +1302,22 @@ impl Custody {
         Ok(())
     }
 
-    pub fn remove_position(&mut self, position: &Position, curtime: i64) -> Result<()> {
+    /// Removes the position from the stats of the traded custody, see add_position().
+    pub fn remove_position(
+        &mut self,
+        position: &Position,
+        curtime: i64,
+        collateral_custody: Option<&Custody>,
+    ) -> Result<()> {
+        let collateral_custody = collateral_custody.unwrap_or(&*self);
+
         // compute accumulated interest
         let collective_position = self.get_collective_position(position.side)?;
-        let interest_usd = self.get_interest_amount_usd(&collective_position, curtime)?;
-        let cumulative_interest_snapshot = self.get_cumulative_interest(curtime)?;
-        let position_interest_usd = self.get_interest_amount_usd(position, curtime)?;
+        let interest_usd =
+            collateral_custody.get_interest_amount_usd(&collective_position, curtime)?;
+        let cumulative_interest_snapshot = collateral_custody.get_cumulative_interest(curtime)?;
+        let position_interest_usd =
+            collateral_custody.get_interest_amount_usd(position, curtime)?;
 
         // update stats
         let stats = if position.side == Side::Long {
@@ -553,15 +1384,134 @@ impl Custody {
 
         Ok(())
     }
+
+    // private helpers
+    fn get_degraded_band(&self, price: &OraclePrice) -> Result<(OraclePrice, OraclePrice)> {
+        let spread = math::checked_as_u64(math::checked_div(
+            math::checked_mul(price.price as u128, self.degraded_mode.spread as u128)?,
+            Perpetuals::BPS_POWER,
+        )?)?;
+        Ok((
+            OraclePrice::new_with_conf(
+                math::checked_add(price.price, spread)?,
+                price.exponent,
+                price.conf,
+            ),
+            OraclePrice::new_with_conf(
+                math::checked_sub(price.price, spread)?,
+                price.exponent,
+                price.conf,
+            ),
+        ))
+    }
 }
 
 impl DeprecatedCustody {
     pub const LEN: usize = 8 + std::mem::size_of::<DeprecatedCustody>();
+
+    /// Converts a custody with the launch layout, parameters added since then start
+    /// disabled until the admin sets them with set_custody_config
+    pub fn upgrade(&self) -> Result<Custody> {
+        let oracle = OracleParams {
+            oracle_account: self.oracle.oracle_account,
+            oracle_type: self.oracle.oracle_type,
+            max_price_error: self.oracle.max_price_error,
+            max_price_age_sec: self.oracle.max_price_age_sec,
+            ..Default::default()
+        };
+
+        let pricing = PricingParams {
+            use_ema: self.pricing.use_ema,
+            use_unrealized_pnl_in_aum: self.pricing.use_unrealized_pnl_in_aum,
+            trade_spread_long: self.pricing.trade_spread_long,
+            trade_spread_short: self.pricing.trade_spread_short,
+            swap_spread: self.pricing.swap_spread,
+            min_initial_leverage: self.pricing.min_initial_leverage,
+            max_initial_leverage: self.pricing.max_initial_leverage,
+            max_leverage: self.pricing.max_leverage,
+            liquidation_target_leverage: 0,
+            max_payoff_mult: self.pricing.max_payoff_mult,
+            max_utilization: self.pricing.max_utilization,
+            max_position_locked_usd: self.pricing.max_position_locked_usd,
+            max_total_locked_usd: self.pricing.max_total_locked_usd,
+            conf_mult: 0,
+        };
+
+        // launch liquidations charged the close fee on the size and paid the liquidator
+        // `liquidation` of the remaining payout, about size / max_leverage, both are
+        // converted to a penalty on the size that pays the same share to the liquidator
+        let reward = if self.fees.liquidation > 0 {
+            math::checked_as_u64(math::checked_ceil_div(
+                math::checked_mul(self.fees.liquidation as u128, Perpetuals::BPS_POWER)?,
+                self.pricing.max_leverage as u128,
+            )?)?
+        } else {
+            0
+        };
+        let liquidation = math::checked_add(self.fees.close_position, reward)?;
+        let liquidator_share = if liquidation > 0 {
+            math::checked_as_u64(math::checked_div(
+                math::checked_mul(reward as u128, Perpetuals::BPS_POWER)?,
+                liquidation as u128,
+            )?)?
+        } else {
+            0
+        };
+
+        let fees = Fees {
+            mode: self.fees.mode,
+            ratio_mult: self.fees.ratio_mult,
+            utilization_mult: self.fees.utilization_mult,
+            swap_in: self.fees.swap_in,
+            swap_out: self.fees.swap_out,
+            stable_swap_in: self.fees.stable_swap_in,
+            stable_swap_out: self.fees.stable_swap_out,
+            add_liquidity: self.fees.add_liquidity,
+            remove_liquidity: self.fees.remove_liquidity,
+            open_position: self.fees.open_position,
+            close_position: self.fees.close_position,
+            liquidation,
+            liquidator_share,
+            protocol_share: self.fees.protocol_share,
+            ..Default::default()
+        };
+
+        let assets = Assets {
+            collateral: self.assets.collateral,
+            protocol_fees: self.assets.protocol_fees,
+            owned: self.assets.owned,
+            locked: self.assets.locked,
+            insurance_fund: 0,
+        };
+
+        Ok(Custody {
+            pool: self.pool,
+            mint: self.mint,
+            token_account: self.token_account,
+            decimals: self.decimals,
+            is_stable: self.is_stable,
+            oracle,
+            pricing,
+            permissions: self.permissions,
+            fees,
+            borrow_rate: self.borrow_rate,
+            assets,
+            collected_fees: self.collected_fees,
+            volume_stats: self.volume_stats,
+            trade_stats: self.trade_stats,
+            long_positions: self.long_positions,
+            short_positions: self.short_positions,
+            borrow_rate_state: self.borrow_rate_state,
+            bump: self.bump,
+            token_account_bump: self.token_account_bump,
+            ..Default::default()
+        })
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::*;
+    use {super::*, crate::state::oracle::TestOracle};
 
     fn get_fixture() -> Custody {
         let assets = Assets {
@@ -679,4 +1629,538 @@ mod test {
         custody.update_borrow_rate(3600).unwrap();
         assert_eq!(custody.borrow_rate_state.current_rate, 199400);
     }
+
+    #[test]
+    fn test_reserve_fee() {
+        let mut custody = get_fixture();
+        custody.fees.fee_insurance_share = 2000;
+        custody.fees.protocol_share = 1000;
+
+        assert_eq!(custody.reserve_fee(1000).unwrap(), 280);
+        assert_eq!(custody.assets.insurance_fund, 200);
+        assert_eq!(custody.assets.protocol_fees, 80);
+        assert_eq!(custody.assets.owned, 1000);
+
+        custody.collect_fee(1000).unwrap();
+        assert_eq!(custody.assets.insurance_fund, 400);
+        assert_eq!(custody.assets.protocol_fees, 160);
+        assert_eq!(custody.assets.owned, 1720);
+    }
+
+    #[test]
+    fn test_settle_collateral() {
+        // loss, what is left of collateral after fees goes to the pool
+        let mut custody = get_fixture();
+        custody.assets.collateral = 300;
+        custody.settle_collateral(300, 100, 20).unwrap();
+        assert_eq!(custody.assets.collateral, 0);
+        assert_eq!(custody.assets.owned, 1180);
+
+        // profit is paid from owned funds
+        let mut custody = get_fixture();
+        custody.assets.collateral = 300;
+        custody.settle_collateral(300, 450, 20).unwrap();
+        assert_eq!(custody.assets.collateral, 0);
+        assert_eq!(custody.assets.owned, 830);
+    }
+
+    #[test]
+    fn test_update_circuit_breaker() {
+        let mut custody = Custody {
+            circuit_breaker: CircuitBreakerParams {
+                max_price_move: 500,
+                window_sec: 60,
+                cooldown_sec: 300,
+            },
+            ..Default::default()
+        };
+
+        // first price is accepted as the reference
+        custody
+            .update_circuit_breaker(&OraclePrice::new(10_000, -2), 1000)
+            .unwrap();
+        assert_eq!(
+            custody.circuit_breaker_state.last_price,
+            OraclePrice::new(10_000, -2)
+        );
+
+        // 4% move within the window
+        custody
+            .update_circuit_breaker(&OraclePrice::new(104_000, -3), 1030)
+            .unwrap();
+        assert!(!custody.is_close_only(1030));
+        assert_eq!(custody.circuit_breaker_state.last_price_time, 1030);
+
+        // 6% move within the window
+        custody
+            .update_circuit_breaker(&OraclePrice::new(11_024, -2), 1060)
+            .unwrap();
+        assert!(custody.is_close_only(1060));
+        assert_eq!(custody.circuit_breaker_state.tripped_time, 1060);
+        assert_eq!(custody.circuit_breaker_state.last_price_time, 1030);
+
+        // prices are ignored until the cooldown expires
+        custody
+            .update_circuit_breaker(&OraclePrice::new(10_400, -2), 1200)
+            .unwrap();
+        assert!(custody.is_close_only(1359));
+        assert_eq!(custody.circuit_breaker_state.last_price_time, 1030);
+
+        // the cooldown has passed, the allowed move grew with the time since the last price
+        assert!(!custody.is_close_only(1360));
+        custody
+            .update_circuit_breaker(&OraclePrice::new(11_024, -2), 1360)
+            .unwrap();
+        assert!(!custody.is_close_only(1360));
+        assert_eq!(
+            custody.circuit_breaker_state.last_price,
+            OraclePrice::new(11_024, -2)
+        );
+
+        // prices older than the window are still compared, 17.9% move after 140 seconds
+        custody
+            .update_circuit_breaker(&OraclePrice::new(13_000, -2), 1500)
+            .unwrap();
+        assert!(custody.is_close_only(1500));
+        assert_eq!(custody.circuit_breaker_state.last_price_time, 1360);
+    }
+
+    #[test]
+    fn test_record_trade_price() {
+        let mut custody = Custody {
+            circuit_breaker: CircuitBreakerParams {
+                max_price_move: 500,
+                window_sec: 60,
+                cooldown_sec: 300,
+            },
+            ..Default::default()
+        };
+
+        assert!(custody
+            .record_trade_price(&OraclePrice::new(10_000, -2), 1000)
+            .unwrap());
+        assert_eq!(custody.last_accepted_price_time, 1000);
+
+        // the price that trips the breaker is rejected without an error, so the trip is saved
+        assert!(!custody
+            .record_trade_price(&OraclePrice::new(11_000, -2), 1010)
+            .unwrap());
+        assert_eq!(custody.circuit_breaker_state.tripped_time, 1010);
+        assert_eq!(custody.last_accepted_price_time, 1000);
+        let event = custody.get_circuit_breaker_event(&OraclePrice::new(11_000, -2));
+        assert_eq!(event.price, OraclePrice::new(11_000, -2));
+        assert_eq!(event.last_price, OraclePrice::new(10_000, -2));
+        assert_eq!(event.tripped_time, 1010);
+
+        // later trades fail on the saved state
+        assert_eq!(
+            custody.record_trade_price(&OraclePrice::new(10_000, -2), 1020),
+            Err(PerpetualsError::CustodyCloseOnly.into())
+        );
+        assert_eq!(custody.circuit_breaker_state.tripped_time, 1010);
+    }
+
+    #[test]
+    fn test_update_twap() {
+        let mut custody = Custody {
+            twap: TwapParams {
+                window_sec: 70,
+                use_twap: true,
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            custody.get_twap_price(1000),
+            Err(PerpetualsError::TwapNotAvailable.into())
+        );
+
+        // first price has no history
+        custody
+            .update_twap(&OraclePrice::new(10_000, -2), 1000)
+            .unwrap();
+        assert_eq!(
+            custody.get_twap_price(1000).unwrap(),
+            OraclePrice::new(100_000_000, -6)
+        );
+
+        // previous price is accumulated over the elapsed time
+        custody
+            .update_twap(&OraclePrice::new(20_000, -2), 1035)
+            .unwrap();
+        assert_eq!(custody.twap_state.cumulative_price, 3_500_000_000);
+        assert_eq!(custody.twap_state.observation_idx, 1);
+        assert_eq!(
+            custody.get_twap_price(1035).unwrap(),
+            OraclePrice::new(100_000_000, -6)
+        );
+        assert_eq!(
+            custody.get_twap_price(1070).unwrap(),
+            OraclePrice::new(150_000_000, -6)
+        );
+
+        // observations are taken at most every 10 seconds
+        custody
+            .update_twap(&OraclePrice::new(30_000, -2), 1040)
+            .unwrap();
+        assert_eq!(custody.twap_state.observation_idx, 1);
+        assert_eq!(
+            custody.get_twap_price(1040).unwrap(),
+            OraclePrice::new(112_500_000, -6)
+        );
+        assert_eq!(
+            custody
+                .get_reference_price(&OraclePrice::new(1, 0), 1040)
+                .unwrap(),
+            OraclePrice::new(112_500_000, -6)
+        );
+        // the reference price is the TWAP at the current time, until it gets stale
+        assert_eq!(
+            custody
+                .get_reference_price(&OraclePrice::new(1, 0), 1060)
+                .unwrap(),
+            custody.get_twap_price(1060).unwrap()
+        );
+        assert_eq!(
+            custody.get_reference_price(&OraclePrice::new(1, 0), 1111),
+            Err(PerpetualsError::TwapNotAvailable.into())
+        );
+
+        // older observations are overwritten
+        for curtime in (1050..=1200).step_by(10) {
+            custody
+                .update_twap(&OraclePrice::new(30_000, -2), curtime)
+                .unwrap();
+        }
+        assert_eq!(custody.twap_state.observation_idx, 1);
+        assert_eq!(
+            custody.get_twap_price(1200).unwrap(),
+            OraclePrice::new(300_000_000, -6)
+        );
+
+        custody.twap.use_twap = false;
+        assert_eq!(
+            custody
+                .get_reference_price(&OraclePrice::new(1, 0), 2000)
+                .unwrap(),
+            OraclePrice::new(1, 0)
+        );
+    }
+
+    fn get_test_oracle_data(price: u64, publish_time: i64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(TestOracle::LEN);
+        TestOracle {
+            price,
+            expo: -2,
+            conf: 0,
+            publish_time,
+        }
+        .try_serialize(&mut data)
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_get_degradable_prices() {
+        let oracle_key = Pubkey::new_unique();
+        let fallback_key = Pubkey::new_unique();
+        let mut custody = Custody {
+            oracle: OracleParams {
+                oracle_account: oracle_key,
+                oracle_type: OracleType::Test,
+                max_price_error: 100,
+                max_price_age_sec: 10,
+                ..Default::default()
+            },
+            degraded_mode: DegradedModeParams {
+                enabled: true,
+                fallback_oracle: OracleSource {
+                    oracle_account: fallback_key,
+                    oracle_type: OracleType::Test,
+                    feed_id: [0; 32],
+                },
+                max_last_price_age_sec: 600,
+                spread: 100,
+            },
+            ..Default::default()
+        };
+        assert!(custody.degraded_mode.validate());
+
+        let (mut lamports, mut fallback_lamports) = (1_000_000, 1_000_000);
+        let mut data = get_test_oracle_data(10_000, 1000);
+        let mut fallback_data = get_test_oracle_data(10_100, 1100);
+        let oracle_account = AccountInfo::new(
+            &oracle_key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &crate::ID,
+            false,
+            0,
+        );
+        let fallback_account = AccountInfo::new(
+            &fallback_key,
+            false,
+            false,
+            &mut fallback_lamports,
+            &mut fallback_data,
+            &crate::ID,
+            false,
+            0,
+        );
+
+        // fresh prices are accepted as is
+        let price = OraclePrice::new(10_000, -2);
+        assert_eq!(
+            custody
+                .get_degradable_prices(&oracle_account, &[], 1005)
+                .unwrap(),
+            (price, price, false)
+        );
+        custody.record_price(&price, 1005).unwrap();
+        assert_eq!(custody.last_accepted_price_time, 1005);
+
+        // stale prices fall back to the last accepted price
+        assert_eq!(
+            custody
+                .get_degradable_prices(&oracle_account, &[], 1100)
+                .unwrap(),
+            (
+                OraclePrice::new(10_100, -2),
+                OraclePrice::new(9_900, -2),
+                true
+            )
+        );
+        assert_eq!(
+            custody.get_degradable_prices(&oracle_account, &[], 1606),
+            Err(PerpetualsError::StaleOraclePrice.into())
+        );
+
+        // or to the fallback oracle, which is only read once the primary is stale
+        let fallback_accounts = [fallback_account.clone()];
+        assert_eq!(
+            custody
+                .get_degradable_prices(&oracle_account, &fallback_accounts, 1005)
+                .unwrap(),
+            (price, price, false)
+        );
+        assert_eq!(
+            custody
+                .get_degradable_prices(&oracle_account, &fallback_accounts, 1105)
+                .unwrap(),
+            (
+                OraclePrice::new(10_201, -2),
+                OraclePrice::new(9_999, -2),
+                true
+            )
+        );
+        assert_eq!(
+            custody.get_degradable_prices(&fallback_account, &[], 1105),
+            Err(PerpetualsError::InvalidOracleAccount.into())
+        );
+
+        // stale prices are rejected without degraded mode
+        custody.degraded_mode.enabled = false;
+        assert_eq!(
+            custody.get_degradable_prices(&oracle_account, &[], 1100),
+            Err(PerpetualsError::StaleOraclePrice.into())
+        );
+        assert_eq!(
+            custody.get_degradable_prices(&oracle_account, &fallback_accounts, 1105),
+            Err(PerpetualsError::StaleOraclePrice.into())
+        );
+    }
+
+    #[test]
+    fn test_cover_bad_debt() {
+        let mut custody = get_fixture();
+        let token_price = OraclePrice::new(1000, -3);
+        custody.assets.insurance_fund = 4_000_000;
+
+        assert_eq!(
+            custody.cover_bad_debt(0, &token_price, 100).unwrap(),
+            (0, 0)
+        );
+        assert_eq!(custody.bad_debt_stats, BadDebtStats::default());
+
+        // fully covered by the insurance fund
+        assert_eq!(
+            custody
+                .cover_bad_debt(10_000_000, &token_price, 100)
+                .unwrap(),
+            (10_000_000, 0)
+        );
+        assert_eq!(custody.assets.insurance_fund, 3_000_000);
+        assert_eq!(custody.assets.owned, 1_001_000);
+
+        // the rest is socialized once the fund is drained
+        assert_eq!(
+            custody
+                .cover_bad_debt(100_000_000, &token_price, 200)
+                .unwrap(),
+            (30_000_000, 70_000_000)
+        );
+        assert_eq!(custody.assets.insurance_fund, 0);
+        assert_eq!(custody.assets.owned, 4_001_000);
+        assert_eq!(
+            custody.bad_debt_stats,
+            BadDebtStats {
+                covered_usd: 40_000_000,
+                socialized_usd: 70_000_000,
+                last_bad_debt_time: 200,
+                last_socialized_time: 200,
+            }
+        );
+    }
+
+    #[test]
+    fn test_custody_upgrade() {
+        let deprecated_custody = DeprecatedCustody {
+            pool: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            decimals: 9,
+            oracle: DeprecatedOracleParams {
+                oracle_account: Pubkey::new_unique(),
+                oracle_type: OracleType::Pyth,
+                max_price_error: 100,
+                max_price_age_sec: 60,
+            },
+            pricing: DeprecatedPricingParams {
+                max_leverage: 1000000,
+                max_utilization: 8000,
+                ..Default::default()
+            },
+            fees: DeprecatedFees {
+                mode: FeesMode::Linear,
+                close_position: 10,
+                liquidation: 500,
+                protocol_share: 10,
+                ..Default::default()
+            },
+            assets: DeprecatedAssets {
+                owned: 1000,
+                locked: 500,
+                ..Default::default()
+            },
+            bump: 1,
+            token_account_bump: 2,
+            ..Default::default()
+        };
+        let custody = deprecated_custody.upgrade().unwrap();
+
+        assert_eq!(custody.pool, deprecated_custody.pool);
+        assert_eq!(custody.mint, deprecated_custody.mint);
+        assert_eq!(
+            custody.oracle.oracle_account,
+            deprecated_custody.oracle.oracle_account
+        );
+        assert_eq!(custody.oracle.oracle_type, OracleType::Pyth);
+        assert_eq!(custody.oracle.max_price_age_sec, 60);
+        assert_eq!(custody.pricing.max_leverage, 1000000);
+        assert_eq!(custody.pricing.liquidation_target_leverage, 0);
+        assert_eq!(custody.fees.mode, FeesMode::Linear);
+        // the close fee plus 5% of the payout at 100x, a third goes to the liquidator
+        assert_eq!(custody.fees.close_position, 10);
+        assert_eq!(custody.fees.liquidation, 15);
+        assert_eq!(custody.fees.liquidator_share, 3333);
+        assert_eq!(custody.assets.owned, 1000);
+        assert_eq!(custody.assets.insurance_fund, 0);
+        assert_eq!(custody.bump, 1);
+        assert_eq!(custody.token_account_bump, 2);
+        assert_eq!(custody.circuit_breaker_state.tripped_time, 0);
+    }
+
+    #[test]
+    fn test_validate_synthetic() {
+        let mut custody = get_fixture();
+        custody.mint = Pubkey::new_unique();
+        custody.pricing.min_initial_leverage = Perpetuals::BPS_POWER as u64;
+        custody.pricing.max_initial_leverage = Perpetuals::BPS_POWER as u64;
+        custody.pricing.max_leverage = Perpetuals::BPS_POWER as u64;
+        custody.permissions = Permissions {
+            allow_open_position: true,
+            allow_close_position: true,
+            ..Permissions::default()
+        };
+
+        // regular custodies need a token account
+        assert!(!custody.validate());
+        custody.token_account = Pubkey::new_unique();
+        assert!(custody.validate());
+
+        custody.token_account = Pubkey::default();
+        custody.is_synthetic = true;
+        assert!(custody.validate());
+
+        custody.permissions.allow_swap = true;
+        assert!(!custody.validate());
+        custody.permissions.allow_swap = false;
+        custody.permissions.allow_add_liquidity = true;
+        assert!(!custody.validate());
+        custody.permissions.allow_add_liquidity = false;
+        custody.permissions.allow_remove_liquidity = true;
+        assert!(!custody.validate());
+        custody.permissions.allow_remove_liquidity = false;
+        custody.is_stable = true;
+        assert!(!custody.validate());
+    }
+
+    #[test]
+    fn test_validate_oracle_sources() {
+        let source = |oracle_type: OracleType, feed: u8| OracleSource {
+            oracle_account: Pubkey::new_unique(),
+            oracle_type,
+            feed_id: [feed; 32],
+        };
+        let mut oracle = OracleParams {
+            oracle_account: Pubkey::new_unique(),
+            oracle_type: OracleType::Aggregate,
+            sources: [
+                source(OracleType::PythPull, 1),
+                source(OracleType::PythPull, 2),
+                OracleSource::default(),
+            ],
+            publisher: Pubkey::new_unique(),
+            min_sources: 2,
+            ..Default::default()
+        };
+        assert!(oracle.validate());
+
+        // the quorum must be reachable
+        oracle.min_sources = 3;
+        assert!(!oracle.validate());
+        oracle.min_sources = 0;
+        assert!(!oracle.validate());
+        oracle.min_sources = 2;
+
+        oracle.oracle_type = OracleType::Composite;
+        assert!(oracle.validate());
+
+        // signed prices are only posted to the custody oracle account
+        oracle.sources[1] = source(OracleType::Signed, 2);
+        assert!(!oracle.validate());
+        oracle.oracle_type = OracleType::Aggregate;
+        assert!(!oracle.validate());
+
+        oracle.sources[1] = source(OracleType::Composite, 2);
+        assert!(!oracle.validate());
+    }
+
+    #[test]
+    fn test_check_quorum() {
+        let mut oracle = OracleParams {
+            oracle_type: OracleType::Aggregate,
+            min_sources: 2,
+            ..Default::default()
+        };
+        assert!(oracle.check_quorum(2).unwrap());
+        assert_eq!(
+            Err(PerpetualsError::OracleQuorumNotMet.into()),
+            oracle.check_quorum(1)
+        );
+
+        // the aggregate price is still recorded, but the custody is close-only
+        oracle.deviation_policy = DeviationPolicy::CloseOnly;
+        assert!(!oracle.check_quorum(1).unwrap());
+    }
 }
@@ -0,0 +1,250 @@
+//! Trading sessions of assets quoted on venues with market hours
+
+use {
+    crate::{error::PerpetualsError, math},
+    anchor_lang::prelude::*,
+};
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub enum MarketClosedPolicy {
+    #[default]
+    Allow,
+    Block,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct TradingSession {
+    // minutes since local midnight, the session is open in [open_minute, close_minute),
+    // sessions with open_minute > close_minute end at close_minute of the next day
+    pub open_minute: u16,
+    pub close_minute: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct MarketHours {
+    // market is always open if the schedule is disabled
+    pub enabled: bool,
+    // offset of the local exchange time from UTC
+    pub utc_offset_sec: i32,
+    // sessions from Monday to Sunday, days with open_minute == close_minute have none
+    pub sessions: [TradingSession; 7],
+    // local dates without trading as YYYYMMDD, unused slots are 0
+    pub holidays: [u32; 16],
+    // actions that may be performed outside of trading sessions,
+    // opens, increases and swaps are always blocked
+    pub close_position_policy: MarketClosedPolicy,
+    pub add_collateral_policy: MarketClosedPolicy,
+    pub liquidation_policy: MarketClosedPolicy,
+}
+
+impl MarketHours {
+    const MINUTES_PER_DAY: u16 = 1440;
+    const SECONDS_PER_DAY: i64 = 86400;
+    const MAX_UTC_OFFSET_SEC: i32 = 14 * 3600;
+
+    pub fn validate(&self) -> bool {
+        self.utc_offset_sec.abs() <= Self::MAX_UTC_OFFSET_SEC
+            && self.sessions.iter().all(|session| {
+                session.open_minute < Self::MINUTES_PER_DAY
+                    && session.close_minute <= Self::MINUTES_PER_DAY
+            })
+            && self.holidays.iter().all(|&date| {
+                date == 0
+                    || ((1..=12).contains(&(date / 100 % 100)) && (1..=31).contains(&(date % 100)))
+            })
+    }
+
+    pub fn is_open(&self, curtime: i64) -> Result<bool> {
+        if !self.enabled {
+            return Ok(true);
+        }
+
+        let local_time = math::checked_add(curtime, self.utc_offset_sec as i64)?;
+        let days = local_time.div_euclid(Self::SECONDS_PER_DAY);
+        if self.holidays.contains(&Self::get_date(days)) {
+            return Ok(false);
+        }
+
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 3).rem_euclid(7) as usize;
+        let minute = (local_time.rem_euclid(Self::SECONDS_PER_DAY) / 60) as u16;
+        let session = &self.sessions[weekday];
+        let prev_session = &self.sessions[(weekday + 6) % 7];
+
+        if session.open_minute <= session.close_minute {
+            if minute >= session.open_minute && minute < session.close_minute {
+                return Ok(true);
+            }
+        } else if minute >= session.open_minute {
+            return Ok(true);
+        }
+
+        // remainder of the previous day's session wrapping past midnight
+        Ok(prev_session.open_minute > prev_session.close_minute
+            && minute < prev_session.close_minute)
+    }
+
+    pub fn check_open(&self, curtime: i64) -> Result<()> {
+        require!(self.is_open(curtime)?, PerpetualsError::MarketClosed);
+        Ok(())
+    }
+
+    pub fn check_close_position(&self, curtime: i64) -> Result<()> {
+        self.check_policy(self.close_position_policy, curtime)
+    }
+
+    pub fn check_add_collateral(&self, curtime: i64) -> Result<()> {
+        self.check_policy(self.add_collateral_policy, curtime)
+    }
+
+    pub fn check_liquidation(&self, curtime: i64) -> Result<()> {
+        self.check_policy(self.liquidation_policy, curtime)
+    }
+
+    // private helpers
+    fn check_policy(&self, policy: MarketClosedPolicy, curtime: i64) -> Result<()> {
+        if policy == MarketClosedPolicy::Allow {
+            Ok(())
+        } else {
+            self.check_open(curtime)
+        }
+    }
+
+    /// Converts days since the unix epoch to a YYYYMMDD civil date
+    fn get_date(days: i64) -> u32 {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        (year * 10000 + month * 100 + day) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // weekdays 13:30 - 20:00 UTC, i.e. 09:30 - 16:00 in New York during daylight saving time
+    fn get_fixture() -> MarketHours {
+        let session = TradingSession {
+            open_minute: 570,
+            close_minute: 960,
+        };
+        let mut market_hours = MarketHours {
+            enabled: true,
+            utc_offset_sec: -4 * 3600,
+            sessions: [session; 7],
+            holidays: [0; 16],
+            close_position_policy: MarketClosedPolicy::Allow,
+            add_collateral_policy: MarketClosedPolicy::Allow,
+            liquidation_policy: MarketClosedPolicy::Block,
+        };
+        market_hours.sessions[5] = TradingSession::default();
+        market_hours.sessions[6] = TradingSession::default();
+        market_hours.holidays[0] = 20250704;
+        market_hours
+    }
+
+    #[test]
+    fn test_get_date() {
+        assert_eq!(MarketHours::get_date(0), 19700101);
+        assert_eq!(MarketHours::get_date(-1), 19691231);
+        assert_eq!(MarketHours::get_date(11016), 20000229);
+        assert_eq!(MarketHours::get_date(20274), 20250705);
+    }
+
+    #[test]
+    fn test_is_open() {
+        let market_hours = get_fixture();
+        assert!(market_hours.validate());
+
+        // Thursday 2025-07-03 13:29 and 13:30 UTC
+        assert!(!market_hours.is_open(1751549340).unwrap());
+        assert!(market_hours.is_open(1751549400).unwrap());
+        // Thursday 2025-07-03 19:59 and 20:00 UTC
+        assert!(market_hours.is_open(1751572740).unwrap());
+        assert!(!market_hours.is_open(1751572800).unwrap());
+        // Friday 2025-07-04 15:00 UTC, holiday
+        assert!(!market_hours.is_open(1751641200).unwrap());
+        // Saturday 2025-07-05 15:00 UTC
+        assert!(!market_hours.is_open(1751727600).unwrap());
+        // Monday 2025-07-07 15:00 UTC
+        assert!(market_hours.is_open(1751900400).unwrap());
+
+        let disabled = MarketHours::default();
+        assert!(disabled.is_open(1751727600).unwrap());
+    }
+
+    #[test]
+    fn test_check_policy() {
+        let market_hours = get_fixture();
+
+        // Saturday 2025-07-05 15:00 UTC
+        let curtime = 1751727600;
+        assert_eq!(
+            market_hours.check_open(curtime),
+            Err(PerpetualsError::MarketClosed.into())
+        );
+        assert!(market_hours.check_close_position(curtime).is_ok());
+        assert!(market_hours.check_add_collateral(curtime).is_ok());
+        assert_eq!(
+            market_hours.check_liquidation(curtime),
+            Err(PerpetualsError::MarketClosed.into())
+        );
+
+        // Monday 2025-07-07 15:00 UTC
+        assert!(market_hours.check_liquidation(1751900400).is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut market_hours = get_fixture();
+        market_hours.sessions[0].close_minute = 1441;
+        assert!(!market_hours.validate());
+
+        let mut market_hours = get_fixture();
+        market_hours.sessions[0].open_minute = 1440;
+        assert!(!market_hours.validate());
+
+        let mut market_hours = get_fixture();
+        market_hours.holidays[1] = 20251301;
+        assert!(!market_hours.validate());
+    }
+
+    #[test]
+    fn test_is_open_overnight() {
+        // Sunday 18:00 to Friday 17:00 in New York with a daily break from 17:00 to 18:00
+        let session = TradingSession {
+            open_minute: 1080,
+            close_minute: 1020,
+        };
+        let mut market_hours = get_fixture();
+        market_hours.sessions = [session; 7];
+        market_hours.sessions[4] = TradingSession::default();
+        market_hours.sessions[5] = TradingSession::default();
+        market_hours.holidays = [0; 16];
+        assert!(market_hours.validate());
+
+        // Sunday 2025-07-06 21:59 and 22:00 UTC
+        assert!(!market_hours.is_open(1751839140).unwrap());
+        assert!(market_hours.is_open(1751839200).unwrap());
+        // Monday 2025-07-07 04:00 UTC, still Sunday's session in New York
+        assert!(market_hours.is_open(1751860800).unwrap());
+        // Monday 2025-07-07 20:59 UTC and during the break at 21:30 UTC
+        assert!(market_hours.is_open(1751921940).unwrap());
+        assert!(!market_hours.is_open(1751923800).unwrap());
+        // Friday 2025-07-11 20:59 and 21:00 UTC, end of Thursday's session
+        assert!(market_hours.is_open(1752267540).unwrap());
+        assert!(!market_hours.is_open(1752267600).unwrap());
+        // Friday 2025-07-11 22:30 UTC, no session opens on Friday
+        assert!(!market_hours.is_open(1752273000).unwrap());
+        // Saturday 2025-07-12 15:00 UTC
+        assert!(!market_hours.is_open(1752332400).unwrap());
+    }
+}
@@ -36,6 +36,12 @@ pub enum AdminInstruction {
     SetTestOraclePrice,
     SetTestTime,
     UpgradeCustody,
+    ResetCircuitBreaker,
+    AddSyntheticCustody,
+    SetPerpetualsConfig,
+    UpgradePool,
+    UpgradePerpetuals,
+    SetPoolConfig,
 }
 
 impl Multisig {
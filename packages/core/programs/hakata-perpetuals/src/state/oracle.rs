@@ -1,8 +1,16 @@
 //! Oracle price service handling
 
 use {
-    crate::{error::PerpetualsError, math, state::perpetuals::Perpetuals, try_from},
-    anchor_lang::prelude::*,
+    crate::{
+        error::PerpetualsError,
+        math,
+        state::{custody::OracleParams, perpetuals::Perpetuals},
+        try_from,
+    },
+    anchor_lang::{
+        prelude::*,
+        solana_program::{ed25519_program, instruction::Instruction, pubkey},
+    },
     core::cmp::Ordering,
 };
 
@@ -10,11 +18,25 @@ const ORACLE_EXPONENT_SCALE: i32 = -9;
 const ORACLE_PRICE_SCALE: u64 = 1_000_000_000;
 const ORACLE_MAX_PRICE: u64 = (1 << 28) - 1;
 
+// owner of PriceUpdateV2 accounts
+pub const PYTH_RECEIVER_PROGRAM_ID: Pubkey = pubkey!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+
+// owners of Switchboard On-Demand pull feed accounts on mainnet and devnet
+pub const SWITCHBOARD_PROGRAM_IDS: [Pubkey; 2] = [
+    pubkey!("SBondMDrcV3K4kxZR1HNVT7osZxAHVHgYXL5Ze1oMUv"),
+    pubkey!("Aio4gaXjXzJNVLtzwtNVmSqGKpANtXhybbkhtAC94ji2"),
+];
+
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Debug)]
 pub enum OracleType {
     None,
     Test,
     Pyth,
+    PythPull,
+    Switchboard,
+    Aggregate,
+    Signed,
+    Composite,
 }
 
 impl Default for OracleType {
@@ -23,10 +45,61 @@ impl Default for OracleType {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub enum OracleAggregation {
+    #[default]
+    Median,
+    // first available source in order
+    PrimaryWithFallback,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub enum OracleComposition {
+    // product of the source prices, e.g. EUR/USD * USD/JPY
+    #[default]
+    Multiply,
+    // first source price divided by the following ones, e.g. NKY/USD / JPY/USD
+    Divide,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub enum DeviationPolicy {
+    // aggregate price is not updated
+    #[default]
+    Reject,
+    // aggregate price is updated and the custody is switched to close-only
+    CloseOnly,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct OracleSource {
+    pub oracle_account: Pubkey,
+    pub oracle_type: OracleType,
+    pub feed_id: [u8; 32],
+}
+
+impl OracleSource {
+    /// Returns true for oracles maintained by a crank from other sources
+    pub fn is_derived(&self) -> bool {
+        matches!(
+            self.oracle_type,
+            OracleType::Aggregate | OracleType::Composite
+        )
+    }
+
+    /// Returns true for oracles that can be read on behalf of another one, signed
+    /// prices are only posted to the custody's own oracle account
+    pub fn can_be_source(&self) -> bool {
+        !self.is_derived() && self.oracle_type != OracleType::Signed
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
 pub struct OraclePrice {
     pub price: u64,
     pub exponent: i32,
+    // confidence interval of the price, has the same exponent
+    pub conf: u64,
 }
 
 #[account]
@@ -42,6 +115,300 @@ impl TestOracle {
     pub const LEN: usize = 8 + std::mem::size_of::<TestOracle>();
 }
 
+/// Publisher signed price recorded by post_signed_price
+#[account]
+#[derive(Default, Debug)]
+pub struct SignedOracle {
+    pub custody: Pubkey,
+    pub price: u64,
+    pub expo: i32,
+    pub conf: u64,
+    pub publish_time: i64,
+    pub bump: u8,
+}
+
+impl SignedOracle {
+    pub const LEN: usize = 8 + std::mem::size_of::<SignedOracle>();
+}
+
+/// Borsh encoded message signed by a price publisher
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct SignedPriceMessage {
+    pub feed_id: [u8; 32],
+    pub price: u64,
+    pub expo: i32,
+    pub conf: u64,
+    pub publish_time: i64,
+}
+
+impl SignedPriceMessage {
+    pub const LEN: usize = 32 + 8 + 4 + 8 + 8;
+    // publishers' clocks may run ahead of the cluster by this much
+    pub const MAX_CLOCK_SKEW_SEC: i64 = 5;
+
+    // Ed25519SignatureOffsets layout of the ed25519 program
+    const SIGNATURE_OFFSETS_START: usize = 2;
+    const SIGNATURE_OFFSETS_LEN: usize = 14;
+
+    /// Extracts the message from an ed25519 program instruction that verifies
+    /// a single publisher signature. Signature, public key and message must be
+    /// inline so they can't be taken from another instruction.
+    pub fn try_from_ed25519_instruction(
+        instruction: &Instruction,
+        publisher: &Pubkey,
+    ) -> Result<Self> {
+        require_keys_eq!(
+            instruction.program_id,
+            ed25519_program::ID,
+            PerpetualsError::InvalidSignedPrice
+        );
+        let data = &instruction.data;
+        require!(
+            data.len() >= Self::SIGNATURE_OFFSETS_START + Self::SIGNATURE_OFFSETS_LEN
+                && data[0] == 1,
+            PerpetualsError::InvalidSignedPrice
+        );
+
+        let read_u16 = |idx: usize| {
+            let offset = Self::SIGNATURE_OFFSETS_START + idx * 2;
+            u16::from_le_bytes([data[offset], data[offset + 1]])
+        };
+        // signature, public key and message instruction indices
+        require!(
+            read_u16(1) == u16::MAX && read_u16(3) == u16::MAX && read_u16(6) == u16::MAX,
+            PerpetualsError::InvalidSignedPrice
+        );
+
+        let public_key_offset = read_u16(2) as usize;
+        let signer = data
+            .get(public_key_offset..public_key_offset + 32)
+            .ok_or(PerpetualsError::InvalidSignedPrice)?;
+        require!(
+            signer == publisher.as_ref(),
+            PerpetualsError::InvalidSignedPrice
+        );
+
+        let message_offset = read_u16(4) as usize;
+        let message_size = read_u16(5) as usize;
+        require_eq!(message_size, Self::LEN, PerpetualsError::InvalidSignedPrice);
+        let message = data
+            .get(message_offset..message_offset + message_size)
+            .ok_or(PerpetualsError::InvalidSignedPrice)?;
+
+        Self::try_from_slice(message).map_err(|_| PerpetualsError::InvalidSignedPrice.into())
+    }
+
+    /// Fails unless the message is newer than the last accepted one and not from
+    /// the future, which would keep it fresh past the max price age
+    pub fn check_publish_time(&self, last_publish_time: i64, curtime: i64) -> Result<()> {
+        require_gt!(
+            self.publish_time,
+            last_publish_time,
+            PerpetualsError::SignedPriceNotNewer
+        );
+        require!(
+            self.publish_time <= math::checked_add(curtime, Self::MAX_CLOCK_SKEW_SEC)?,
+            PerpetualsError::SignedPriceInFuture
+        );
+        Ok(())
+    }
+}
+
+/// Price aggregated from the custody oracle sources by update_oracle_aggregate
+#[account]
+#[derive(Default, Debug)]
+pub struct OracleAggregate {
+    pub custody: Pubkey,
+    pub price: u64,
+    pub ema_price: u64,
+    pub expo: i32,
+    // oldest publish time among the sources used
+    pub publish_time: i64,
+    pub num_sources: u8,
+    pub bump: u8,
+}
+
+/// Price composed from the custody oracle sources by update_oracle_composite
+#[account]
+#[derive(Default, Debug)]
+pub struct OracleComposite {
+    pub custody: Pubkey,
+    pub price: u64,
+    pub ema_price: u64,
+    pub expo: i32,
+    // confidence intervals combined from the sources, with the same exponent
+    pub conf: u64,
+    pub ema_conf: u64,
+    // oldest publish time among the sources
+    pub publish_time: i64,
+    pub bump: u8,
+}
+
+impl OracleComposite {
+    pub const LEN: usize = 8 + std::mem::size_of::<OracleComposite>();
+
+    /// Folds source prices in order, the result is rescaled to ORACLE_EXPONENT_SCALE
+    pub fn compose(prices: &[OraclePrice], composition: OracleComposition) -> Result<OraclePrice> {
+        let (first, rest) = prices
+            .split_first()
+            .ok_or(PerpetualsError::InvalidOracleState)?;
+
+        let mut price = first.normalize()?;
+        for other in rest {
+            price = match composition {
+                OracleComposition::Multiply => price.checked_mul(&other.normalize()?)?,
+                OracleComposition::Divide => price.checked_div(other)?,
+            }
+            .normalize()?;
+        }
+
+        price.scale_to_exponent(ORACLE_EXPONENT_SCALE)
+    }
+}
+
+impl OracleAggregate {
+    pub const LEN: usize = 8 + std::mem::size_of::<OracleAggregate>();
+
+    /// Combines source prices into one, prices are rescaled to a common exponent
+    pub fn combine(prices: &[OraclePrice], aggregation: OracleAggregation) -> Result<OraclePrice> {
+        let mut scaled = Self::scale_prices(prices)?;
+        require!(!scaled.is_empty(), PerpetualsError::InvalidOracleState);
+
+        let price = match aggregation {
+            OracleAggregation::PrimaryWithFallback => scaled[0],
+            OracleAggregation::Median => {
+                scaled.sort_unstable();
+                let mid = scaled.len() / 2;
+                if scaled.len() % 2 == 0 {
+                    math::checked_div(math::checked_add(scaled[mid - 1], scaled[mid])?, 2)?
+                } else {
+                    scaled[mid]
+                }
+            }
+        };
+
+        Ok(OraclePrice::new(price, ORACLE_EXPONENT_SCALE))
+    }
+
+    /// Returns the spread between the highest and lowest source price in BPS of the aggregate
+    pub fn get_deviation(prices: &[OraclePrice], aggregate_price: &OraclePrice) -> Result<u64> {
+        let scaled = Self::scale_prices(prices)?;
+        let aggregate_price = aggregate_price.scale_to_exponent(ORACLE_EXPONENT_SCALE)?;
+        if scaled.len() < 2 || aggregate_price.price == 0 {
+            return Ok(0);
+        }
+
+        let max_price = scaled.iter().max().copied().unwrap_or_default();
+        let min_price = scaled.iter().min().copied().unwrap_or_default();
+
+        math::checked_as_u64(math::checked_div(
+            math::checked_mul((max_price - min_price) as u128, Perpetuals::BPS_POWER)?,
+            aggregate_price.price as u128,
+        )?)
+    }
+
+    fn scale_prices(prices: &[OraclePrice]) -> Result<Vec<u64>> {
+        prices
+            .iter()
+            .map(|price| Ok(price.scale_to_exponent(ORACLE_EXPONENT_SCALE)?.price))
+            .collect()
+    }
+}
+
+// Pyth receiver account layouts, mirrored to avoid pulling in the receiver sdk
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Debug)]
+pub enum VerificationLevel {
+    Partial { num_signatures: u8 },
+    Full,
+}
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct PriceFeedMessage {
+    pub feed_id: [u8; 32],
+    pub price: i64,
+    pub conf: u64,
+    pub exponent: i32,
+    pub publish_time: i64,
+    pub prev_publish_time: i64,
+    pub ema_price: i64,
+    pub ema_conf: u64,
+}
+
+#[derive(Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Debug)]
+pub struct PriceUpdateV2 {
+    pub write_authority: Pubkey,
+    pub verification_level: VerificationLevel,
+    pub price_message: PriceFeedMessage,
+    pub posted_slot: u64,
+}
+
+impl PriceUpdateV2 {
+    pub const DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+
+    pub fn try_from_account(account: &AccountInfo) -> Result<Self> {
+        require_keys_eq!(
+            *account.owner,
+            PYTH_RECEIVER_PROGRAM_ID,
+            PerpetualsError::InvalidOracleAccount
+        );
+        let data = account.try_borrow_data()?;
+        require!(
+            data.len() >= 8 && data[..8] == Self::DISCRIMINATOR,
+            PerpetualsError::InvalidOracleAccount
+        );
+        Self::deserialize(&mut &data[8..]).map_err(|_| PerpetualsError::InvalidOracleAccount.into())
+    }
+}
+
+/// Fields of a Switchboard On-Demand PullFeedAccountData account used for pricing.
+/// The account is zero-copy, so fields are read at their fixed offsets.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct SwitchboardPullFeed {
+    // median of the oracle submissions, with SWITCHBOARD_DECIMALS decimals
+    pub value: i128,
+    // standard deviation of the oracle submissions, with SWITCHBOARD_DECIMALS decimals
+    pub std_dev: i128,
+    pub last_update_timestamp: i64,
+}
+
+impl SwitchboardPullFeed {
+    pub const DISCRIMINATOR: [u8; 8] = [196, 27, 108, 196, 10, 215, 219, 40];
+    pub const LEN: usize = 8 + 3200;
+    pub const SWITCHBOARD_DECIMALS: u32 = 18;
+
+    // offsets include the account discriminator
+    const LAST_UPDATE_TIMESTAMP_OFFSET: usize = 8 + 2208;
+    const RESULT_VALUE_OFFSET: usize = 8 + 2256;
+    const RESULT_STD_DEV_OFFSET: usize = 8 + 2272;
+
+    pub fn try_from_account(account: &AccountInfo) -> Result<Self> {
+        require!(
+            SWITCHBOARD_PROGRAM_IDS.contains(account.owner),
+            PerpetualsError::InvalidOracleAccount
+        );
+        let data = account.try_borrow_data()?;
+        require!(
+            data.len() >= Self::LEN && data[..8] == Self::DISCRIMINATOR,
+            PerpetualsError::InvalidOracleAccount
+        );
+        Ok(Self {
+            value: i128::from_le_bytes(Self::read_bytes(&data, Self::RESULT_VALUE_OFFSET)),
+            std_dev: i128::from_le_bytes(Self::read_bytes(&data, Self::RESULT_STD_DEV_OFFSET)),
+            last_update_timestamp: i64::from_le_bytes(Self::read_bytes(
+                &data,
+                Self::LAST_UPDATE_TIMESTAMP_OFFSET,
+            )),
+        })
+    }
+
+    fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(&data[offset..offset + N]);
+        bytes
+    }
+}
+
 impl PartialOrd for OraclePrice {
     fn partial_cmp(&self, other: &OraclePrice) -> Option<Ordering> {
         let (lhs, rhs) = if self.exponent == other.exponent {
@@ -64,35 +431,75 @@ impl PartialOrd for OraclePrice {
 #[allow(dead_code)]
 impl OraclePrice {
     pub fn new(price: u64, exponent: i32) -> Self {
-        Self { price, exponent }
+        Self {
+            price,
+            exponent,
+            conf: 0,
+        }
     }
 
-    pub fn new_from_token(amount_and_decimals: (u64, u8)) -> Self {
+    pub fn new_with_conf(price: u64, exponent: i32, conf: u64) -> Self {
         Self {
-            price: amount_and_decimals.0,
-            exponent: -(amount_and_decimals.1 as i32),
+            price,
+            exponent,
+            conf,
         }
     }
 
+    pub fn new_from_token(amount_and_decimals: (u64, u8)) -> Self {
+        Self::new(amount_and_decimals.0, -(amount_and_decimals.1 as i32))
+    }
+
     pub fn new_from_oracle(
-        oracle_type: OracleType,
+        oracle_params: &OracleParams,
         oracle_account: &AccountInfo,
-        max_price_error: u64,
-        max_price_age_sec: u32,
         current_time: i64,
         use_ema: bool,
     ) -> Result<Self> {
-        match oracle_type {
+        match oracle_params.oracle_type {
             OracleType::Test => Self::get_test_price(
                 oracle_account,
-                max_price_error,
-                max_price_age_sec,
+                oracle_params.max_price_error,
+                oracle_params.max_price_age_sec,
                 current_time,
             ),
             OracleType::Pyth => Self::get_pyth_price(
                 oracle_account,
-                max_price_error,
-                max_price_age_sec,
+                oracle_params.max_price_error,
+                oracle_params.max_price_age_sec,
+                current_time,
+                use_ema,
+            ),
+            OracleType::PythPull => Self::get_pyth_pull_price(
+                oracle_account,
+                &oracle_params.feed_id,
+                oracle_params.max_price_error,
+                oracle_params.max_price_age_sec,
+                current_time,
+                use_ema,
+            ),
+            OracleType::Switchboard => Self::get_switchboard_price(
+                oracle_account,
+                oracle_params.max_price_error,
+                oracle_params.max_price_age_sec,
+                current_time,
+            ),
+            OracleType::Aggregate => Self::get_aggregate_price(
+                oracle_account,
+                oracle_params.max_price_age_sec,
+                current_time,
+                use_ema,
+            ),
+            OracleType::Signed => Self::get_signed_price(
+                oracle_account,
+                oracle_params.max_price_error,
+                oracle_params.max_price_age_sec,
+                current_time,
+            ),
+            OracleType::Composite => Self::get_composite_price(
+                oracle_account,
+                oracle_params.max_price_error,
+                oracle_params.max_price_age_sec,
                 current_time,
                 use_ema,
             ),
@@ -100,6 +507,68 @@ impl OraclePrice {
         }
     }
 
+    /// Returns the time the current oracle price was published at
+    pub fn get_publish_time(
+        oracle_params: &OracleParams,
+        oracle_account: &AccountInfo,
+    ) -> Result<i64> {
+        match oracle_params.oracle_type {
+            OracleType::Test => {
+                let oracle_acc = try_from!(Account<TestOracle>, oracle_account)?;
+                Ok(oracle_acc.publish_time)
+            }
+            OracleType::Pyth => {
+                // TODO: Update deprecated load_price_feed_from_account_info
+                let price_feed = pyth_sdk_solana::load_price_feed_from_account_info(oracle_account)
+                    .map_err(|_| PerpetualsError::InvalidOracleAccount)?;
+                Ok(price_feed.get_price_unchecked().publish_time)
+            }
+            OracleType::PythPull => {
+                let price_update = PriceUpdateV2::try_from_account(oracle_account)?;
+                require!(
+                    price_update.price_message.feed_id == oracle_params.feed_id,
+                    PerpetualsError::OracleFeedMismatch
+                );
+                Ok(price_update.price_message.publish_time)
+            }
+            OracleType::Switchboard => {
+                let feed = SwitchboardPullFeed::try_from_account(oracle_account)?;
+                Ok(feed.last_update_timestamp)
+            }
+            OracleType::Aggregate => {
+                let oracle_acc = try_from!(Account<OracleAggregate>, oracle_account)?;
+                Ok(oracle_acc.publish_time)
+            }
+            OracleType::Signed => {
+                let oracle_acc = try_from!(Account<SignedOracle>, oracle_account)?;
+                Ok(oracle_acc.publish_time)
+            }
+            OracleType::Composite => {
+                let oracle_acc = try_from!(Account<OracleComposite>, oracle_account)?;
+                Ok(oracle_acc.publish_time)
+            }
+            _ => err!(PerpetualsError::UnsupportedOracle),
+        }
+    }
+
+    /// Returns the slot the current oracle price was posted at, if the oracle records it
+    pub fn get_posted_slot(
+        oracle_params: &OracleParams,
+        oracle_account: &AccountInfo,
+    ) -> Result<Option<u64>> {
+        match oracle_params.oracle_type {
+            OracleType::PythPull => {
+                let price_update = PriceUpdateV2::try_from_account(oracle_account)?;
+                require!(
+                    price_update.price_message.feed_id == oracle_params.feed_id,
+                    PerpetualsError::OracleFeedMismatch
+                );
+                Ok(Some(price_update.posted_slot))
+            }
+            _ => Ok(None),
+        }
+    }
+
     // Converts token amount to USD using oracle price
     pub fn get_asset_value_usd(&self, token_amount: u64, token_decimals: u8) -> Result<f64> {
         if token_amount == 0 || self.price == 0 {
@@ -156,32 +625,48 @@ impl OraclePrice {
             e = math::checked_add(e, 1)?;
         }
 
-        Ok(OraclePrice {
-            price: p,
-            exponent: e,
-        })
+        self.scale_to_exponent(e)
     }
 
+    /// Relative confidence of the result is the sum of the relative confidences
     pub fn checked_div(&self, other: &OraclePrice) -> Result<OraclePrice> {
         let base = self.normalize()?;
         let other = other.normalize()?;
 
-        Ok(OraclePrice {
-            price: math::checked_div(
-                math::checked_mul(base.price, ORACLE_PRICE_SCALE)?,
+        let price = math::checked_div(
+            math::checked_mul(base.price, ORACLE_PRICE_SCALE)?,
+            other.price,
+        )?;
+        let conf = math::checked_add(
+            math::checked_div(
+                math::checked_mul(base.conf, ORACLE_PRICE_SCALE)?,
                 other.price,
             )?,
+            math::checked_as_u64(math::checked_div(
+                math::checked_mul(price as u128, other.conf as u128)?,
+                other.price as u128,
+            )?)?,
+        )?;
+
+        Ok(OraclePrice {
+            price,
             exponent: math::checked_sub(
                 math::checked_add(base.exponent, ORACLE_EXPONENT_SCALE)?,
                 other.exponent,
             )?,
+            conf,
         })
     }
 
+    /// Relative confidence of the result is the sum of the relative confidences
     pub fn checked_mul(&self, other: &OraclePrice) -> Result<OraclePrice> {
         Ok(OraclePrice {
             price: math::checked_mul(self.price, other.price)?,
             exponent: math::checked_add(self.exponent, other.exponent)?,
+            conf: math::checked_add(
+                math::checked_mul(self.conf, other.price)?,
+                math::checked_mul(other.conf, self.price)?,
+            )?,
         })
     }
 
@@ -191,14 +676,18 @@ impl OraclePrice {
         }
         let delta = math::checked_sub(target_exponent, self.exponent)?;
         if delta > 0 {
+            let scale = math::checked_pow(10, delta as usize)?;
             Ok(OraclePrice {
-                price: math::checked_div(self.price, math::checked_pow(10, delta as usize)?)?,
+                price: math::checked_div(self.price, scale)?,
                 exponent: target_exponent,
+                conf: math::checked_div(self.conf, scale)?,
             })
         } else {
+            let scale = math::checked_pow(10, (-delta) as usize)?;
             Ok(OraclePrice {
-                price: math::checked_mul(self.price, math::checked_pow(10, (-delta) as usize)?)?,
+                price: math::checked_mul(self.price, scale)?,
                 exponent: target_exponent,
+                conf: math::checked_mul(self.conf, scale)?,
             })
         }
     }
@@ -241,6 +730,7 @@ impl OraclePrice {
             // price is i64 and > 0 per check above
             price: oracle_acc.price,
             exponent: oracle_acc.expo,
+            conf: oracle_acc.conf,
         })
     }
 
@@ -284,6 +774,225 @@ impl OraclePrice {
             // price is i64 and > 0 per check above
             price: pyth_price.price as u64,
             exponent: pyth_price.expo,
+            conf: pyth_price.conf,
+        })
+    }
+
+    fn get_pyth_pull_price(
+        price_update_info: &AccountInfo,
+        feed_id: &[u8; 32],
+        max_price_error: u64,
+        max_price_age_sec: u32,
+        current_time: i64,
+        use_ema: bool,
+    ) -> Result<OraclePrice> {
+        require!(
+            !Perpetuals::is_empty_account(price_update_info)?,
+            PerpetualsError::InvalidOracleAccount
+        );
+        let price_update = PriceUpdateV2::try_from_account(price_update_info)?;
+
+        // partially verified updates are signed by a subset of guardians only
+        require!(
+            price_update.verification_level == VerificationLevel::Full,
+            PerpetualsError::InsufficientOracleVerification
+        );
+        let message = &price_update.price_message;
+        require!(
+            message.feed_id == *feed_id,
+            PerpetualsError::OracleFeedMismatch
+        );
+
+        let (price, conf) = if use_ema {
+            (message.ema_price, message.ema_conf)
+        } else {
+            (message.price, message.conf)
+        };
+
+        let last_update_age_sec = math::checked_sub(current_time, message.publish_time)?;
+        if last_update_age_sec > max_price_age_sec as i64 {
+            msg!("Error: Pyth pull oracle price is stale");
+            return err!(PerpetualsError::StaleOraclePrice);
+        }
+
+        if price <= 0
+            || math::checked_div(
+                math::checked_mul(conf as u128, Perpetuals::BPS_POWER)?,
+                price as u128,
+            )? > max_price_error as u128
+        {
+            msg!("Error: Pyth pull oracle price is out of bounds");
+            return err!(PerpetualsError::InvalidOraclePrice);
+        }
+
+        Ok(OraclePrice {
+            // price is i64 and > 0 per check above
+            price: price as u64,
+            exponent: message.exponent,
+            conf,
+        })
+    }
+
+    /// Publishers don't sign an EMA, the last signed price is returned regardless of use_ema
+    fn get_signed_price(
+        signed_price_info: &AccountInfo,
+        max_price_error: u64,
+        max_price_age_sec: u32,
+        current_time: i64,
+    ) -> Result<OraclePrice> {
+        require!(
+            !Perpetuals::is_empty_account(signed_price_info)?,
+            PerpetualsError::InvalidOracleAccount
+        );
+
+        let oracle_acc = try_from!(Account<SignedOracle>, signed_price_info)?;
+
+        let last_update_age_sec = math::checked_sub(current_time, oracle_acc.publish_time)?;
+        if last_update_age_sec > max_price_age_sec as i64 {
+            msg!("Error: Signed oracle price is stale");
+            return err!(PerpetualsError::StaleOraclePrice);
+        }
+
+        if oracle_acc.price == 0
+            || math::checked_div(
+                math::checked_mul(oracle_acc.conf as u128, Perpetuals::BPS_POWER)?,
+                oracle_acc.price as u128,
+            )? > max_price_error as u128
+        {
+            msg!("Error: Signed oracle price is out of bounds");
+            return err!(PerpetualsError::InvalidOraclePrice);
+        }
+
+        Ok(OraclePrice {
+            price: oracle_acc.price,
+            exponent: oracle_acc.expo,
+            conf: oracle_acc.conf,
+        })
+    }
+
+    // source prices have passed their own confidence checks when aggregated,
+    // so the aggregate carries no confidence
+    fn get_aggregate_price(
+        aggregate_info: &AccountInfo,
+        max_price_age_sec: u32,
+        current_time: i64,
+        use_ema: bool,
+    ) -> Result<OraclePrice> {
+        require!(
+            !Perpetuals::is_empty_account(aggregate_info)?,
+            PerpetualsError::InvalidOracleAccount
+        );
+
+        let oracle_acc = try_from!(Account<OracleAggregate>, aggregate_info)?;
+
+        let last_update_age_sec = math::checked_sub(current_time, oracle_acc.publish_time)?;
+        if last_update_age_sec > max_price_age_sec as i64 {
+            msg!("Error: Aggregate oracle price is stale");
+            return err!(PerpetualsError::StaleOraclePrice);
+        }
+
+        let price = if use_ema {
+            oracle_acc.ema_price
+        } else {
+            oracle_acc.price
+        };
+        if price == 0 {
+            msg!("Error: Aggregate oracle price is out of bounds");
+            return err!(PerpetualsError::InvalidOraclePrice);
+        }
+
+        Ok(OraclePrice::new(price, oracle_acc.expo))
+    }
+
+    // sources have passed their own checks when composed, the combined
+    // confidence is checked again as it grows with every source
+    fn get_composite_price(
+        composite_info: &AccountInfo,
+        max_price_error: u64,
+        max_price_age_sec: u32,
+        current_time: i64,
+        use_ema: bool,
+    ) -> Result<OraclePrice> {
+        require!(
+            !Perpetuals::is_empty_account(composite_info)?,
+            PerpetualsError::InvalidOracleAccount
+        );
+
+        let oracle_acc = try_from!(Account<OracleComposite>, composite_info)?;
+
+        let last_update_age_sec = math::checked_sub(current_time, oracle_acc.publish_time)?;
+        if last_update_age_sec > max_price_age_sec as i64 {
+            msg!("Error: Composite oracle price is stale");
+            return err!(PerpetualsError::StaleOraclePrice);
+        }
+
+        let (price, conf) = if use_ema {
+            (oracle_acc.ema_price, oracle_acc.ema_conf)
+        } else {
+            (oracle_acc.price, oracle_acc.conf)
+        };
+        if price == 0
+            || math::checked_div(
+                math::checked_mul(conf as u128, Perpetuals::BPS_POWER)?,
+                price as u128,
+            )? > max_price_error as u128
+        {
+            msg!("Error: Composite oracle price is out of bounds");
+            return err!(PerpetualsError::InvalidOraclePrice);
+        }
+
+        Ok(OraclePrice {
+            price,
+            exponent: oracle_acc.expo,
+            conf,
+        })
+    }
+
+    /// Switchboard feeds have no native EMA, the median of the latest oracle
+    /// submissions is returned regardless of use_ema. Custodies priced by
+    /// Switchboard should set pricing.use_ema to false.
+    fn get_switchboard_price(
+        feed_info: &AccountInfo,
+        max_price_error: u64,
+        max_price_age_sec: u32,
+        current_time: i64,
+    ) -> Result<OraclePrice> {
+        require!(
+            !Perpetuals::is_empty_account(feed_info)?,
+            PerpetualsError::InvalidOracleAccount
+        );
+        let feed = SwitchboardPullFeed::try_from_account(feed_info)?;
+
+        let last_update_age_sec = math::checked_sub(current_time, feed.last_update_timestamp)?;
+        if last_update_age_sec > max_price_age_sec as i64 {
+            msg!("Error: Switchboard oracle price is stale");
+            return err!(PerpetualsError::StaleOraclePrice);
+        }
+
+        // standard deviation of the submissions stands in for the confidence interval
+        if feed.value <= 0
+            || feed.std_dev < 0
+            || math::checked_div(
+                math::checked_mul(feed.std_dev as u128, Perpetuals::BPS_POWER)?,
+                feed.value as u128,
+            )? > max_price_error as u128
+        {
+            msg!("Error: Switchboard oracle price is out of bounds");
+            return err!(PerpetualsError::InvalidOraclePrice);
+        }
+
+        // rescale from SWITCHBOARD_DECIMALS to ORACLE_EXPONENT_SCALE so the mantissa fits u64
+        let scale = math::checked_pow(
+            10u128,
+            (SwitchboardPullFeed::SWITCHBOARD_DECIMALS as i32 + ORACLE_EXPONENT_SCALE) as usize,
+        )?;
+        let price = math::checked_div(feed.value as u128, scale)?;
+        let conf = math::checked_div(feed.std_dev as u128, scale)?;
+
+        Ok(OraclePrice {
+            price: math::checked_as_u64(price)?,
+            exponent: ORACLE_EXPONENT_SCALE,
+            conf: math::checked_as_u64(conf)?,
         })
     }
 }
@@ -303,17 +1012,344 @@ mod test {
 
     #[test]
     fn test_scale_to_exponent() {
-        let price = OraclePrice::new(12300, -3);
+        let price = OraclePrice::new_with_conf(12300, -3, 150);
         let scaled = price.scale_to_exponent(-6).unwrap();
         assert_eq!(12300000, scaled.price);
         assert_eq!(-6, scaled.exponent);
+        assert_eq!(150000, scaled.conf);
 
         let scaled = price.scale_to_exponent(-1).unwrap();
         assert_eq!(123, scaled.price);
         assert_eq!(-1, scaled.exponent);
+        assert_eq!(1, scaled.conf);
 
         let scaled = price.scale_to_exponent(1).unwrap();
         assert_eq!(1, scaled.price);
         assert_eq!(1, scaled.exponent);
     }
+
+    fn get_ed25519_instruction(publisher: &Pubkey, message: &SignedPriceMessage) -> Instruction {
+        let public_key_offset: u16 = 16;
+        let signature_offset: u16 = public_key_offset + 32;
+        let message_offset: u16 = signature_offset + 64;
+
+        let mut data = vec![1u8, 0];
+        for value in [
+            signature_offset,
+            u16::MAX,
+            public_key_offset,
+            u16::MAX,
+            message_offset,
+            SignedPriceMessage::LEN as u16,
+            u16::MAX,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(publisher.as_ref());
+        data.extend_from_slice(&[0u8; 64]);
+        message.serialize(&mut data).unwrap();
+
+        Instruction {
+            program_id: ed25519_program::ID,
+            accounts: vec![],
+            data,
+        }
+    }
+
+    #[test]
+    fn test_signed_price_message() {
+        let publisher = Pubkey::new_unique();
+        let message = SignedPriceMessage {
+            feed_id: [7; 32],
+            price: 15_012_345,
+            expo: -5,
+            conf: 1_000,
+            publish_time: 100,
+        };
+        let mut instruction = get_ed25519_instruction(&publisher, &message);
+
+        assert_eq!(
+            message,
+            SignedPriceMessage::try_from_ed25519_instruction(&instruction, &publisher).unwrap()
+        );
+        assert_eq!(
+            Err(PerpetualsError::InvalidSignedPrice.into()),
+            SignedPriceMessage::try_from_ed25519_instruction(&instruction, &Pubkey::new_unique())
+        );
+
+        // public key read from another instruction
+        instruction.data[8..10].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(
+            Err(PerpetualsError::InvalidSignedPrice.into()),
+            SignedPriceMessage::try_from_ed25519_instruction(&instruction, &publisher)
+        );
+
+        let mut instruction = get_ed25519_instruction(&publisher, &message);
+        instruction.program_id = Pubkey::new_unique();
+        assert_eq!(
+            Err(PerpetualsError::InvalidSignedPrice.into()),
+            SignedPriceMessage::try_from_ed25519_instruction(&instruction, &publisher)
+        );
+
+        assert!(message.check_publish_time(99, 100).is_ok());
+        assert!(message.check_publish_time(99, 95).is_ok());
+        assert_eq!(
+            Err(PerpetualsError::SignedPriceNotNewer.into()),
+            message.check_publish_time(100, 100)
+        );
+        assert_eq!(
+            Err(PerpetualsError::SignedPriceInFuture.into()),
+            message.check_publish_time(99, 94)
+        );
+    }
+
+    #[test]
+    fn test_oracle_aggregate_combine() {
+        let prices = [
+            OraclePrice::new(101_000, -3),
+            OraclePrice::new(99_000_000_000, -9),
+            OraclePrice::new(1_000, -1),
+        ];
+        assert_eq!(
+            OraclePrice::new(100_000_000_000, -9),
+            OracleAggregate::combine(&prices, OracleAggregation::Median).unwrap()
+        );
+        assert_eq!(
+            OraclePrice::new(101_000_000_000, -9),
+            OracleAggregate::combine(&prices, OracleAggregation::PrimaryWithFallback).unwrap()
+        );
+        assert_eq!(
+            OraclePrice::new(100_000_000_000, -9),
+            OracleAggregate::combine(&prices[..2], OracleAggregation::Median).unwrap()
+        );
+        assert!(OracleAggregate::combine(&[], OracleAggregation::Median).is_err());
+    }
+
+    #[test]
+    fn test_oracle_aggregate_get_deviation() {
+        let prices = [
+            OraclePrice::new(101_000, -3),
+            OraclePrice::new(99_000_000_000, -9),
+            OraclePrice::new(1_000, -1),
+        ];
+        let aggregate_price = OracleAggregate::combine(&prices, OracleAggregation::Median).unwrap();
+        assert_eq!(
+            200,
+            OracleAggregate::get_deviation(&prices, &aggregate_price).unwrap()
+        );
+        assert_eq!(
+            0,
+            OracleAggregate::get_deviation(&prices[..1], &aggregate_price).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_oracle_composite_compose() {
+        // EUR/USD 1.08 +/- 5 bps, USD/JPY 150 +/- 10 bps
+        let prices = [
+            OraclePrice::new_with_conf(108_000, -5, 54),
+            OraclePrice::new_with_conf(150_000, -3, 150),
+        ];
+        assert_eq!(
+            OraclePrice::new_with_conf(162_000_000_000, -9, 243_000_000),
+            OracleComposite::compose(&prices, OracleComposition::Multiply).unwrap()
+        );
+
+        // NKY/USD 270 +/- 10 bps, JPY/USD 0.00675 +/- 10 bps
+        let prices = [
+            OraclePrice::new_with_conf(27_000, -2, 27),
+            OraclePrice::new_with_conf(675_000, -8, 675),
+        ];
+        assert_eq!(
+            OraclePrice::new_with_conf(40_000_000_000_000, -9, 80_000_000_000),
+            OracleComposite::compose(&prices, OracleComposition::Divide).unwrap()
+        );
+
+        assert!(OracleComposite::compose(&[], OracleComposition::Multiply).is_err());
+    }
+
+    fn get_switchboard_feed_data(
+        value: i128,
+        std_dev: i128,
+        last_update_timestamp: i64,
+    ) -> Vec<u8> {
+        let mut data = vec![0u8; SwitchboardPullFeed::LEN];
+        data[..8].copy_from_slice(&SwitchboardPullFeed::DISCRIMINATOR);
+        data[8 + 2208..8 + 2216].copy_from_slice(&last_update_timestamp.to_le_bytes());
+        data[8 + 2256..8 + 2272].copy_from_slice(&value.to_le_bytes());
+        data[8 + 2272..8 + 2288].copy_from_slice(&std_dev.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_get_switchboard_price() {
+        let key = Pubkey::new_unique();
+        let mut lamports = 1_000_000;
+        // 123.45 +/- 1.2345
+        let mut data =
+            get_switchboard_feed_data(123_450_000_000_000_000_000, 1_234_500_000_000_000_000, 100);
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &SWITCHBOARD_PROGRAM_IDS[0],
+            false,
+            0,
+        );
+        let mut oracle = OracleParams {
+            oracle_account: key,
+            oracle_type: OracleType::Switchboard,
+            max_price_error: 100,
+            max_price_age_sec: 10,
+            feed_id: [0; 32],
+            ..Default::default()
+        };
+
+        let price = OraclePrice::new_with_conf(123_450_000_000, -9, 1_234_500_000);
+        assert_eq!(
+            price,
+            OraclePrice::new_from_oracle(&oracle, &account, 105, false).unwrap()
+        );
+        assert_eq!(
+            price,
+            OraclePrice::new_from_oracle(&oracle, &account, 105, true).unwrap()
+        );
+        assert_eq!(
+            100,
+            OraclePrice::get_publish_time(&oracle, &account).unwrap()
+        );
+
+        assert_eq!(
+            Err(PerpetualsError::StaleOraclePrice.into()),
+            OraclePrice::new_from_oracle(&oracle, &account, 111, false)
+        );
+
+        // standard deviation is 100 bps of the price
+        oracle.max_price_error = 99;
+        assert_eq!(
+            Err(PerpetualsError::InvalidOraclePrice.into()),
+            OraclePrice::new_from_oracle(&oracle, &account, 105, false)
+        );
+    }
+
+    fn get_price_update_data(verification_level: VerificationLevel) -> Vec<u8> {
+        let price_update = PriceUpdateV2 {
+            write_authority: Pubkey::default(),
+            verification_level,
+            price_message: PriceFeedMessage {
+                feed_id: [1; 32],
+                price: 12_300_000,
+                conf: 1_000,
+                exponent: -5,
+                publish_time: 100,
+                prev_publish_time: 99,
+                ema_price: 12_000_000,
+                ema_conf: 2_000,
+            },
+            posted_slot: 1,
+        };
+        let mut data = PriceUpdateV2::DISCRIMINATOR.to_vec();
+        price_update.serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_get_pyth_pull_price() {
+        let key = Pubkey::new_unique();
+        let mut lamports = 1_000_000;
+        let mut data = get_price_update_data(VerificationLevel::Full);
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &PYTH_RECEIVER_PROGRAM_ID,
+            false,
+            0,
+        );
+        let mut oracle = OracleParams {
+            oracle_account: key,
+            oracle_type: OracleType::PythPull,
+            max_price_error: 100,
+            max_price_age_sec: 10,
+            feed_id: [1; 32],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            OraclePrice::new_with_conf(12_300_000, -5, 1_000),
+            OraclePrice::new_from_oracle(&oracle, &account, 105, false).unwrap()
+        );
+        assert_eq!(
+            OraclePrice::new_with_conf(12_000_000, -5, 2_000),
+            OraclePrice::new_from_oracle(&oracle, &account, 105, true).unwrap()
+        );
+        assert_eq!(
+            100,
+            OraclePrice::get_publish_time(&oracle, &account).unwrap()
+        );
+
+        assert_eq!(
+            Err(PerpetualsError::StaleOraclePrice.into()),
+            OraclePrice::new_from_oracle(&oracle, &account, 111, false)
+        );
+
+        oracle.feed_id = [2; 32];
+        assert_eq!(
+            Err(PerpetualsError::OracleFeedMismatch.into()),
+            OraclePrice::new_from_oracle(&oracle, &account, 105, false)
+        );
+    }
+
+    #[test]
+    fn test_get_pyth_pull_price_verification() {
+        let key = Pubkey::new_unique();
+        let mut lamports = 1_000_000;
+        let mut data = get_price_update_data(VerificationLevel::Partial { num_signatures: 5 });
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &PYTH_RECEIVER_PROGRAM_ID,
+            false,
+            0,
+        );
+        let oracle = OracleParams {
+            oracle_account: key,
+            oracle_type: OracleType::PythPull,
+            max_price_error: 100,
+            max_price_age_sec: 10,
+            feed_id: [1; 32],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Err(PerpetualsError::InsufficientOracleVerification.into()),
+            OraclePrice::new_from_oracle(&oracle, &account, 105, false)
+        );
+
+        // accounts not owned by the receiver program are rejected
+        let mut lamports = 1_000_000;
+        let mut data = get_price_update_data(VerificationLevel::Full);
+        let owner = Pubkey::new_unique();
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+        assert_eq!(
+            Err(PerpetualsError::InvalidOracleAccount.into()),
+            OraclePrice::new_from_oracle(&oracle, &account, 105, false)
+        );
+    }
 }
@@ -0,0 +1,79 @@
+use {crate::state::position::Side, anchor_lang::prelude::*};
+
+#[account]
+#[derive(Default, Debug)]
+pub struct Order {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub custody: Pubkey,
+    pub collateral_custody: Pubkey,
+    pub order_id: u64,
+    // seed of the position opened on execution
+    pub position_id: u64,
+
+    pub side: Side,
+    // entry price that triggers the order, has implied PRICE_DECIMALS decimals
+    pub trigger_price: u64,
+    // amount of collateral tokens in escrow, entry and execution fees are taken from it
+    pub collateral: u64,
+    // position size in custody tokens
+    pub size: u64,
+    pub create_time: i64,
+    // zero if the order never expires
+    pub expiration_time: i64,
+
+    pub bump: u8,
+    pub token_account_bump: u8,
+}
+
+impl Order {
+    pub const LEN: usize = 8 + std::mem::size_of::<Order>();
+
+    /// Long orders fill at or below the trigger price, short orders at or above it
+    pub fn is_triggered(&self, entry_price: u64) -> bool {
+        match self.side {
+            Side::Long => entry_price <= self.trigger_price,
+            Side::Short => entry_price >= self.trigger_price,
+            Side::None => false,
+        }
+    }
+
+    pub fn is_expired(&self, curtime: i64) -> bool {
+        self.expiration_time > 0 && curtime >= self.expiration_time
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_triggered() {
+        let mut order = Order {
+            side: Side::Long,
+            trigger_price: 100,
+            ..Order::default()
+        };
+        assert!(order.is_triggered(99));
+        assert!(order.is_triggered(100));
+        assert!(!order.is_triggered(101));
+
+        order.side = Side::Short;
+        assert!(!order.is_triggered(99));
+        assert!(order.is_triggered(100));
+        assert!(order.is_triggered(101));
+
+        order.side = Side::None;
+        assert!(!order.is_triggered(100));
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let mut order = Order::default();
+        assert!(!order.is_expired(i64::MAX));
+
+        order.expiration_time = 100;
+        assert!(!order.is_expired(99));
+        assert!(order.is_expired(100));
+    }
+}
@@ -1,6 +1,14 @@
 use {
-    anchor_lang::{prelude::*, solana_program::clock},
+    crate::state::position::Side,
+    anchor_lang::{
+        prelude::*,
+        solana_program::{clock, program_memory::sol_memcpy},
+    },
     anchor_spl::token::{Burn, MintTo, Transfer},
+    std::{
+        cmp,
+        io::{self, Write},
+    },
 };
 
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
@@ -35,6 +43,24 @@ pub struct ProfitAndLoss {
     pub loss: u64,
 }
 
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct UserPosition {
+    pub position: Pubkey,
+    pub custody: Pubkey,
+    pub position_id: u64,
+    pub side: Side,
+    pub price: u64,
+    pub size_usd: u64,
+    pub collateral_usd: u64,
+}
+
+#[derive(Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct LiquidationSummary {
+    pub liquidated: Vec<Pubkey>,
+    pub partially_liquidated: Vec<Pubkey>,
+    pub skipped: u64,
+}
+
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
 pub struct Permissions {
     pub allow_swap: bool,
@@ -57,6 +83,22 @@ pub struct Perpetuals {
     pub perpetuals_bump: u8,
     // time of inception, also used as current wall clock time for testing
     pub inception_time: i64,
+
+    // fields below are appended to the launch layout, see upgrade_perpetuals
+
+    // keepers must fill position requests within this time, after that owners can cancel them
+    pub position_request_timeout_sec: u32,
+}
+
+#[account]
+#[derive(Default, Debug)]
+pub struct DeprecatedPerpetuals {
+    pub permissions: Permissions,
+    pub pools: Vec<Pubkey>,
+
+    pub transfer_authority_bump: u8,
+    pub perpetuals_bump: u8,
+    pub inception_time: i64,
 }
 
 impl anchor_lang::Id for Perpetuals {
@@ -70,15 +112,30 @@ impl Perpetuals {
     pub const BPS_DECIMALS: u8 = 4;
     pub const BPS_POWER: u128 = 10i64.pow(Self::BPS_DECIMALS as u32) as u128;
     pub const PRICE_DECIMALS: u8 = 6;
+    // USD amounts of pools with a non-USD quote currency are in that currency
     pub const USD_DECIMALS: u8 = 6;
     pub const LP_DECIMALS: u8 = Self::USD_DECIMALS;
     pub const RATE_DECIMALS: u8 = 9;
     pub const RATE_POWER: u128 = 10i64.pow(Self::RATE_DECIMALS as u32) as u128;
+    // keeps Vec<UserPosition> within the 1024 bytes of return data
+    pub const MAX_USER_POSITIONS: usize = 10;
+    // max (position, receiving account) pairs liquidated in one transaction
+    pub const MAX_LIQUIDATION_BATCH: usize = 10;
+    // used until the admin sets a position request timeout
+    pub const DEFAULT_POSITION_REQUEST_TIMEOUT_SEC: u32 = 60;
 
     pub fn validate(&self) -> bool {
         true
     }
 
+    pub fn get_position_request_timeout(&self) -> i64 {
+        if self.position_request_timeout_sec > 0 {
+            self.position_request_timeout_sec as i64
+        } else {
+            Self::DEFAULT_POSITION_REQUEST_TIMEOUT_SEC as i64
+        }
+    }
+
     #[cfg(feature = "test")]
     pub fn get_time(&self) -> Result<i64> {
         Ok(self.inception_time)
@@ -256,4 +313,72 @@ impl Perpetuals {
             .realloc(new_len, zero_init)
             .map_err(|_| ProgramError::InvalidRealloc.into())
     }
+
+    /// Resizes an account created with a previous layout and writes the upgraded data
+    pub fn upgrade_account<'a, T: AccountSerialize>(
+        funding_account: AccountInfo<'a>,
+        target_account: AccountInfo<'a>,
+        system_program: AccountInfo<'a>,
+        new_len: usize,
+        upgraded_data: &T,
+    ) -> Result<()> {
+        Perpetuals::realloc(
+            funding_account,
+            target_account.clone(),
+            system_program,
+            new_len,
+            true,
+        )?;
+
+        let mut data = target_account.try_borrow_mut_data()?;
+        let mut writer = BpfWriter::new(&mut data[..]);
+        upgraded_data.try_serialize(&mut writer)
+    }
+}
+
+impl DeprecatedPerpetuals {
+    pub const LEN: usize = 8 + std::mem::size_of::<DeprecatedPerpetuals>();
+}
+
+#[derive(Debug, Default)]
+pub struct BpfWriter<T> {
+    inner: T,
+    pos: u64,
+}
+
+impl<T> BpfWriter<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner, pos: 0 }
+    }
+}
+
+impl Write for BpfWriter<&mut [u8]> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos >= self.inner.len() as u64 {
+            return Ok(0);
+        }
+
+        let amt = cmp::min(
+            self.inner.len().saturating_sub(self.pos as usize),
+            buf.len(),
+        );
+        sol_memcpy(&mut self.inner[(self.pos as usize)..], buf, amt);
+        self.pos += amt as u64;
+        Ok(amt)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.write(buf)? == buf.len() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write whole buffer",
+            ))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
@@ -3,8 +3,8 @@ use {
         error::PerpetualsError,
         math,
         state::{
-            custody::{Custody, FeesMode},
-            oracle::OraclePrice,
+            custody::{BadDebtStats, Custody, FeesMode},
+            oracle::{OracleComposition, OraclePrice, OracleSource, OracleType},
             perpetuals::Perpetuals,
             position::{Position, Side},
         },
@@ -20,6 +20,7 @@ pub enum AumCalcMode {
     Max,
     Last,
     EMA,
+    Twap,
 }
 
 #[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
@@ -29,6 +30,17 @@ pub struct TokenRatios {
     pub max: u64,
 }
 
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub struct QuoteCurrency {
+    // ISO 4217 code such as b"JPY", all zeros for USD
+    pub code: [u8; 3],
+    // USD price in the quote currency (e.g. USD/JPY), the last source of every custody oracle
+    pub fx_oracle: OracleSource,
+    // stablecoin pegged to the quote currency, the only stable custody of non-USD pools
+    // that backs shorts, default if there is none
+    pub mint: Pubkey,
+}
+
 #[account]
 #[derive(Default, Debug)]
 pub struct Pool {
@@ -40,6 +52,29 @@ pub struct Pool {
     pub bump: u8,
     pub lp_token_bump: u8,
     pub inception_time: i64,
+
+    // fields below are appended to the launch layout, see upgrade_pool
+
+    // USD denominated amounts of the pool and its custodies are in the quote currency
+    pub quote: QuoteCurrency,
+    // bad debt across all custodies of the pool
+    pub bad_debt_stats: BadDebtStats,
+    // positions must score at least this to be auto-deleveraged, see get_adl_score,
+    // auto-deleveraging is disabled while zero
+    pub adl_min_score: u64,
+}
+
+#[account]
+#[derive(Default, Debug)]
+pub struct DeprecatedPool {
+    pub name: String,
+    pub custodies: Vec<Pubkey>,
+    pub ratios: Vec<TokenRatios>,
+    pub aum_usd: u128,
+
+    pub bump: u8,
+    pub lp_token_bump: u8,
+    pub inception_time: i64,
 }
 
 impl TokenRatios {
@@ -52,6 +87,47 @@ impl TokenRatios {
     }
 }
 
+impl QuoteCurrency {
+    pub fn is_usd(&self) -> bool {
+        self.code == [0; 3] || self.code == *b"USD"
+    }
+
+    pub fn validate(&self) -> bool {
+        if self.is_usd() {
+            self.fx_oracle.oracle_type == OracleType::None && self.mint == Pubkey::default()
+        } else {
+            self.code.iter().all(u8::is_ascii_uppercase)
+                && self.fx_oracle.oracle_type != OracleType::None
+                && !self.fx_oracle.is_derived()
+                && self.fx_oracle.oracle_account != Pubkey::default()
+        }
+    }
+
+    /// Custodies of non-USD pools are priced in the quote currency by composite
+    /// oracles that multiply the USD price by the FX rate. Stable swaps and stats
+    /// assume a peg to the quote currency, so the quote mint is the only stable custody.
+    pub fn validate_custody(&self, custody: &Custody) -> bool {
+        if self.is_usd() {
+            return true;
+        }
+        if custody.is_stable && (self.mint == Pubkey::default() || custody.mint != self.mint) {
+            return false;
+        }
+        let oracle = &custody.oracle;
+        let num_sources = oracle
+            .sources
+            .iter()
+            .take_while(|source| source.oracle_type != OracleType::None)
+            .count();
+        oracle.oracle_type == OracleType::Composite
+            && oracle.composition == OracleComposition::Multiply
+            && num_sources >= 2
+            && oracle.sources[num_sources - 1] == self.fx_oracle
+            // fallback prices would be in USD
+            && custody.degraded_mode.fallback_oracle.oracle_type == OracleType::None
+    }
+}
+
 /// Token Pool
 /// All returned prices are scaled to PRICE_DECIMALS.
 /// All returned amounts are scaled to corresponding custody decimals.
@@ -85,7 +161,10 @@ impl Pool {
             }
         }
 
-        !self.name.is_empty() && self.name.len() <= 64 && self.custodies.len() == self.ratios.len()
+        !self.name.is_empty()
+            && self.name.len() <= 64
+            && self.custodies.len() == self.ratios.len()
+            && self.quote.validate()
     }
 
     pub fn get_token_id(&self, custody: &Pubkey) -> Result<usize> {
@@ -101,16 +180,18 @@ impl Pool {
         token_ema_price: &OraclePrice,
         side: Side,
         custody: &Custody,
+        curtime: i64,
     ) -> Result<u64> {
         let price = self.get_price(
             token_price,
-            token_ema_price,
+            &custody.get_reference_price(token_ema_price, curtime)?,
             side,
             if side == Side::Long {
                 custody.pricing.trade_spread_long
             } else {
                 custody.pricing.trade_spread_short
             },
+            custody.pricing.conf_mult,
         )?;
         require_gt!(price.price, 0, PerpetualsError::MaxPriceSlippage);
 
@@ -119,48 +200,61 @@ impl Pool {
             .price)
     }
 
-    pub fn get_entry_fee(&self, size: u64, custody: &Custody) -> Result<u64> {
-        // entry_fee = custody.fees.open_position * utilization_fee * size
-        // where utilization_fee = 1 + custody.fees.utilization_mult * (new_utilization - optimal_utilization) / (1 - optimal_utilization);
-
-        let mut size_fee = Self::get_fee_amount(custody.fees.open_position, size)?;
-
-        let new_locked_amount = math::checked_as_u64(math::checked_div(
-            math::checked_mul(size as u128, custody.pricing.max_payoff_mult as u128)?,
+    /// Returns the amount of collateral tokens to lock for the max payoff of the position
+    pub fn get_locked_amount(
+        &self,
+        size_usd: u64,
+        custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_custody: &Custody,
+    ) -> Result<u64> {
+        let max_payoff_usd = math::checked_as_u64(math::checked_div(
+            math::checked_mul(size_usd as u128, custody.pricing.max_payoff_mult as u128)?,
             Perpetuals::BPS_POWER,
         )?)?;
 
-        let new_utilization = if custody.assets.owned > 0 {
-            // utilization = (assets_locked + new_locked_amount) / assets_owned
+        collateral_token_price.get_token_amount(max_payoff_usd, collateral_custody.decimals)
+    }
+
+    /// Size and locked amount are in collateral tokens, base_fee comes from the traded custody
+    pub fn get_entry_fee(
+        &self,
+        base_fee: u64,
+        size: u64,
+        locked_amount: u64,
+        collateral_custody: &Custody,
+    ) -> Result<u64> {
+        // entry_fee = custody.fees.open_position * utilization_fee * size
+        // where utilization_fee = 1 + collateral_custody.fees.utilization_mult * (new_utilization - optimal_utilization) / (1 - optimal_utilization);
+
+        let mut size_fee = Self::get_fee_amount(base_fee, size)?;
+
+        let new_utilization = if collateral_custody.assets.owned > 0 {
+            // utilization = (assets_locked + locked_amount) / assets_owned
             std::cmp::min(
                 Perpetuals::RATE_POWER,
                 math::checked_div(
                     math::checked_mul(
-                        math::checked_add(custody.assets.locked, new_locked_amount)? as u128,
+                        math::checked_add(collateral_custody.assets.locked, locked_amount)? as u128,
                         Perpetuals::RATE_POWER,
                     )?,
-                    custody.assets.owned as u128,
+                    collateral_custody.assets.owned as u128,
                 )?,
             )
         } else {
             Perpetuals::RATE_POWER
         };
 
-        if new_utilization > custody.borrow_rate.optimal_utilization as u128 {
+        let optimal_utilization = collateral_custody.borrow_rate.optimal_utilization as u128;
+        if new_utilization > optimal_utilization {
             let utilization_fee = math::checked_add(
                 Perpetuals::BPS_POWER,
                 math::checked_div(
                     math::checked_mul(
-                        custody.fees.utilization_mult as u128,
-                        math::checked_sub(
-                            new_utilization,
-                            custody.borrow_rate.optimal_utilization as u128,
-                        )?,
-                    )?,
-                    math::checked_sub(
-                        Perpetuals::RATE_POWER,
-                        custody.borrow_rate.optimal_utilization as u128,
+                        collateral_custody.fees.utilization_mult as u128,
+                        math::checked_sub(new_utilization, optimal_utilization)?,
                     )?,
+                    math::checked_sub(Perpetuals::RATE_POWER, optimal_utilization)?,
                 )?,
             )?;
             size_fee = math::checked_as_u64(math::checked_div(
@@ -178,10 +272,11 @@ impl Pool {
         token_ema_price: &OraclePrice,
         side: Side,
         custody: &Custody,
+        curtime: i64,
     ) -> Result<u64> {
         let price = self.get_price(
             token_price,
-            token_ema_price,
+            &custody.get_reference_price(token_ema_price, curtime)?,
             if side == Side::Long {
                 Side::Short
             } else {
@@ -192,6 +287,7 @@ impl Pool {
             } else {
                 custody.pricing.trade_spread_long
             },
+            custody.pricing.conf_mult,
         )?;
 
         Ok(price
@@ -210,6 +306,9 @@ impl Pool {
         token_price: &OraclePrice,
         token_ema_price: &OraclePrice,
         custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_token_ema_price: &OraclePrice,
+        collateral_custody: &Custody,
         curtime: i64,
         liquidation: bool,
     ) -> Result<(u64, u64, u64, u64)> {
@@ -218,6 +317,9 @@ impl Pool {
             token_price,
             token_ema_price,
             custody,
+            collateral_token_price,
+            collateral_token_ema_price,
+            collateral_custody,
             curtime,
             liquidation,
         )?;
@@ -230,12 +332,13 @@ impl Pool {
             0
         };
 
-        let max_price = if token_price > token_ema_price {
-            token_price
+        let max_collateral_price = if collateral_token_price > collateral_token_ema_price {
+            collateral_token_price
         } else {
-            token_ema_price
+            collateral_token_ema_price
         };
-        let close_amount = max_price.get_token_amount(available_amount_usd, custody.decimals)?;
+        let close_amount = max_collateral_price
+            .get_token_amount(available_amount_usd, collateral_custody.decimals)?;
         let max_amount = math::checked_add(position.locked_amount, position.collateral_amount)?;
 
         Ok((
@@ -246,6 +349,34 @@ impl Pool {
         ))
     }
 
+    /// Same as get_close_amount() but only for the `size_usd` share of the position
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_partial_close_amount(
+        &self,
+        position: &Position,
+        size_usd: u64,
+        token_price: &OraclePrice,
+        token_ema_price: &OraclePrice,
+        custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_token_ema_price: &OraclePrice,
+        collateral_custody: &Custody,
+        curtime: i64,
+        liquidation: bool,
+    ) -> Result<(u64, u64, u64, u64)> {
+        self.get_close_amount(
+            &position.get_partial(size_usd)?,
+            token_price,
+            token_ema_price,
+            custody,
+            collateral_token_price,
+            collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+            liquidation,
+        )
+    }
+
     pub fn get_swap_price(
         &self,
         token_in_price: &OraclePrice,
@@ -273,6 +404,7 @@ impl Pool {
             &pair_price,
             Side::Short,
             custody_in.pricing.swap_spread,
+            0,
         )
     }
 
@@ -382,7 +514,34 @@ impl Pool {
     }
 
     pub fn get_liquidation_fee(&self, size: u64, custody: &Custody) -> Result<u64> {
-        Self::get_fee_amount(custody.fees.close_position, size)
+        Self::get_fee_amount(custody.fees.liquidation, size)
+    }
+
+    /// Splits collected liquidation penalty into the liquidator reward and the
+    /// insurance fund share, the rest is kept as a regular fee
+    pub fn get_liquidation_penalty_split(
+        &self,
+        penalty_amount: u64,
+        custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_custody: &Custody,
+    ) -> Result<(u64, u64)> {
+        let mut reward = Self::get_fee_amount(custody.fees.liquidator_share, penalty_amount)?;
+        if custody.fees.max_liquidator_reward_usd > 0 {
+            let max_reward = collateral_token_price.get_token_amount(
+                custody.fees.max_liquidator_reward_usd,
+                collateral_custody.decimals,
+            )?;
+            reward = std::cmp::min(reward, max_reward);
+        }
+        let insurance =
+            Self::get_fee_amount(custody.fees.liquidation_insurance_share, penalty_amount)?;
+
+        // both shares are rounded up, so they may exceed the penalty by a token
+        let reward = std::cmp::min(reward, penalty_amount);
+        let insurance = std::cmp::min(insurance, math::checked_sub(penalty_amount, reward)?);
+
+        Ok((reward, insurance))
     }
 
     pub fn check_token_ratio(
@@ -417,10 +576,21 @@ impl Pool {
         position: &Position,
         token_price: &OraclePrice,
         custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_custody: &Custody,
         curtime: i64,
     ) -> Result<u64> {
-        let (profit_usd, loss_usd, _) =
-            self.get_pnl_usd(position, token_price, token_price, custody, curtime, false)?;
+        let (profit_usd, loss_usd, _) = self.get_pnl_usd(
+            position,
+            token_price,
+            token_price,
+            custody,
+            collateral_token_price,
+            collateral_token_price,
+            collateral_custody,
+            curtime,
+            false,
+        )?;
 
         let current_margin_usd = if profit_usd > 0 {
             math::checked_add(position.collateral_usd, profit_usd)?
@@ -446,10 +616,19 @@ impl Pool {
         position: &Position,
         token_price: &OraclePrice,
         custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_custody: &Custody,
         curtime: i64,
         initial: bool,
     ) -> Result<bool> {
-        let current_leverage = self.get_leverage(position, token_price, custody, curtime)?;
+        let current_leverage = self.get_leverage(
+            position,
+            token_price,
+            custody,
+            collateral_token_price,
+            collateral_custody,
+            curtime,
+        )?;
 
         Ok(current_leverage <= custody.pricing.max_leverage
             && (!initial
@@ -457,25 +636,154 @@ impl Pool {
                     && current_leverage <= custody.pricing.max_initial_leverage)))
     }
 
-    pub fn get_liquidation_price(
+    /// Returns the size to close for the position leverage to drop to the custody
+    /// liquidation target, zero if the position has to be liquidated in full
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_partial_liquidation_size(
+        &self,
+        position: &Position,
+        token_price: &OraclePrice,
+        token_ema_price: &OraclePrice,
+        custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_token_ema_price: &OraclePrice,
+        collateral_custody: &Custody,
+        curtime: i64,
+    ) -> Result<u64> {
+        let target_leverage = custody.pricing.liquidation_target_leverage as u128;
+        if target_leverage == 0 {
+            return Ok(0);
+        }
+
+        // margin at the exit price the position is settled at
+        let (profit_usd, loss_usd, _) = self.get_pnl_usd(
+            position,
+            token_price,
+            token_ema_price,
+            custody,
+            collateral_token_price,
+            collateral_token_ema_price,
+            collateral_custody,
+            curtime,
+            false,
+        )?;
+        let margin_usd = if profit_usd > 0 {
+            math::checked_add(position.collateral_usd, profit_usd)?
+        } else if loss_usd < position.collateral_usd {
+            math::checked_sub(position.collateral_usd, loss_usd)?
+        } else {
+            return Ok(0);
+        };
+
+        // the penalty on the closed size is paid from the margin:
+        // (size - closed) / (margin - closed * penalty) <= target
+        let penalty_leverage = math::checked_div(
+            math::checked_mul(target_leverage, custody.fees.liquidation as u128)?,
+            Perpetuals::BPS_POWER,
+        )?;
+        if penalty_leverage >= Perpetuals::BPS_POWER {
+            return Ok(0);
+        }
+        let size_scaled = math::checked_mul(position.size_usd as u128, Perpetuals::BPS_POWER)?;
+        let target_size_scaled = math::checked_mul(margin_usd as u128, target_leverage)?;
+        if size_scaled <= target_size_scaled {
+            return Ok(0);
+        }
+        let close_size_usd = math::checked_as_u64(math::checked_ceil_div(
+            math::checked_sub(size_scaled, target_size_scaled)?,
+            math::checked_sub(Perpetuals::BPS_POWER, penalty_leverage)?,
+        )?)?;
+
+        if close_size_usd >= position.size_usd {
+            Ok(0)
+        } else {
+            Ok(close_size_usd)
+        }
+    }
+
+    /// Ranks positions for auto-deleveraging: unrealized profit ratio multiplied
+    /// by effective leverage, with implied BPS_DECIMALS decimals. Positions that
+    /// are not in profit score zero.
+    pub fn get_adl_score(
         &self,
         position: &Position,
         token_price: &OraclePrice,
         custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_custody: &Custody,
         curtime: i64,
     ) -> Result<u64> {
-        // liq_price = pos_price +- (collateral + unreal_profit - unreal_loss - exit_fee - interest - size/max_leverage) * pos_price / size
+        if position.collateral_usd == 0 {
+            return Ok(0);
+        }
+
+        let (profit_usd, _, _) = self.get_pnl_usd(
+            position,
+            token_price,
+            token_price,
+            custody,
+            collateral_token_price,
+            collateral_token_price,
+            collateral_custody,
+            curtime,
+            false,
+        )?;
+        if profit_usd == 0 {
+            return Ok(0);
+        }
+
+        let leverage = self.get_leverage(
+            position,
+            token_price,
+            custody,
+            collateral_token_price,
+            collateral_custody,
+            curtime,
+        )?;
+        let profit_ratio = math::checked_div(
+            math::checked_mul(profit_usd as u128, Perpetuals::BPS_POWER)?,
+            position.collateral_usd as u128,
+        )?;
+
+        math::checked_as_u64(math::checked_div(
+            math::checked_mul(profit_ratio, leverage as u128)?,
+            Perpetuals::BPS_POWER,
+        )?)
+    }
+
+    /// Returns true if a position with the given score can be auto-deleveraged,
+    /// it must reach the pool watermark, which disables auto-deleveraging while zero
+    pub fn check_adl_score(&self, adl_score: u64) -> bool {
+        self.adl_min_score > 0 && adl_score >= self.adl_min_score
+    }
+
+    pub fn get_liquidation_price(
+        &self,
+        position: &Position,
+        custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_custody: &Custody,
+        curtime: i64,
+    ) -> Result<u64> {
+        // liq_price = pos_price +- (collateral + unreal_profit + funding_received - unreal_loss - exit_fee - interest - funding_paid - size/max_leverage) * pos_price / size
 
         if position.size_usd == 0 || position.price == 0 {
             return Ok(0);
         }
 
-        let size = token_price.get_token_amount(position.size_usd, custody.decimals)?;
+        let size = collateral_token_price
+            .get_token_amount(position.size_usd, collateral_custody.decimals)?;
         let exit_fee_tokens = self.get_exit_fee(size, custody)?;
-        let exit_fee_usd = token_price.get_asset_amount_usd(exit_fee_tokens, custody.decimals)?;
-        let interest_usd = custody.get_interest_amount_usd(position, curtime)?;
+        let exit_fee_usd = collateral_token_price
+            .get_asset_amount_usd(exit_fee_tokens, collateral_custody.decimals)?;
+        let interest_usd = collateral_custody.get_interest_amount_usd(position, curtime)?;
+        let (funding_paid_usd, funding_received_usd) =
+            custody.get_funding_amount_usd(position, curtime)?;
         let unrealized_loss_usd = math::checked_add(
-            math::checked_add(exit_fee_usd, interest_usd)?,
+            math::checked_add(
+                math::checked_add(exit_fee_usd, interest_usd)?,
+                funding_paid_usd,
+            )?,
             position.unrealized_loss_usd,
         )?;
 
@@ -485,8 +793,10 @@ impl Pool {
         )?)?;
         let max_loss_usd = math::checked_add(max_loss_usd, unrealized_loss_usd)?;
 
-        let margin_usd =
-            math::checked_add(position.collateral_usd, position.unrealized_profit_usd)?;
+        let margin_usd = math::checked_add(
+            math::checked_add(position.collateral_usd, position.unrealized_profit_usd)?,
+            funding_received_usd,
+        )?;
 
         let max_price_diff = if max_loss_usd >= margin_usd {
             math::checked_sub(max_loss_usd, margin_usd)?
@@ -537,6 +847,9 @@ impl Pool {
         token_price: &OraclePrice,
         token_ema_price: &OraclePrice,
         custody: &Custody,
+        collateral_token_price: &OraclePrice,
+        collateral_token_ema_price: &OraclePrice,
+        collateral_custody: &Custody,
         curtime: i64,
         liquidation: bool,
     ) -> Result<(u64, u64, u64)> {
@@ -544,16 +857,23 @@ impl Pool {
             return Ok((0, 0, 0));
         }
 
-        let min_price = if token_price < token_ema_price {
-            token_price
+        let min_collateral_price = if collateral_token_price < collateral_token_ema_price {
+            collateral_token_price
         } else {
-            token_ema_price
+            collateral_token_ema_price
         };
 
-        let exit_price =
-            self.get_exit_price(token_price, token_ema_price, position.side, custody)?;
+        let exit_price = self.get_exit_price(
+            token_price,
+            token_ema_price,
+            position.side,
+            custody,
+            curtime,
+        )?;
 
-        let size = token_ema_price.get_token_amount(position.size_usd, custody.decimals)?;
+        // fees are paid in collateral tokens
+        let size = collateral_token_ema_price
+            .get_token_amount(position.size_usd, collateral_custody.decimals)?;
 
         let exit_fee = if liquidation {
             self.get_liquidation_fee(size, custody)?
@@ -561,12 +881,20 @@ impl Pool {
             self.get_exit_fee(size, custody)?
         };
 
-        let exit_fee_usd = token_ema_price.get_asset_amount_usd(exit_fee, custody.decimals)?;
-        let interest_usd = custody.get_interest_amount_usd(position, curtime)?;
+        let exit_fee_usd = collateral_token_ema_price
+            .get_asset_amount_usd(exit_fee, collateral_custody.decimals)?;
+        let interest_usd = collateral_custody.get_interest_amount_usd(position, curtime)?;
+        let (funding_paid_usd, funding_received_usd) =
+            custody.get_funding_amount_usd(position, curtime)?;
         let unrealized_loss_usd = math::checked_add(
-            math::checked_add(exit_fee_usd, interest_usd)?,
+            math::checked_add(
+                math::checked_add(exit_fee_usd, interest_usd)?,
+                funding_paid_usd,
+            )?,
             position.unrealized_loss_usd,
         )?;
+        let unrealized_profit_usd =
+            math::checked_add(position.unrealized_profit_usd, funding_received_usd)?;
 
         let (price_diff_profit, price_diff_loss) = if position.side == Side::Long {
             if exit_price > position.price {
@@ -593,12 +921,12 @@ impl Pool {
             )?;
 
             let potential_profit_usd =
-                math::checked_add(potential_profit_usd, position.unrealized_profit_usd)?;
+                math::checked_add(potential_profit_usd, unrealized_profit_usd)?;
 
             if potential_profit_usd >= unrealized_loss_usd {
                 let cur_profit_usd = math::checked_sub(potential_profit_usd, unrealized_loss_usd)?;
-                let max_profit_usd =
-                    min_price.get_asset_amount_usd(position.locked_amount, custody.decimals)?;
+                let max_profit_usd = min_collateral_price
+                    .get_asset_amount_usd(position.locked_amount, collateral_custody.decimals)?;
                 Ok((
                     std::cmp::min(max_profit_usd, cur_profit_usd),
                     0u64,
@@ -619,17 +947,16 @@ impl Pool {
 
             let potential_loss_usd = math::checked_add(potential_loss_usd, unrealized_loss_usd)?;
 
-            if potential_loss_usd >= position.unrealized_profit_usd {
+            if potential_loss_usd >= unrealized_profit_usd {
                 Ok((
                     0u64,
-                    math::checked_sub(potential_loss_usd, position.unrealized_profit_usd)?,
+                    math::checked_sub(potential_loss_usd, unrealized_profit_usd)?,
                     exit_fee,
                 ))
             } else {
-                let cur_profit_usd =
-                    math::checked_sub(position.unrealized_profit_usd, potential_loss_usd)?;
-                let max_profit_usd =
-                    min_price.get_asset_amount_usd(position.locked_amount, custody.decimals)?;
+                let cur_profit_usd = math::checked_sub(unrealized_profit_usd, potential_loss_usd)?;
+                let max_profit_usd = min_collateral_price
+                    .get_asset_amount_usd(position.locked_amount, collateral_custody.decimals)?;
                 Ok((
                     std::cmp::min(max_profit_usd, cur_profit_usd),
                     0u64,
@@ -639,6 +966,29 @@ impl Pool {
         }
     }
 
+    /// Fails if any pool custody is in close-only mode, takes the custody
+    /// accounts in the same order as get_assets_under_management_usd
+    pub fn check_custodies_not_close_only(
+        &self,
+        accounts: &[AccountInfo],
+        curtime: i64,
+    ) -> Result<()> {
+        for (idx, &custody) in self.custodies.iter().enumerate() {
+            if idx >= accounts.len() {
+                return Err(ProgramError::NotEnoughAccountKeys.into());
+            }
+
+            require_keys_eq!(accounts[idx].key(), custody);
+            let custody = try_from!(Account<Custody>, &accounts[idx])?;
+            require!(
+                !custody.is_close_only(curtime),
+                PerpetualsError::CustodyCloseOnly
+            );
+        }
+
+        Ok(())
+    }
+
     pub fn get_assets_under_management_usd(
         &self,
         aum_calc_mode: AumCalcMode,
@@ -657,19 +1007,15 @@ impl Pool {
             require_keys_eq!(accounts[oracle_idx].key(), custody.oracle.oracle_account);
 
             let token_price = OraclePrice::new_from_oracle(
-                custody.oracle.oracle_type,
+                &custody.oracle,
                 &accounts[oracle_idx],
-                custody.oracle.max_price_error,
-                custody.oracle.max_price_age_sec,
                 curtime,
                 false,
             )?;
 
             let token_ema_price = OraclePrice::new_from_oracle(
-                custody.oracle.oracle_type,
+                &custody.oracle,
                 &accounts[oracle_idx],
-                custody.oracle.max_price_error,
-                custody.oracle.max_price_age_sec,
                 curtime,
                 custody.pricing.use_ema,
             )?;
@@ -677,6 +1023,7 @@ impl Pool {
             let aum_token_price = match aum_calc_mode {
                 AumCalcMode::Last => token_price,
                 AumCalcMode::EMA => token_ema_price,
+                AumCalcMode::Twap => custody.get_twap_price(curtime)?,
                 AumCalcMode::Min => {
                     if token_price < token_ema_price {
                         token_price
@@ -699,17 +1046,62 @@ impl Pool {
             pool_amount_usd = math::checked_add(pool_amount_usd, token_amount_usd as u128)?;
 
             if custody.pricing.use_unrealized_pnl_in_aum {
-                // compute aggregate unrealized pnl
+                // compute aggregate unrealized pnl, funding nets out between
+                // the sides so it is left out until settled
+                let min_price = if token_price < token_ema_price {
+                    token_price
+                } else {
+                    token_ema_price
+                };
+
+                // longs of synthetic custodies are backed by stablecoin custodies
+                // and are re-expressed the same way as shorts below
+                let mut long_position = custody.get_collective_position(Side::Long)?;
+                long_position.cumulative_funding_snapshot =
+                    custody.get_cumulative_funding(Side::Long, curtime)?;
+                if custody.is_synthetic && long_position.size_usd > 0 {
+                    long_position.locked_amount = self.get_locked_amount(
+                        long_position.size_usd,
+                        &custody,
+                        &min_price,
+                        &custody,
+                    )?;
+                    long_position.cumulative_interest_snapshot =
+                        custody.get_cumulative_interest(curtime)?;
+                }
                 let (long_profit, long_loss, _) = self.get_pnl_usd(
-                    &custody.get_collective_position(Side::Long)?,
+                    &long_position,
+                    &token_price,
+                    &token_ema_price,
+                    &custody,
                     &token_price,
                     &token_ema_price,
                     &custody,
                     curtime,
                     false,
                 )?;
+
+                // shorts are backed by stablecoin custodies, so the payoff cap is
+                // re-expressed in custody tokens and only interest already settled
+                // into the stats is accounted for
+                let mut short_position = custody.get_collective_position(Side::Short)?;
+                if short_position.size_usd > 0 {
+                    short_position.locked_amount = self.get_locked_amount(
+                        short_position.size_usd,
+                        &custody,
+                        &min_price,
+                        &custody,
+                    )?;
+                    short_position.cumulative_interest_snapshot =
+                        custody.get_cumulative_interest(curtime)?;
+                    short_position.cumulative_funding_snapshot =
+                        custody.get_cumulative_funding(Side::Short, curtime)?;
+                }
                 let (short_profit, short_loss, _) = self.get_pnl_usd(
-                    &custody.get_collective_position(Side::Short)?,
+                    &short_position,
+                    &token_price,
+                    &token_ema_price,
+                    &custody,
                     &token_price,
                     &token_ema_price,
                     &custody,
@@ -804,12 +1196,15 @@ impl Pool {
         Ok(std::cmp::min(ratio, Perpetuals::BPS_POWER as u64))
     }
 
+    /// Widens the max (long) or min (short) of spot and EMA prices by the spread
+    /// and by conf_mult times the confidence of the spot price
     fn get_price(
         &self,
         token_price: &OraclePrice,
         token_ema_price: &OraclePrice,
         side: Side,
         spread: u64,
+        conf_mult: u64,
     ) -> Result<OraclePrice> {
         if side == Side::Long {
             let max_price = if token_price > token_ema_price {
@@ -818,18 +1213,31 @@ impl Pool {
                 token_ema_price
             };
 
+            let conf = token_price.scale_to_exponent(max_price.exponent)?.conf;
+            let conf_spread = math::checked_decimal_ceil_mul(
+                conf,
+                max_price.exponent,
+                conf_mult,
+                -(Perpetuals::BPS_DECIMALS as i32),
+                max_price.exponent,
+            )?;
+
             Ok(OraclePrice {
                 price: math::checked_add(
-                    max_price.price,
-                    math::checked_decimal_ceil_mul(
+                    math::checked_add(
                         max_price.price,
-                        max_price.exponent,
-                        spread,
-                        -(Perpetuals::BPS_DECIMALS as i32),
-                        max_price.exponent,
+                        math::checked_decimal_ceil_mul(
+                            max_price.price,
+                            max_price.exponent,
+                            spread,
+                            -(Perpetuals::BPS_DECIMALS as i32),
+                            max_price.exponent,
+                        )?,
                     )?,
+                    conf_spread,
                 )?,
                 exponent: max_price.exponent,
+                conf: max_price.conf,
             })
         } else {
             let min_price = if token_price < token_ema_price {
@@ -838,12 +1246,22 @@ impl Pool {
                 token_ema_price
             };
 
-            let spread = math::checked_decimal_mul(
-                min_price.price,
-                min_price.exponent,
-                spread,
-                -(Perpetuals::BPS_DECIMALS as i32),
-                min_price.exponent,
+            let conf = token_price.scale_to_exponent(min_price.exponent)?.conf;
+            let spread = math::checked_add(
+                math::checked_decimal_mul(
+                    min_price.price,
+                    min_price.exponent,
+                    spread,
+                    -(Perpetuals::BPS_DECIMALS as i32),
+                    min_price.exponent,
+                )?,
+                math::checked_decimal_mul(
+                    conf,
+                    min_price.exponent,
+                    conf_mult,
+                    -(Perpetuals::BPS_DECIMALS as i32),
+                    min_price.exponent,
+                )?,
             )?;
 
             let price = if spread < min_price.price {
@@ -855,6 +1273,7 @@ impl Pool {
             Ok(OraclePrice {
                 price,
                 exponent: min_price.exponent,
+                conf: min_price.conf,
             })
         }
     }
@@ -949,14 +1368,22 @@ impl Pool {
     }
 }
 
+impl DeprecatedPool {
+    pub const LEN: usize = 8 + std::mem::size_of::<DeprecatedPool>();
+}
+
 #[cfg(test)]
 mod test {
     use {
         super::*,
         crate::state::{
-            custody::{BorrowRateParams, Fees, OracleParams, PricingParams},
+            custody::{
+                BorrowRateParams, CircuitBreakerParams, Fees, FundingRateParams, OracleParams,
+                PricingParams,
+            },
             oracle::OracleType,
             perpetuals::Permissions,
+            trade::{Trade, TradePrices},
         },
     };
 
@@ -972,6 +1399,8 @@ mod test {
             oracle_type: OracleType::Test,
             max_price_error: 100,
             max_price_age_sec: 1,
+            feed_id: [0; 32],
+            ..Default::default()
         };
 
         let pricing = PricingParams {
@@ -983,10 +1412,12 @@ mod test {
             min_initial_leverage: 10000,
             max_initial_leverage: 100000,
             max_leverage: 100000,
+            liquidation_target_leverage: 0,
             max_payoff_mult: 10000,
             max_utilization: 0,
             max_position_locked_usd: 0,
             max_total_locked_usd: 0,
+            conf_mult: 0,
         };
 
         let permissions = Permissions {
@@ -1013,7 +1444,12 @@ mod test {
             open_position: 100,
             close_position: 0,
             liquidation: 50,
+            liquidator_share: 5000,
+            liquidation_insurance_share: 2000,
+            fee_insurance_share: 2000,
+            order_execution: 10,
             protocol_share: 25,
+            max_liquidator_reward_usd: 0,
         };
 
         let custody = Custody {
@@ -1037,14 +1473,8 @@ mod test {
             ..Position::default()
         };
 
-        let token_price = OraclePrice {
-            price: 123000,
-            exponent: -3,
-        };
-        let token_ema_price = OraclePrice {
-            price: 122000,
-            exponent: -3,
-        };
+        let token_price = OraclePrice::new(123000, -3);
+        let token_ema_price = OraclePrice::new(122000, -3);
 
         (
             Pool {
@@ -1124,29 +1554,50 @@ mod test {
         let (pool, custody, _position, token_price, token_ema_price) = get_fixture();
 
         assert_eq!(
-            OraclePrice {
-                price: 124230,
-                exponent: -3
-            },
+            OraclePrice::new(124230, -3),
             pool.get_price(
                 &token_price,
                 &token_ema_price,
                 Side::Long,
                 custody.pricing.trade_spread_long,
+                custody.pricing.conf_mult,
             )
             .unwrap()
         );
 
         assert_eq!(
-            OraclePrice {
-                price: 120780,
-                exponent: -3
-            },
+            OraclePrice::new(120780, -3),
+            pool.get_price(
+                &token_price,
+                &token_ema_price,
+                Side::Short,
+                custody.pricing.trade_spread_short,
+                custody.pricing.conf_mult,
+            )
+            .unwrap()
+        );
+
+        // prices are widened by a multiple of the spot price confidence
+        let token_price = OraclePrice::new_with_conf(123000, -3, 500);
+        assert_eq!(
+            OraclePrice::new_with_conf(125230, -3, 500),
+            pool.get_price(
+                &token_price,
+                &token_ema_price,
+                Side::Long,
+                custody.pricing.trade_spread_long,
+                20000,
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            OraclePrice::new(119780, -3),
             pool.get_price(
                 &token_price,
                 &token_ema_price,
                 Side::Short,
                 custody.pricing.trade_spread_short,
+                20000,
             )
             .unwrap()
         );
@@ -1160,48 +1611,116 @@ mod test {
         custody.assets.owned = 200000;
         custody.borrow_rate.optimal_utilization = 500000000;
 
-        assert_eq!(0, pool.get_entry_fee(0, &custody).unwrap());
+        assert_eq!(
+            0,
+            pool.get_entry_fee(custody.fees.open_position, 0, 0, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(1000, pool.get_entry_fee(100000, &custody).unwrap());
+        assert_eq!(
+            1000,
+            pool.get_entry_fee(custody.fees.open_position, 100000, 100000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(3000, pool.get_entry_fee(150000, &custody).unwrap());
+        assert_eq!(
+            3000,
+            pool.get_entry_fee(custody.fees.open_position, 150000, 150000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(6000, pool.get_entry_fee(200000, &custody).unwrap());
+        assert_eq!(
+            6000,
+            pool.get_entry_fee(custody.fees.open_position, 200000, 200000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(9000, pool.get_entry_fee(300000, &custody).unwrap());
+        assert_eq!(
+            9000,
+            pool.get_entry_fee(custody.fees.open_position, 300000, 300000, &custody)
+                .unwrap()
+        );
 
         custody.fees.utilization_mult = 10000;
         custody.assets.owned = 200000;
         custody.borrow_rate.optimal_utilization = 500000000;
 
-        assert_eq!(1000, pool.get_entry_fee(100000, &custody).unwrap());
+        assert_eq!(
+            1000,
+            pool.get_entry_fee(custody.fees.open_position, 100000, 100000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(2250, pool.get_entry_fee(150000, &custody).unwrap());
+        assert_eq!(
+            2250,
+            pool.get_entry_fee(custody.fees.open_position, 150000, 150000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(4000, pool.get_entry_fee(200000, &custody).unwrap());
+        assert_eq!(
+            4000,
+            pool.get_entry_fee(custody.fees.open_position, 200000, 200000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(6000, pool.get_entry_fee(300000, &custody).unwrap());
+        assert_eq!(
+            6000,
+            pool.get_entry_fee(custody.fees.open_position, 300000, 300000, &custody)
+                .unwrap()
+        );
 
         custody.fees.utilization_mult = 5000;
 
-        assert_eq!(1000, pool.get_entry_fee(100000, &custody).unwrap());
+        assert_eq!(
+            1000,
+            pool.get_entry_fee(custody.fees.open_position, 100000, 100000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(1875, pool.get_entry_fee(150000, &custody).unwrap());
+        assert_eq!(
+            1875,
+            pool.get_entry_fee(custody.fees.open_position, 150000, 150000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(3000, pool.get_entry_fee(200000, &custody).unwrap());
+        assert_eq!(
+            3000,
+            pool.get_entry_fee(custody.fees.open_position, 200000, 200000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(4500, pool.get_entry_fee(300000, &custody).unwrap());
+        assert_eq!(
+            4500,
+            pool.get_entry_fee(custody.fees.open_position, 300000, 300000, &custody)
+                .unwrap()
+        );
 
         custody.fees.utilization_mult = 20000;
         custody.borrow_rate.optimal_utilization = 1000000000;
 
-        assert_eq!(1000, pool.get_entry_fee(100000, &custody).unwrap());
+        assert_eq!(
+            1000,
+            pool.get_entry_fee(custody.fees.open_position, 100000, 100000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(1500, pool.get_entry_fee(150000, &custody).unwrap());
+        assert_eq!(
+            1500,
+            pool.get_entry_fee(custody.fees.open_position, 150000, 150000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(2000, pool.get_entry_fee(200000, &custody).unwrap());
+        assert_eq!(
+            2000,
+            pool.get_entry_fee(custody.fees.open_position, 200000, 200000, &custody)
+                .unwrap()
+        );
 
-        assert_eq!(3000, pool.get_entry_fee(300000, &custody).unwrap());
+        assert_eq!(
+            3000,
+            pool.get_entry_fee(custody.fees.open_position, 300000, 300000, &custody)
+                .unwrap()
+        );
     }
 
     #[test]
@@ -1322,6 +1841,9 @@ mod test {
                 &token_price,
                 &token_ema_price,
                 &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
                 0,
                 false
             )
@@ -1336,6 +1858,9 @@ mod test {
                 &token_price,
                 &token_ema_price,
                 &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
                 0,
                 false
             )
@@ -1350,6 +1875,9 @@ mod test {
                 &token_price,
                 &token_ema_price,
                 &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
                 0,
                 false
             )
@@ -1363,50 +1891,300 @@ mod test {
 
         assert_eq!(
             scale_f64(4.8426, Perpetuals::BPS_DECIMALS),
-            pool.get_leverage(&position, &token_ema_price, &custody, 0)
-                .unwrap()
+            pool.get_leverage(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
         );
 
         position.price = scale(110, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(3.3557, Perpetuals::BPS_DECIMALS),
-            pool.get_leverage(&position, &token_ema_price, &custody, 0)
-                .unwrap()
+            pool.get_leverage(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
         );
 
         position.price = scale(130, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(7.7473, Perpetuals::BPS_DECIMALS),
-            pool.get_leverage(&position, &token_ema_price, &custody, 0)
-                .unwrap()
+            pool.get_leverage(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
         );
 
         position.price = scale(80, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(1.4089, Perpetuals::BPS_DECIMALS),
-            pool.get_leverage(&position, &token_ema_price, &custody, 0)
-                .unwrap()
+            pool.get_leverage(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
         );
 
         position.price = scale(0, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(5.0, Perpetuals::BPS_DECIMALS),
-            pool.get_leverage(&position, &token_ema_price, &custody, 0)
-                .unwrap()
+            pool.get_leverage(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
         );
 
         position.price = scale(150, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             1923076,
-            pool.get_leverage(&position, &token_ema_price, &custody, 0)
-                .unwrap()
+            pool.get_leverage(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
         );
 
         position.price = scale(180, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             u64::MAX,
-            pool.get_leverage(&position, &token_ema_price, &custody, 0)
-                .unwrap()
+            pool.get_leverage(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_partial_liquidation_size() {
+        let (pool, mut custody, mut position, token_price, token_ema_price) = get_fixture();
+
+        position.price = scale(130, Perpetuals::PRICE_DECIMALS);
+        assert_eq!(
+            0,
+            pool.get_partial_liquidation_size(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
+        );
+
+        custody.pricing.liquidation_target_leverage = 50000;
+        let close_size_usd = pool
+            .get_partial_liquidation_size(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                0,
+            )
+            .unwrap();
+        assert_eq!(close_size_usd, 363_708_088);
+
+        // remaining size over margin at the settlement exit price, reduced by the penalty,
+        // is at the target
+        let (_, loss_usd, _) = pool
+            .get_pnl_usd(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                0,
+                false,
+            )
+            .unwrap();
+        let margin_usd =
+            position.collateral_usd - loss_usd - close_size_usd * custody.fees.liquidation / 10000;
+        assert!((position.size_usd - close_size_usd) * 10000 / margin_usd <= 50000);
+
+        // with the spot price below the EMA the position is settled at the spot price
+        let ema_close_size_usd = pool
+            .get_partial_liquidation_size(
+                &position,
+                &token_price,
+                &token_price,
+                &custody,
+                &token_price,
+                &token_price,
+                &custody,
+                0,
+            )
+            .unwrap();
+        assert!(
+            pool.get_partial_liquidation_size(
+                &position,
+                &token_ema_price,
+                &token_price,
+                &custody,
+                &token_ema_price,
+                &token_price,
+                &custody,
+                0,
+            )
+            .unwrap()
+                > ema_close_size_usd
+        );
+
+        // margin is exhausted
+        position.price = scale(180, Perpetuals::PRICE_DECIMALS);
+        assert_eq!(
+            0,
+            pool.get_partial_liquidation_size(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_liquidation_circuit_breaker() {
+        let (pool, mut custody, position, token_price, _token_ema_price) = get_fixture();
+        custody.circuit_breaker = CircuitBreakerParams {
+            max_price_move: 500,
+            window_sec: 60,
+            cooldown_sec: 300,
+        };
+        custody.record_price(&token_price, 1000).unwrap();
+
+        // the position is over max leverage at a price that trips the breaker
+        let jump_price = OraclePrice::new(100000, -3);
+        assert!(!pool
+            .check_leverage(
+                &position,
+                &jump_price,
+                &custody,
+                &jump_price,
+                &custody,
+                1010,
+                false
+            )
+            .unwrap());
+        custody.record_price(&jump_price, 1010).unwrap();
+        assert!(custody.is_circuit_breaker_tripped(1010));
+
+        // but is valued at the last accepted price until the breaker clears
+        let (price, ema_price) = custody
+            .get_accepted_prices(&jump_price, &jump_price, 1010)
+            .unwrap();
+        assert_eq!((price, ema_price), (token_price, token_price));
+        assert!(pool
+            .check_leverage(&position, &ema_price, &custody, &ema_price, &custody, 1010, false)
+            .unwrap());
+
+        // positions over max leverage at the last accepted price are still liquidated
+        let mut underwater_position = position.clone();
+        underwater_position.price = scale(140, Perpetuals::PRICE_DECIMALS);
+        assert!(!pool
+            .check_leverage(
+                &underwater_position,
+                &ema_price,
+                &custody,
+                &ema_price,
+                &custody,
+                1010,
+                false
+            )
+            .unwrap());
+
+        assert_eq!(
+            custody.get_accepted_prices(&jump_price, &jump_price, 1310),
+            Ok((jump_price, jump_price))
+        );
+
+        custody.last_accepted_price = OraclePrice::default();
+        assert_eq!(
+            custody.get_accepted_prices(&jump_price, &jump_price, 1010),
+            Err(PerpetualsError::CircuitBreakerTripped.into())
+        );
+    }
+
+    #[test]
+    fn test_get_adl_score() {
+        let (mut pool, custody, mut position, _token_price, token_ema_price) = get_fixture();
+
+        position.price = scale(110, Perpetuals::PRICE_DECIMALS);
+        assert_eq!(
+            16442,
+            pool.get_adl_score(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
+        );
+
+        // auto-deleveraging is disabled until the watermark is set
+        assert!(!pool.check_adl_score(16442));
+        pool.adl_min_score = 10000;
+        assert!(pool.check_adl_score(16442));
+        assert!(!pool.check_adl_score(9999));
+
+        position.price = scale(130, Perpetuals::PRICE_DECIMALS);
+        assert_eq!(
+            0,
+            pool.get_adl_score(
+                &position,
+                &token_ema_price,
+                &custody,
+                &token_ema_price,
+                &custody,
+                0
+            )
+            .unwrap()
         );
     }
 
@@ -1416,42 +2194,42 @@ mod test {
 
         assert_eq!(
             scale_f64(108.0, Perpetuals::PRICE_DECIMALS),
-            pool.get_liquidation_price(&position, &token_price, &custody, 0)
+            pool.get_liquidation_price(&position, &custody, &token_price, &custody, 0)
                 .unwrap()
         );
 
         position.price = scale(110, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(99.0, Perpetuals::PRICE_DECIMALS),
-            pool.get_liquidation_price(&position, &token_price, &custody, 0)
+            pool.get_liquidation_price(&position, &custody, &token_price, &custody, 0)
                 .unwrap()
         );
 
         position.price = scale(130, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(117.0, Perpetuals::PRICE_DECIMALS),
-            pool.get_liquidation_price(&position, &token_price, &custody, 0)
+            pool.get_liquidation_price(&position, &custody, &token_price, &custody, 0)
                 .unwrap()
         );
 
         position.price = scale(80, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(72.0, Perpetuals::PRICE_DECIMALS),
-            pool.get_liquidation_price(&position, &token_price, &custody, 0)
+            pool.get_liquidation_price(&position, &custody, &token_price, &custody, 0)
                 .unwrap()
         );
 
         position.price = scale(0, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(0.0, Perpetuals::PRICE_DECIMALS),
-            pool.get_liquidation_price(&position, &token_price, &custody, 0)
+            pool.get_liquidation_price(&position, &custody, &token_price, &custody, 0)
                 .unwrap()
         );
 
         position.price = scale(160, Perpetuals::PRICE_DECIMALS);
         assert_eq!(
             scale_f64(144.0, Perpetuals::PRICE_DECIMALS),
-            pool.get_liquidation_price(&position, &token_price, &custody, 0)
+            pool.get_liquidation_price(&position, &custody, &token_price, &custody, 0)
                 .unwrap()
         );
     }
@@ -1472,6 +2250,31 @@ mod test {
                 &token_price,
                 &token_ema_price,
                 &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                0,
+                false
+            )
+            .unwrap()
+        );
+
+        assert_eq!(
+            (
+                scale_f64(0.83943, custody.decimals),
+                0,
+                scale_f64(3.25, Perpetuals::USD_DECIMALS),
+                0
+            ),
+            pool.get_partial_close_amount(
+                &position,
+                scale(500, Perpetuals::USD_DECIMALS),
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
                 0,
                 false
             )
@@ -1479,6 +2282,90 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_stable_collateral() {
+        let (pool, custody, mut position, token_price, token_ema_price) = get_fixture();
+
+        let stable_custody = Custody {
+            decimals: 6,
+            is_stable: true,
+            fees: custody.fees,
+            ..Custody::default()
+        };
+        let stable_price = OraclePrice::new(1000, -3);
+
+        position.side = Side::Short;
+        position.locked_amount = scale(1000, stable_custody.decimals);
+        position.collateral_amount = scale(200, stable_custody.decimals);
+
+        assert_eq!(
+            (
+                scale_f64(164.75, stable_custody.decimals),
+                0,
+                0,
+                scale_f64(35.25, Perpetuals::USD_DECIMALS)
+            ),
+            pool.get_close_amount(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &stable_price,
+                &stable_price,
+                &stable_custody,
+                0,
+                false
+            )
+            .unwrap()
+        );
+
+        position.price = scale(130, Perpetuals::PRICE_DECIMALS);
+        assert_eq!(
+            (scale_f64(44.384615, Perpetuals::USD_DECIMALS), 0, 0),
+            pool.get_pnl_usd(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &stable_price,
+                &stable_price,
+                &stable_custody,
+                0,
+                false
+            )
+            .unwrap()
+        );
+
+        // payoff is capped by the stablecoins locked
+        position.locked_amount = scale(40, stable_custody.decimals);
+        assert_eq!(
+            (scale(40, Perpetuals::USD_DECIMALS), 0, 0),
+            pool.get_pnl_usd(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &stable_price,
+                &stable_price,
+                &stable_custody,
+                0,
+                false
+            )
+            .unwrap()
+        );
+
+        assert_eq!(
+            scale(1000, stable_custody.decimals),
+            pool.get_locked_amount(
+                scale(1000, Perpetuals::USD_DECIMALS),
+                &custody,
+                &stable_price,
+                &stable_custody
+            )
+            .unwrap()
+        );
+    }
+
     #[test]
     fn test_get_interest_amount_usd() {
         let (_pool, mut custody, mut position, _token_price, _token_ema_price) = get_fixture();
@@ -1507,4 +2394,290 @@ mod test {
         let interest = custody.get_interest_amount_usd(&position, 7200).unwrap();
         assert_eq!(interest, scale_f64(0.07, Perpetuals::USD_DECIMALS));
     }
+
+    #[test]
+    fn test_get_funding_amount_usd() {
+        let (pool, mut custody, mut position, token_price, token_ema_price) = get_fixture();
+
+        custody.funding_rate = FundingRateParams { max_rate: 1000000 };
+        custody.trade_stats.oi_long_usd = scale(3000, Perpetuals::USD_DECIMALS);
+        custody.trade_stats.oi_short_usd = scale(1000, Perpetuals::USD_DECIMALS);
+
+        // longs are the majority and pay half of the max rate,
+        // shorts receive it scaled by the open interest ratio
+        custody.update_funding_rate(3600).unwrap();
+        assert_eq!(custody.funding_rate_state.long_rate, 500000);
+        assert_eq!(custody.funding_rate_state.short_rate, -1500000);
+        position.cumulative_funding_snapshot =
+            custody.get_cumulative_funding(Side::Long, 3600).unwrap();
+
+        let funding = custody.get_funding_amount_usd(&position, 7200).unwrap();
+        assert_eq!(funding, (scale_f64(0.5, Perpetuals::USD_DECIMALS), 0));
+
+        position.side = Side::Short;
+        position.cumulative_funding_snapshot =
+            custody.get_cumulative_funding(Side::Short, 3600).unwrap();
+        let funding = custody.get_funding_amount_usd(&position, 7200).unwrap();
+        assert_eq!(funding, (0, scale_f64(1.5, Perpetuals::USD_DECIMALS)));
+
+        // funding received is part of the short's profit
+        let (_, loss_before, _) = pool
+            .get_pnl_usd(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                3600,
+                false,
+            )
+            .unwrap();
+        let (_, loss_after, _) = pool
+            .get_pnl_usd(
+                &position,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                &token_price,
+                &token_ema_price,
+                &custody,
+                7200,
+                false,
+            )
+            .unwrap();
+        assert_eq!(
+            loss_before - loss_after,
+            scale_f64(1.5, Perpetuals::USD_DECIMALS)
+        );
+
+        // shorts become the majority, rates flip after accruing the previous ones
+        custody.trade_stats.oi_short_usd = scale(5000, Perpetuals::USD_DECIMALS);
+        custody.update_funding_rate(7200).unwrap();
+        assert_eq!(custody.funding_rate_state.short_rate, 250000);
+        assert_eq!(custody.funding_rate_state.long_rate, -416666);
+        assert_eq!(custody.funding_rate_state.cumulative_long_funding, 500000);
+        assert_eq!(
+            custody.funding_rate_state.cumulative_short_funding,
+            -1500000
+        );
+
+        position.cumulative_funding_snapshot =
+            custody.get_cumulative_funding(Side::Short, 7200).unwrap();
+        let funding = custody.get_funding_amount_usd(&position, 10800).unwrap();
+        assert_eq!(funding, (scale_f64(0.25, Perpetuals::USD_DECIMALS), 0));
+
+        // no counterparty, no funding
+        custody.trade_stats.oi_long_usd = 0;
+        custody.update_funding_rate(10800).unwrap();
+        assert_eq!(custody.funding_rate_state.long_rate, 0);
+        assert_eq!(custody.funding_rate_state.short_rate, 0);
+    }
+
+    #[test]
+    fn test_funding_is_zero_sum() {
+        let (_pool, mut custody, position, _token_price, _token_ema_price) = get_fixture();
+        custody.funding_rate = FundingRateParams { max_rate: 1000000 };
+
+        for (oi_long, oi_short, rounding) in [(3000, 1000, 0), (2000, 6000, 0), (3000, 5000, 2)] {
+            custody.trade_stats.oi_long_usd = scale(oi_long, Perpetuals::USD_DECIMALS);
+            custody.trade_stats.oi_short_usd = scale(oi_short, Perpetuals::USD_DECIMALS);
+            custody.update_funding_rate(0).unwrap();
+
+            let long_position = Position {
+                side: Side::Long,
+                size_usd: custody.trade_stats.oi_long_usd,
+                cumulative_funding_snapshot: custody.get_cumulative_funding(Side::Long, 0).unwrap(),
+                ..position
+            };
+            let short_position = Position {
+                side: Side::Short,
+                size_usd: custody.trade_stats.oi_short_usd,
+                cumulative_funding_snapshot: custody
+                    .get_cumulative_funding(Side::Short, 0)
+                    .unwrap(),
+                ..position
+            };
+            let (long_paid, long_received) = custody
+                .get_funding_amount_usd(&long_position, 3600)
+                .unwrap();
+            let (short_paid, short_received) = custody
+                .get_funding_amount_usd(&short_position, 3600)
+                .unwrap();
+
+            // what the majority pays is what the minority receives, up to rounding
+            // in favor of the pool
+            let paid = long_paid + short_paid;
+            let received = long_received + short_received;
+            assert!(paid > 0);
+            assert_eq!(paid - received, rounding);
+        }
+    }
+
+    #[test]
+    fn test_get_liquidation_penalty_split() {
+        let (pool, mut custody, _position, token_price, _token_ema_price) = get_fixture();
+
+        assert_eq!(
+            (500, 200),
+            pool.get_liquidation_penalty_split(1000, &custody, &token_price, &custody)
+                .unwrap()
+        );
+        assert_eq!(
+            (0, 0),
+            pool.get_liquidation_penalty_split(0, &custody, &token_price, &custody)
+                .unwrap()
+        );
+
+        // reward is capped in USD, the insurance share is not affected
+        custody.fees.max_liquidator_reward_usd = scale(1, Perpetuals::USD_DECIMALS);
+        let max_reward = token_price
+            .get_token_amount(scale(1, Perpetuals::USD_DECIMALS), custody.decimals)
+            .unwrap();
+        let penalty = max_reward * 10;
+        assert_eq!(
+            (max_reward, penalty / 5),
+            pool.get_liquidation_penalty_split(penalty, &custody, &token_price, &custody)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pool_layout() {
+        let deprecated_pool = DeprecatedPool {
+            name: "test".to_string(),
+            custodies: vec![Pubkey::new_unique()],
+            ratios: vec![TokenRatios {
+                target: 10000,
+                min: 0,
+                max: 10000,
+            }],
+            aum_usd: 1000,
+            bump: 1,
+            lp_token_bump: 2,
+            inception_time: 3,
+        };
+        let pool = Pool {
+            name: deprecated_pool.name.clone(),
+            custodies: deprecated_pool.custodies.clone(),
+            ratios: deprecated_pool.ratios.clone(),
+            aum_usd: deprecated_pool.aum_usd,
+            bump: deprecated_pool.bump,
+            lp_token_bump: deprecated_pool.lp_token_bump,
+            inception_time: deprecated_pool.inception_time,
+            ..Default::default()
+        };
+
+        // new fields are appended, so launch data stays readable at the same offsets
+        let mut deprecated_data = Vec::new();
+        deprecated_pool.serialize(&mut deprecated_data).unwrap();
+        let mut data = Vec::new();
+        pool.serialize(&mut data).unwrap();
+        assert_eq!(data[..deprecated_data.len()], deprecated_data[..]);
+    }
+
+    #[test]
+    fn test_quote_currency() {
+        let (pool, mut custody, _position, _token_price, _token_ema_price) = get_fixture();
+        assert!(pool.quote.is_usd());
+        assert!(pool.quote.validate());
+        assert!(pool.quote.validate_custody(&custody));
+
+        let fx_oracle = OracleSource {
+            oracle_account: Pubkey::new_unique(),
+            oracle_type: OracleType::PythPull,
+            feed_id: [1; 32],
+        };
+        let quote = QuoteCurrency {
+            code: *b"JPY",
+            fx_oracle,
+            mint: Pubkey::new_unique(),
+        };
+        assert!(quote.validate());
+        assert!(!QuoteCurrency {
+            code: *b"JPY",
+            ..Default::default()
+        }
+        .validate());
+
+        // custody oracles must convert USD prices with the pool FX rate
+        assert!(!quote.validate_custody(&custody));
+        custody.oracle.oracle_type = OracleType::Composite;
+        custody.oracle.sources[0] = OracleSource {
+            oracle_account: Pubkey::new_unique(),
+            oracle_type: OracleType::PythPull,
+            feed_id: [2; 32],
+        };
+        custody.oracle.sources[1] = fx_oracle;
+        assert!(quote.validate_custody(&custody));
+
+        // the only stable custody is pegged to the quote currency
+        custody.is_stable = true;
+        assert!(!quote.validate_custody(&custody));
+        custody.mint = quote.mint;
+        assert!(quote.validate_custody(&custody));
+        custody.is_stable = false;
+
+        custody.oracle.composition = OracleComposition::Divide;
+        assert!(!quote.validate_custody(&custody));
+    }
+
+    #[test]
+    fn test_open_short_in_quote_currency() {
+        let (mut pool, mut custody, _position, token_price, token_ema_price) = get_fixture();
+        let fx_oracle = OracleSource {
+            oracle_account: Pubkey::new_unique(),
+            oracle_type: OracleType::PythPull,
+            feed_id: [1; 32],
+        };
+        pool.quote = QuoteCurrency {
+            code: *b"JPY",
+            fx_oracle,
+            mint: Pubkey::new_unique(),
+        };
+        custody.oracle.oracle_type = OracleType::Composite;
+        custody.oracle.sources[0] = OracleSource {
+            oracle_account: Pubkey::new_unique(),
+            oracle_type: OracleType::PythPull,
+            feed_id: [2; 32],
+        };
+        custody.oracle.sources[1] = fx_oracle;
+
+        // shorts are backed by the stablecoin pegged to the quote currency
+        let mut stable_custody = Custody {
+            mint: pool.quote.mint,
+            is_stable: true,
+            ..custody.clone()
+        };
+        stable_custody.oracle.sources[0].feed_id = [3; 32];
+        stable_custody.assets.owned = scale(10_000, 5);
+        assert!(pool.quote.validate_custody(&custody));
+        assert!(pool.quote.validate_custody(&stable_custody));
+
+        let stable_price = OraclePrice::new(1000, -3);
+        let mut trade = Trade {
+            pool: &mut pool,
+            custody: &mut custody,
+            collateral_custody: &mut stable_custody,
+            same_custody: false,
+            prices: TradePrices {
+                token_price,
+                token_ema_price,
+                collateral_token_price: stable_price,
+                collateral_token_ema_price: stable_price,
+            },
+            curtime: 0,
+        };
+        let mut position = Position::default();
+        trade
+            .open_position(&mut position, Side::Short, 1, scale(1, 5), scale(30, 5))
+            .unwrap();
+
+        assert_eq!(position.side, Side::Short);
+        assert_eq!(position.collateral_amount, scale(30, 5));
+        assert_eq!(position.collateral_usd, scale(30, Perpetuals::USD_DECIMALS));
+        assert_eq!(stable_custody.assets.locked, position.locked_amount);
+        assert_eq!(custody.short_positions.open_positions, 1);
+    }
 }
@@ -35,6 +35,10 @@ pub struct Position {
   pub owner: Pubkey,
   pub pool: Pubkey,
   pub custody: Pubkey,
+  // holds the collateral and locked funds, same as custody for longs
+  pub collateral_custody: Pubkey,
+  // user-chosen index, allows several positions per owner, custody and side
+  pub position_id: u64,
 
   pub open_time: i64,
   pub update_time: i64,
@@ -44,9 +48,19 @@ pub struct Position {
   pub collateral_usd: u64,
   pub unrealized_profit_usd: u64,
   pub unrealized_loss_usd: u64,
+  // cumulative interest of the collateral custody
   pub cumulative_interest_snapshot: u128,
+  // cumulative funding of the position custody
+  pub cumulative_funding_snapshot: i128,
   pub locked_amount: u64,
   pub collateral_amount: u64,
+  // stop loss and take profit prices, zero if not set
+  pub stop_loss_price: u64,
+  pub take_profit_price: u64,
+  // size closed when a trigger is hit, zero closes the whole position
+  pub trigger_size_usd: u64,
+  // max deviation of the exit price from the trigger price, in BPS
+  pub trigger_slippage: u64,
 
   pub bump: u8,
 }
@@ -60,4 +74,272 @@ impl Position {
           self.collateral_usd as u128,
       )?)
   }
+
+  /// Returns the share of the position that corresponds to `size_usd`
+  pub fn get_partial(&self, size_usd: u64) -> Result<Position> {
+      if size_usd >= self.size_usd {
+          return Ok(self.clone());
+      }
+      let share = |value: u64| -> Result<u64> {
+          math::checked_as_u64(math::checked_div(
+              math::checked_mul(value as u128, size_usd as u128)?,
+              self.size_usd as u128,
+          )?)
+      };
+      Ok(Position {
+          size_usd,
+          collateral_usd: share(self.collateral_usd)?,
+          unrealized_profit_usd: share(self.unrealized_profit_usd)?,
+          unrealized_loss_usd: share(self.unrealized_loss_usd)?,
+          locked_amount: share(self.locked_amount)?,
+          collateral_amount: share(self.collateral_amount)?,
+          ..self.clone()
+      })
+  }
+
+  /// Removes the given share of the position, as returned by get_partial()
+  pub fn remove_partial(&mut self, partial: &Position) -> Result<()> {
+      self.size_usd = math::checked_sub(self.size_usd, partial.size_usd)?;
+      self.collateral_usd = math::checked_sub(self.collateral_usd, partial.collateral_usd)?;
+      self.unrealized_profit_usd =
+          math::checked_sub(self.unrealized_profit_usd, partial.unrealized_profit_usd)?;
+      self.unrealized_loss_usd =
+          math::checked_sub(self.unrealized_loss_usd, partial.unrealized_loss_usd)?;
+      self.locked_amount = math::checked_sub(self.locked_amount, partial.locked_amount)?;
+      self.collateral_amount =
+          math::checked_sub(self.collateral_amount, partial.collateral_amount)?;
+      Ok(())
+  }
+
+  /// Returns the trigger price crossed by `exit_price`, stop loss first
+  pub fn get_trigger_price(&self, exit_price: u64) -> Option<u64> {
+      let stop_loss = self.stop_loss_price > 0
+          && match self.side {
+              Side::Long => exit_price <= self.stop_loss_price,
+              Side::Short => exit_price >= self.stop_loss_price,
+              Side::None => false,
+          };
+      let take_profit = self.take_profit_price > 0
+          && match self.side {
+              Side::Long => exit_price >= self.take_profit_price,
+              Side::Short => exit_price <= self.take_profit_price,
+              Side::None => false,
+          };
+      if stop_loss {
+          Some(self.stop_loss_price)
+      } else if take_profit {
+          Some(self.take_profit_price)
+      } else {
+          None
+      }
+  }
+
+  /// Returns the worst exit price accepted when a trigger at `trigger_price` executes
+  pub fn get_trigger_exit_bound(&self, trigger_price: u64) -> Result<u64> {
+      let slippage = math::checked_as_u64(math::checked_div(
+          math::checked_mul(trigger_price as u128, self.trigger_slippage as u128)?,
+          Perpetuals::BPS_POWER,
+      )?)?;
+      if self.side == Side::Long {
+          Ok(trigger_price.saturating_sub(slippage))
+      } else {
+          math::checked_add(trigger_price, slippage)
+      }
+  }
+
+  pub fn clear_triggers(&mut self) {
+      self.stop_loss_price = 0;
+      self.take_profit_price = 0;
+      self.trigger_size_usd = 0;
+      self.trigger_slippage = 0;
+  }
+
+  /// Returns the size-weighted entry price after adding `size_usd` at `price`
+  pub fn get_average_price(&self, size_usd: u64, price: u64) -> Result<u64> {
+      // avg_price = (size + added_size) / (size / price + added_size / added_price)
+      if self.size_usd == 0 || self.price == 0 {
+          return Ok(price);
+      }
+      if size_usd == 0 {
+          return Ok(self.price);
+      }
+      let added_size_scaled = math::checked_div(
+          math::checked_mul(size_usd as u128, self.price as u128)?,
+          price as u128,
+      )?;
+      math::checked_as_u64(math::checked_div(
+          math::checked_mul(
+              math::checked_add(self.size_usd, size_usd)? as u128,
+              self.price as u128,
+          )?,
+          math::checked_add(self.size_usd as u128, added_size_scaled)?,
+      )?)
+  }
+}
+
+// launch layout, positions were seeded by owner, pool, custody and side, see upgrade_position
+#[account]
+#[derive(Default, Debug)]
+pub struct DeprecatedPosition {
+  pub owner: Pubkey,
+  pub pool: Pubkey,
+  pub custody: Pubkey,
+
+  pub open_time: i64,
+  pub update_time: i64,
+  pub side: Side,
+  pub price: u64,
+  pub size_usd: u64,
+  pub collateral_usd: u64,
+  pub unrealized_profit_usd: u64,
+  pub unrealized_loss_usd: u64,
+  pub cumulative_interest_snapshot: u128,
+  pub locked_amount: u64,
+  pub collateral_amount: u64,
+
+  pub bump: u8,
+}
+
+impl DeprecatedPosition {
+  pub const LEN: usize = 8 + std::mem::size_of::<DeprecatedPosition>();
+
+  /// Converts a position with the launch layout, the collateral was held by the
+  /// position custody and funding accrues from `cumulative_funding_snapshot`
+  pub fn upgrade(&self, position_id: u64, cumulative_funding_snapshot: i128, bump: u8) -> Position {
+      Position {
+          owner: self.owner,
+          pool: self.pool,
+          custody: self.custody,
+          collateral_custody: self.custody,
+          position_id,
+          open_time: self.open_time,
+          update_time: self.update_time,
+          side: self.side,
+          price: self.price,
+          size_usd: self.size_usd,
+          collateral_usd: self.collateral_usd,
+          unrealized_profit_usd: self.unrealized_profit_usd,
+          unrealized_loss_usd: self.unrealized_loss_usd,
+          cumulative_interest_snapshot: self.cumulative_interest_snapshot,
+          cumulative_funding_snapshot,
+          locked_amount: self.locked_amount,
+          collateral_amount: self.collateral_amount,
+          bump,
+          ..Position::default()
+      }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_get_average_price() {
+      let position = Position {
+          price: 100_000_000,
+          size_usd: 1_000_000_000,
+          ..Position::default()
+      };
+
+      assert_eq!(100_000_000, position.get_average_price(0, 120_000_000).unwrap());
+      assert_eq!(100_000_000, position.get_average_price(500_000_000, 100_000_000).unwrap());
+      // 2000 / (1000 / 100 + 1000 / 150) = 120
+      assert_eq!(120_000_000, position.get_average_price(1_000_000_000, 150_000_000).unwrap());
+      // 3000 / (1000 / 100 + 2000 / 80) = 85.714285
+      assert_eq!(85_714_285, position.get_average_price(2_000_000_000, 80_000_000).unwrap());
+
+      assert_eq!(
+          90_000_000,
+          Position::default().get_average_price(1_000_000_000, 90_000_000).unwrap()
+      );
+  }
+
+  #[test]
+  fn test_get_partial() {
+      let mut position = Position {
+          price: 100_000_000,
+          size_usd: 1_000_000_000,
+          collateral_usd: 200_000_000,
+          unrealized_loss_usd: 3_000_000,
+          locked_amount: 1_000_001,
+          collateral_amount: 200_001,
+          ..Position::default()
+      };
+
+      let partial = position.get_partial(250_000_000).unwrap();
+      assert_eq!(250_000_000, partial.size_usd);
+      assert_eq!(50_000_000, partial.collateral_usd);
+      assert_eq!(750_000, partial.unrealized_loss_usd);
+      assert_eq!(250_000, partial.locked_amount);
+      assert_eq!(50_000, partial.collateral_amount);
+      assert_eq!(position.price, partial.price);
+
+      position.remove_partial(&partial).unwrap();
+      assert_eq!(750_000_000, position.size_usd);
+      assert_eq!(150_000_000, position.collateral_usd);
+      assert_eq!(2_250_000, position.unrealized_loss_usd);
+      assert_eq!(750_001, position.locked_amount);
+      assert_eq!(150_001, position.collateral_amount);
+
+      let partial = position.get_partial(u64::MAX).unwrap();
+      assert_eq!(position.size_usd, partial.size_usd);
+      assert_eq!(position.locked_amount, partial.locked_amount);
+  }
+
+  #[test]
+  fn test_triggers() {
+      let mut position = Position {
+          side: Side::Long,
+          stop_loss_price: 90_000_000,
+          take_profit_price: 120_000_000,
+          trigger_slippage: 100,
+          ..Position::default()
+      };
+
+      assert_eq!(None, position.get_trigger_price(100_000_000));
+      assert_eq!(Some(90_000_000), position.get_trigger_price(90_000_000));
+      assert_eq!(Some(120_000_000), position.get_trigger_price(125_000_000));
+      assert_eq!(89_100_000, position.get_trigger_exit_bound(90_000_000).unwrap());
+
+      position.side = Side::Short;
+      position.stop_loss_price = 110_000_000;
+      position.take_profit_price = 0;
+      assert_eq!(None, position.get_trigger_price(80_000_000));
+      assert_eq!(Some(110_000_000), position.get_trigger_price(111_000_000));
+      assert_eq!(111_100_000, position.get_trigger_exit_bound(110_000_000).unwrap());
+
+      position.clear_triggers();
+      assert_eq!(None, position.get_trigger_price(111_000_000));
+  }
+
+  #[test]
+  fn test_position_upgrade() {
+      let deprecated_position = DeprecatedPosition {
+          owner: Pubkey::new_unique(),
+          pool: Pubkey::new_unique(),
+          custody: Pubkey::new_unique(),
+          side: Side::Short,
+          price: 100_000_000,
+          size_usd: 1_000_000_000,
+          collateral_usd: 200_000_000,
+          cumulative_interest_snapshot: 5000,
+          locked_amount: 1_000_000,
+          collateral_amount: 200_000,
+          bump: 254,
+          ..DeprecatedPosition::default()
+      };
+
+      let position = deprecated_position.upgrade(3, -700, 253);
+      assert_eq!(deprecated_position.owner, position.owner);
+      assert_eq!(deprecated_position.custody, position.collateral_custody);
+      assert_eq!(3, position.position_id);
+      assert_eq!(Side::Short, position.side);
+      assert_eq!(1_000_000_000, position.size_usd);
+      assert_eq!(5000, position.cumulative_interest_snapshot);
+      assert_eq!(-700, position.cumulative_funding_snapshot);
+      assert_eq!(200_000, position.collateral_amount);
+      assert_eq!(253, position.bump);
+      assert_eq!(0, position.stop_loss_price);
+  }
 }
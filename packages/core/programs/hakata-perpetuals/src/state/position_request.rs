@@ -0,0 +1,84 @@
+use {
+    crate::{math, state::position::Side},
+    anchor_lang::prelude::*,
+};
+
+#[derive(Copy, Clone, PartialEq, AnchorSerialize, AnchorDeserialize, Default, Debug)]
+pub enum PositionRequestType {
+    #[default]
+    Open,
+    Close,
+    AddCollateral,
+    RemoveCollateral,
+}
+
+#[account]
+#[derive(Default, Debug)]
+pub struct PositionRequest {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub custody: Pubkey,
+    pub collateral_custody: Pubkey,
+    pub position_id: u64,
+    pub side: Side,
+
+    pub request_type: PositionRequestType,
+    // worst accepted entry or exit price, has implied PRICE_DECIMALS decimals
+    pub price: u64,
+    // collateral tokens in escrow for Open and AddCollateral, fees are taken from it
+    pub collateral: u64,
+    // collateral to withdraw for RemoveCollateral
+    pub collateral_usd: u64,
+    // position size in custody tokens for Open
+    pub size: u64,
+    pub request_time: i64,
+    pub request_slot: u64,
+
+    pub bump: u8,
+    pub token_account_bump: u8,
+}
+
+impl PositionRequest {
+    pub const LEN: usize = 8 + std::mem::size_of::<PositionRequest>();
+
+    pub fn is_expired(&self, curtime: i64, timeout_sec: i64) -> Result<bool> {
+        Ok(curtime >= math::checked_add(self.request_time, timeout_sec)?)
+    }
+
+    /// Only prices posted after the request slot can be used to fill it. Oracles that
+    /// don't record the slot fall back to a publish time after the request time.
+    pub fn is_valid_price_update(&self, publish_time: i64, posted_slot: Option<u64>) -> bool {
+        match posted_slot {
+            Some(slot) => slot > self.request_slot,
+            None => publish_time > self.request_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_timing() {
+        let request = PositionRequest {
+            request_time: 1000,
+            request_slot: 5000,
+            ..PositionRequest::default()
+        };
+
+        assert!(!request.is_valid_price_update(999, None));
+        assert!(!request.is_valid_price_update(1000, None));
+        assert!(request.is_valid_price_update(1001, None));
+
+        // the posted slot takes precedence, prices from the request slot may have been seen
+        assert!(!request.is_valid_price_update(1001, Some(4999)));
+        assert!(!request.is_valid_price_update(1001, Some(5000)));
+        assert!(request.is_valid_price_update(1000, Some(5001)));
+
+        assert!(!request.is_expired(1000, 60).unwrap());
+        assert!(!request.is_expired(1059, 60).unwrap());
+        assert!(request.is_expired(1060, 60).unwrap());
+        assert!(!request.is_expired(1060, 120).unwrap());
+    }
+}
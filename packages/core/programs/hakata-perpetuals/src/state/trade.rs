@@ -0,0 +1,490 @@
+//! Position accounting shared by user instructions and keeper executions
+
+use {
+    crate::{
+        error::PerpetualsError,
+        math,
+        state::{
+            custody::Custody,
+            oracle::OraclePrice,
+            pool::Pool,
+            position::{Position, Side},
+        },
+    },
+    anchor_lang::prelude::*,
+};
+
+#[derive(Copy, Clone, Debug)]
+pub struct TradePrices {
+    pub token_price: OraclePrice,
+    pub token_ema_price: OraclePrice,
+    pub collateral_token_price: OraclePrice,
+    pub collateral_token_ema_price: OraclePrice,
+}
+
+/// Pool and custody accounts of a position. Token transfers are left to the caller,
+/// which moves the amounts returned by the methods below.
+pub struct Trade<'a> {
+    pub pool: &'a mut Pool,
+    pub custody: &'a mut Custody,
+    pub collateral_custody: &'a mut Custody,
+    // collateral_custody is serialized last, so if both are the same account
+    // position token stats must be recorded there
+    pub same_custody: bool,
+    pub prices: TradePrices,
+    pub curtime: i64,
+}
+
+impl TradePrices {
+    pub fn get_min_price(&self) -> OraclePrice {
+        if self.token_price < self.token_ema_price {
+            self.token_price
+        } else {
+            self.token_ema_price
+        }
+    }
+
+    pub fn get_min_collateral_price(&self) -> OraclePrice {
+        if self.collateral_token_price < self.collateral_token_ema_price {
+            self.collateral_token_price
+        } else {
+            self.collateral_token_ema_price
+        }
+    }
+
+    pub fn get_max_collateral_price(&self) -> OraclePrice {
+        if self.collateral_token_price > self.collateral_token_ema_price {
+            self.collateral_token_price
+        } else {
+            self.collateral_token_ema_price
+        }
+    }
+}
+
+impl Trade<'_> {
+    /// Returns the fee charged for opening a position of the given size
+    pub fn get_open_fee(&self, size: u64) -> Result<u64> {
+        let (_, fee_amount) = self.get_open_amounts(size)?;
+        Ok(fee_amount)
+    }
+
+    /// Fills a new position with the given collateral and locks funds for its payoff,
+    /// returns the fee. Owner and account keys are set by the caller.
+    pub fn open_position(
+        &mut self,
+        position: &mut Position,
+        side: Side,
+        price: u64,
+        size: u64,
+        collateral: u64,
+    ) -> Result<u64> {
+        let prices = self.prices;
+        let curtime = self.curtime;
+
+        let position_price = self.pool.get_entry_price(
+            &prices.token_price,
+            &prices.token_ema_price,
+            side,
+            self.custody,
+            curtime,
+        )?;
+        msg!("Entry price: {}", position_price);
+
+        if side == Side::Long {
+            require_gte!(price, position_price, PerpetualsError::MaxPriceSlippage);
+        } else {
+            require_gte!(position_price, price, PerpetualsError::MaxPriceSlippage);
+        }
+
+        // compute fee
+        let (locked_amount, fee_amount) = self.get_open_amounts(size)?;
+        let size_usd = prices
+            .get_min_price()
+            .get_asset_amount_usd(size, self.custody.decimals)?;
+        msg!("Collected fee: {}", fee_amount);
+
+        // init new position
+        msg!("Initialize new position");
+        let collateral_usd = prices
+            .get_min_collateral_price()
+            .get_asset_amount_usd(collateral, self.collateral_custody.decimals)?;
+
+        position.open_time = curtime;
+        position.update_time = 0;
+        position.side = side;
+        position.price = position_price;
+        position.size_usd = size_usd;
+        position.collateral_usd = collateral_usd;
+        position.unrealized_profit_usd = 0;
+        position.unrealized_loss_usd = 0;
+        position.cumulative_interest_snapshot =
+            self.collateral_custody.get_cumulative_interest(curtime)?;
+        position.cumulative_funding_snapshot =
+            self.custody.get_cumulative_funding(side, curtime)?;
+        position.locked_amount = locked_amount;
+        position.collateral_amount = collateral;
+
+        // check position risk
+        msg!("Check position risks");
+        require!(
+            position.locked_amount > 0,
+            PerpetualsError::InsufficientAmountReturned
+        );
+        require!(
+            self.pool.check_leverage(
+                position,
+                &prices.token_ema_price,
+                self.custody,
+                &prices.collateral_token_ema_price,
+                self.collateral_custody,
+                curtime,
+                true
+            )?,
+            PerpetualsError::MaxLeverage
+        );
+
+        // lock funds for potential profit payoff
+        self.collateral_custody.lock_funds(position.locked_amount)?;
+
+        // update custody stats
+        msg!("Update custody stats");
+        let collateral_custody = &mut *self.collateral_custody;
+        collateral_custody.collected_fees.open_position_usd = collateral_custody
+            .collected_fees
+            .open_position_usd
+            .wrapping_add(
+                prices
+                    .collateral_token_ema_price
+                    .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+            );
+
+        collateral_custody.assets.collateral =
+            math::checked_add(collateral_custody.assets.collateral, collateral)?;
+
+        collateral_custody.collect_fee(fee_amount)?;
+
+        let (custody, position_collateral_custody) = if self.same_custody {
+            (&mut *self.collateral_custody, None)
+        } else {
+            (&mut *self.custody, Some(&*self.collateral_custody))
+        };
+
+        custody.volume_stats.open_position_usd = custody
+            .volume_stats
+            .open_position_usd
+            .wrapping_add(size_usd);
+
+        if side == Side::Long {
+            custody.trade_stats.oi_long_usd =
+                math::checked_add(custody.trade_stats.oi_long_usd, size_usd)?;
+        } else {
+            custody.trade_stats.oi_short_usd =
+                math::checked_add(custody.trade_stats.oi_short_usd, size_usd)?;
+        }
+
+        custody.add_position(
+            position,
+            &prices.collateral_token_ema_price,
+            curtime,
+            position_collateral_custody,
+        )?;
+        custody.update_funding_rate(curtime)?;
+        self.collateral_custody.update_borrow_rate(curtime)?;
+
+        Ok(fee_amount)
+    }
+
+    /// Settles the whole position and releases its locked funds,
+    /// returns the amount of collateral tokens owed to the owner
+    pub fn close_position(&mut self, position: &Position, price: u64) -> Result<u64> {
+        let prices = self.prices;
+        let curtime = self.curtime;
+
+        let exit_price = self.pool.get_exit_price(
+            &prices.token_price,
+            &prices.token_ema_price,
+            position.side,
+            self.custody,
+            curtime,
+        )?;
+        msg!("Exit price: {}", exit_price);
+
+        if position.side == Side::Long {
+            require_gte!(exit_price, price, PerpetualsError::MaxPriceSlippage);
+        } else {
+            require_gte!(price, exit_price, PerpetualsError::MaxPriceSlippage);
+        }
+
+        msg!("Settle position");
+        let (transfer_amount, fee_amount, profit_usd, loss_usd) = self.pool.get_close_amount(
+            position,
+            &prices.token_price,
+            &prices.token_ema_price,
+            self.custody,
+            &prices.collateral_token_price,
+            &prices.collateral_token_ema_price,
+            self.collateral_custody,
+            curtime,
+            false,
+        )?;
+
+        msg!("Net profit: {}, loss: {}", profit_usd, loss_usd);
+        msg!("Collected fee: {}", fee_amount);
+        msg!("Amount out: {}", transfer_amount);
+
+        // unlock pool funds
+        self.collateral_custody
+            .unlock_funds(position.locked_amount)?;
+
+        // check pool constraints
+        msg!("Check pool constraints");
+        require!(
+            self.pool
+                .check_available_amount(transfer_amount, self.collateral_custody)?,
+            PerpetualsError::CustodyAmountLimit
+        );
+
+        // update custody stats
+        msg!("Update custody stats");
+        let collateral_custody = &mut *self.collateral_custody;
+        collateral_custody.collected_fees.close_position_usd = collateral_custody
+            .collected_fees
+            .close_position_usd
+            .wrapping_add(
+                prices
+                    .collateral_token_ema_price
+                    .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+            );
+
+        let reserved_amount = collateral_custody.reserve_fee(fee_amount)?;
+        collateral_custody.settle_collateral(
+            position.collateral_amount,
+            transfer_amount,
+            reserved_amount,
+        )?;
+
+        // loss in excess of collateral is covered by the insurance fund first
+        let bad_debt_usd = loss_usd.saturating_sub(position.collateral_usd);
+        let (covered_usd, socialized_usd) = collateral_custody.cover_bad_debt(
+            bad_debt_usd,
+            &prices.collateral_token_ema_price,
+            curtime,
+        )?;
+        if bad_debt_usd > 0 {
+            msg!(
+                "Bad debt covered: {}, socialized: {}",
+                covered_usd,
+                socialized_usd
+            );
+        }
+        self.pool
+            .bad_debt_stats
+            .record(covered_usd, socialized_usd, curtime);
+
+        let (custody, position_collateral_custody) = if self.same_custody {
+            (&mut *self.collateral_custody, None)
+        } else {
+            (&mut *self.custody, Some(&*self.collateral_custody))
+        };
+
+        custody.volume_stats.close_position_usd = custody
+            .volume_stats
+            .close_position_usd
+            .wrapping_add(position.size_usd);
+
+        if position.side == Side::Long {
+            custody.trade_stats.oi_long_usd = custody
+                .trade_stats
+                .oi_long_usd
+                .saturating_sub(position.size_usd);
+        } else {
+            custody.trade_stats.oi_short_usd = custody
+                .trade_stats
+                .oi_short_usd
+                .saturating_sub(position.size_usd);
+        }
+
+        custody.trade_stats.profit_usd = custody.trade_stats.profit_usd.wrapping_add(profit_usd);
+        custody.trade_stats.loss_usd = custody.trade_stats.loss_usd.wrapping_add(loss_usd);
+
+        custody.remove_position(position, curtime, position_collateral_custody)?;
+        custody.update_funding_rate(curtime)?;
+        self.collateral_custody.update_borrow_rate(curtime)?;
+
+        Ok(transfer_amount)
+    }
+
+    /// Adds collateral tokens to the position, returns the fee charged on top of them
+    pub fn add_collateral(
+        &mut self,
+        position: &mut Position,
+        token_id: usize,
+        collateral: u64,
+    ) -> Result<u64> {
+        let prices = self.prices;
+
+        // compute fee
+        let fee_amount = self.pool.get_add_liquidity_fee(
+            token_id,
+            collateral,
+            self.collateral_custody,
+            &prices.collateral_token_ema_price,
+        )?;
+        msg!("Collected fee: {}", fee_amount);
+
+        let collateral_usd = prices
+            .get_min_collateral_price()
+            .get_asset_amount_usd(collateral, self.collateral_custody.decimals)?;
+        msg!("Collateral added in USD: {}", collateral_usd);
+
+        // update existing position
+        msg!("Update existing position");
+        position.update_time = self.curtime;
+        position.collateral_usd = math::checked_add(position.collateral_usd, collateral_usd)?;
+        position.collateral_amount = math::checked_add(position.collateral_amount, collateral)?;
+
+        // check position risk
+        msg!("Check position risks");
+        require!(
+            self.pool.check_leverage(
+                position,
+                &prices.token_ema_price,
+                self.custody,
+                &prices.collateral_token_ema_price,
+                self.collateral_custody,
+                self.curtime,
+                true
+            )?,
+            PerpetualsError::MaxLeverage
+        );
+
+        // update custody stats
+        msg!("Update custody stats");
+        let collateral_custody = &mut *self.collateral_custody;
+        collateral_custody.collected_fees.open_position_usd = collateral_custody
+            .collected_fees
+            .open_position_usd
+            .wrapping_add(
+                prices
+                    .collateral_token_ema_price
+                    .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+            );
+
+        collateral_custody.assets.collateral =
+            math::checked_add(collateral_custody.assets.collateral, collateral)?;
+
+        collateral_custody.collect_fee(fee_amount)?;
+
+        // position stats are recorded in the serialized copy of the custody
+        if self.same_custody {
+            collateral_custody.add_collateral(position.side, collateral_usd)?;
+        } else {
+            self.custody.add_collateral(position.side, collateral_usd)?;
+        }
+
+        Ok(fee_amount)
+    }
+
+    /// Withdraws collateral worth collateral_usd from the position,
+    /// returns the amount of collateral tokens owed to the owner
+    pub fn remove_collateral(
+        &mut self,
+        position: &mut Position,
+        token_id: usize,
+        collateral_usd: u64,
+    ) -> Result<u64> {
+        let prices = self.prices;
+        if collateral_usd == 0 || collateral_usd >= position.collateral_usd {
+            return Err(ProgramError::InvalidArgument.into());
+        }
+
+        // compute fee
+        let collateral = prices
+            .get_max_collateral_price()
+            .get_token_amount(collateral_usd, self.collateral_custody.decimals)?;
+        let fee_amount = self.pool.get_remove_liquidity_fee(
+            token_id,
+            collateral,
+            self.collateral_custody,
+            &prices.collateral_token_ema_price,
+        )?;
+        msg!("Collected fee: {}", fee_amount);
+
+        // compute amount to transfer
+        if collateral > position.collateral_amount {
+            return Err(ProgramError::InsufficientFunds.into());
+        }
+        let transfer_amount = math::checked_sub(collateral, fee_amount)?;
+        msg!("Amount out: {}", transfer_amount);
+
+        // update existing position
+        msg!("Update existing position");
+        position.update_time = self.curtime;
+        position.collateral_usd = math::checked_sub(position.collateral_usd, collateral_usd)?;
+        position.collateral_amount = math::checked_sub(position.collateral_amount, collateral)?;
+
+        // check position risk
+        msg!("Check position risks");
+        require!(
+            self.pool.check_leverage(
+                position,
+                &prices.token_ema_price,
+                self.custody,
+                &prices.collateral_token_ema_price,
+                self.collateral_custody,
+                self.curtime,
+                true
+            )?,
+            PerpetualsError::MaxLeverage
+        );
+
+        // update custody stats
+        msg!("Update custody stats");
+        let collateral_custody = &mut *self.collateral_custody;
+        collateral_custody.collected_fees.open_position_usd = collateral_custody
+            .collected_fees
+            .open_position_usd
+            .wrapping_add(
+                prices
+                    .collateral_token_ema_price
+                    .get_asset_amount_usd(fee_amount, collateral_custody.decimals)?,
+            );
+
+        collateral_custody.assets.collateral =
+            math::checked_sub(collateral_custody.assets.collateral, collateral)?;
+
+        collateral_custody.collect_fee(fee_amount)?;
+
+        // position stats are recorded in the serialized copy of the custody
+        if self.same_custody {
+            collateral_custody.remove_collateral(position.side, collateral_usd)?;
+        } else {
+            self.custody
+                .remove_collateral(position.side, collateral_usd)?;
+        }
+
+        Ok(transfer_amount)
+    }
+
+    // private helpers
+    fn get_open_amounts(&self, size: u64) -> Result<(u64, u64)> {
+        let size_usd = self
+            .prices
+            .get_min_price()
+            .get_asset_amount_usd(size, self.custody.decimals)?;
+        let min_collateral_price = self.prices.get_min_collateral_price();
+        let locked_amount = self.pool.get_locked_amount(
+            size_usd,
+            self.custody,
+            &min_collateral_price,
+            self.collateral_custody,
+        )?;
+        let fee_amount = self.pool.get_entry_fee(
+            self.custody.fees.open_position,
+            min_collateral_price.get_token_amount(size_usd, self.collateral_custody.decimals)?,
+            locked_amount,
+            self.collateral_custody,
+        )?;
+        Ok((locked_amount, fee_amount))
+    }
+}
@@ -41,6 +41,7 @@ describe("Hakata Perpetuals", () => {
       transferAuthorityBump: tc.authority.bump,
       perpetualsBump: tc.perpetuals.bump,
       inceptionTime: new anchor.BN(0),
+      positionRequestTimeoutSec: 60,
     };
 
     multisigExpected = {
@@ -342,6 +342,7 @@ export class TestClient {
           allowPnlWithdrawal: true,
           allowCollateralWithdrawal: true,
           allowSizeChange: true,
+          positionRequestTimeoutSec: 60,
         })
         .accounts({
           upgradeAuthority: this.provider.wallet.publicKey,